@@ -0,0 +1,136 @@
+use std::fs::{self, File};
+use std::io::Read;
+use std::thread;
+use std::time::Duration;
+
+use http::StatusCode as HttpStatus;
+use reqwest::header::{self, HeaderMap, HeaderValue};
+use serde_json::{json, Value as JsonValue};
+use svc_agent::AccountId;
+use svc_authn::{jose::Algorithm, token::jws_compact};
+
+// Must match `admin.events_path` in App.toml.
+const EVENTS_PATH: &str = "target/tenants.json";
+const TENANTS_URL: &str = "http://0.0.0.0:31181/api/v1/admin/tenants";
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[test]
+fn manage_tenants() {
+    let _ = fs::remove_file(EVENTS_PATH);
+    thread::spawn(http_gateway::run);
+    thread::sleep(Duration::from_secs(3));
+
+    let client = reqwest::Client::new();
+    let headers = build_headers();
+
+    let tenant_config = json!({
+        "callback": "https://tenant.example.org/callback",
+        "sources": [{ "account_id": "mock-service.test.svc.example.org", "version": "v1" }],
+    });
+
+    // Create.
+    let resp = client
+        .put(&format!("{}/tenant.example.org", TENANTS_URL))
+        .headers(headers.clone())
+        .json(&tenant_config)
+        .send()
+        .expect("HTTP request failed");
+
+    assert_eq!(resp.status(), HttpStatus::OK);
+
+    let persisted = read_persisted();
+    assert_eq!(
+        persisted["tenant.example.org"]["callback"],
+        tenant_config["callback"]
+    );
+
+    // List.
+    let mut resp = client
+        .get(TENANTS_URL)
+        .headers(headers.clone())
+        .send()
+        .expect("HTTP request failed");
+
+    assert_eq!(resp.status(), HttpStatus::OK);
+    let tenants = resp.json::<JsonValue>().expect("Failed to parse response");
+    assert!(tenants.get("tenant.example.org").is_some());
+    assert!(tenants.get("example.org").is_some());
+
+    // Audiences with MQTT wildcards or separators are rejected.
+    for audience in &["%2B", "%23", "tenant.example.org%2F%23"] {
+        let resp = client
+            .put(&format!("{}/{}", TENANTS_URL, audience))
+            .headers(headers.clone())
+            .json(&tenant_config)
+            .send()
+            .expect("HTTP request failed");
+
+        assert_eq!(resp.status(), HttpStatus::BAD_REQUEST, "{}", audience);
+    }
+
+    assert!(read_persisted().get("+").is_none());
+
+    // File sinks are only allowed in App.toml since `admin.sink_dir` isn't set.
+    let resp = client
+        .put(&format!("{}/files.example.org", TENANTS_URL))
+        .headers(headers.clone())
+        .json(&json!({
+            "sink": { "type": "file", "path": "/etc/cron.d/events" },
+            "sources": [{ "account_id": "mock-service.test.svc.example.org", "version": "v1" }],
+        }))
+        .send()
+        .expect("HTTP request failed");
+
+    assert_eq!(resp.status(), HttpStatus::BAD_REQUEST);
+    assert!(read_persisted().get("files.example.org").is_none());
+
+    // Delete.
+    let resp = client
+        .delete(&format!("{}/tenant.example.org", TENANTS_URL))
+        .headers(headers.clone())
+        .send()
+        .expect("HTTP request failed");
+
+    assert_eq!(resp.status(), HttpStatus::OK);
+    assert!(read_persisted().get("tenant.example.org").is_none());
+
+    let resp = client
+        .delete(&format!("{}/tenant.example.org", TENANTS_URL))
+        .headers(headers)
+        .send()
+        .expect("HTTP request failed");
+
+    assert_eq!(resp.status(), HttpStatus::NOT_FOUND);
+
+    let _ = fs::remove_file(EVENTS_PATH);
+}
+
+fn read_persisted() -> JsonValue {
+    let data = fs::read(EVENTS_PATH).expect("Failed to read persisted events config");
+    serde_json::from_slice(&data).expect("Failed to parse persisted events config")
+}
+
+fn build_headers() -> HeaderMap {
+    let authz_header = HeaderValue::from_str(&format!("Bearer {}", build_authz_token()))
+        .expect("Failed to build Authorization header");
+
+    let mut headers = HeaderMap::new();
+    headers.insert(header::AUTHORIZATION, authz_header);
+    headers
+}
+
+fn build_authz_token() -> String {
+    let mut key_file =
+        File::open("../data/keys/iam.private_key.p8.der.sample").expect("Failed to open key file");
+
+    let mut key = Vec::<u8>::new();
+    key_file.read_to_end(&mut key).expect("Failed to read key");
+
+    jws_compact::TokenBuilder::new()
+        .issuer("iam.test.svc.example.org")
+        .subject(&AccountId::new("admin", "test.svc.example.org"))
+        .key(Algorithm::ES256, &key)
+        .build()
+        .expect("Error creating an id token")
+}
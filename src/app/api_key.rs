@@ -0,0 +1,164 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use anyhow::{format_err, Context, Result};
+use ring::digest;
+use serde_derive::Deserialize;
+use svc_agent::{AccountId, AgentId};
+
+////////////////////////////////////////////////////////////////////////////////
+
+pub(crate) const HEADER: &str = "x-api-key";
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub(crate) struct Config {
+    /// JSON file with a list of keys in addition to the ones configured inline.
+    path: Option<PathBuf>,
+    #[serde(default)]
+    keys: Vec<ApiKey>,
+}
+
+/// A key allowed to call `/api/v1/request` on behalf of the account.
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct ApiKey {
+    /// Hex encoded SHA-256 of the key.
+    sha256: String,
+    account_id: AccountId,
+    /// Agent label the caller must use in `me`.
+    me: String,
+    /// Destinations allowed to call, any if not set.
+    destinations: Option<Vec<AccountId>>,
+    /// Methods allowed to call, any if not set.
+    methods: Option<Vec<String>>,
+}
+
+impl ApiKey {
+    pub(crate) fn account_id(&self) -> &AccountId {
+        &self.account_id
+    }
+
+    /// Checks that the request is within the key's scope.
+    pub(crate) fn authorize(
+        &self,
+        me: &AgentId,
+        destination: &AccountId,
+        method: &str,
+    ) -> Result<(), String> {
+        if me.label() != self.me {
+            return Err(format!(
+                "agent label = '{}' is not allowed for the API key",
+                me.label()
+            ));
+        }
+
+        if let Some(ref destinations) = self.destinations {
+            if !destinations.contains(destination) {
+                return Err(format!(
+                    "destination = '{}' is not allowed for the API key",
+                    destination
+                ));
+            }
+        }
+
+        if let Some(ref methods) = self.methods {
+            if !methods.iter().any(|allowed| allowed == method) {
+                return Err(format!(
+                    "method = '{}' is not allowed for the API key",
+                    method
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// API keys looked up by their hashes so that the keys themselves are never stored.
+#[derive(Debug, Default)]
+pub(crate) struct ApiKeys {
+    keys: HashMap<String, Arc<ApiKey>>,
+}
+
+impl ApiKeys {
+    /// Loads the inline keys and the ones from the file.
+    pub(crate) fn load(config: &Config) -> Result<Self> {
+        let mut keys = config.keys.clone();
+
+        if let Some(ref path) = config.path {
+            let data = fs::read(path)
+                .with_context(|| format!("Failed to read API keys from {}", path.display()))?;
+
+            let file_keys = serde_json::from_slice::<Vec<ApiKey>>(&data)
+                .with_context(|| format!("Failed to parse API keys from {}", path.display()))?;
+
+            keys.extend(file_keys);
+        }
+
+        let mut by_hash = HashMap::with_capacity(keys.len());
+
+        for mut key in keys {
+            key.sha256 = key.sha256.to_lowercase();
+
+            if key.sha256.len() != 64 || !key.sha256.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err(format_err!(
+                    "Invalid SHA-256 of the API key for account = '{}'",
+                    key.account_id
+                ));
+            }
+
+            by_hash.insert(key.sha256.to_owned(), Arc::new(key));
+        }
+
+        Ok(Self { keys: by_hash })
+    }
+
+    pub(crate) fn find(&self, key: &str) -> Option<Arc<ApiKey>> {
+        self.keys.get(&sha256(key)).cloned()
+    }
+}
+
+fn sha256(value: &str) -> String {
+    digest::digest(&digest::SHA256, value.as_bytes())
+        .as_ref()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn finds_key_by_hash() {
+        let config = serde_json::from_value::<Config>(json!({
+            "keys": [{
+                "sha256": sha256("secret").to_uppercase(),
+                "account_id": "backend.svc.example.org",
+                "me": "worker",
+                "methods": ["room.create"],
+            }],
+        }))
+        .expect("Failed to parse config");
+
+        let keys = ApiKeys::load(&config).expect("Failed to load keys");
+        assert!(keys.find("other").is_none());
+
+        let key = keys.find("secret").expect("Key not found");
+        let me = AgentId::new("worker", AccountId::new("backend", "svc.example.org"));
+        let other = AgentId::new("other", AccountId::new("backend", "svc.example.org"));
+        let destination = AccountId::new("conference", "svc.example.org");
+
+        assert!(key.authorize(&me, &destination, "room.create").is_ok());
+        assert!(key.authorize(&other, &destination, "room.create").is_err());
+        assert!(key.authorize(&me, &destination, "room.delete").is_err());
+    }
+}
@@ -0,0 +1,378 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::{try_ready, Async, Poll};
+use http::{header, HeaderMap, StatusCode};
+use serde::de::{self, Deserialize, Deserializer};
+use serde_derive::Deserialize;
+use serde_json::{Map as JsonMap, Value as JsonValue};
+use svc_agent::AccountId;
+use tower_web::extract::{self, Context, Extract, ExtractFuture, Immediate};
+use tower_web::util::BufStream;
+
+use crate::app::api_key::{self, ApiKey, ApiKeys};
+use crate::app::cookie_auth::CookieToken;
+use crate::app::endpoint::admin::error;
+use crate::app::revocation::Revocations;
+use crate::util::jwks::{self, JwksStore};
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Issuers keyed by their names.
+///
+/// Ones with `jwks_url` get keys from the URL, the rest are configured with a static `key`
+/// and verified by svc-authn.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ConfigMap {
+    keys: svc_authn::jose::ConfigMap,
+    jwks: HashMap<String, JwksConfig>,
+}
+
+impl ConfigMap {
+    pub(crate) fn keys(&self) -> &svc_authn::jose::ConfigMap {
+        &self.keys
+    }
+
+    pub(crate) fn jwks(&self) -> &HashMap<String, JwksConfig> {
+        &self.jwks
+    }
+}
+
+impl<'de> Deserialize<'de> for ConfigMap {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let issuers = HashMap::<String, JsonValue>::deserialize(deserializer)?;
+        let (mut keys, mut jwks) = (JsonMap::new(), JsonMap::new());
+
+        for (issuer, config) in issuers {
+            if config.get("jwks_url").is_some() {
+                jwks.insert(issuer, config);
+            } else {
+                keys.insert(issuer, config);
+            }
+        }
+
+        Ok(Self {
+            keys: serde_json::from_value(JsonValue::Object(keys)).map_err(de::Error::custom)?,
+            jwks: serde_json::from_value(JsonValue::Object(jwks)).map_err(de::Error::custom)?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct JwksConfig {
+    jwks_url: String,
+    audience: HashSet<String>,
+    #[serde(deserialize_with = "crate::serde::duration")]
+    #[serde(default = "JwksConfig::default_refresh_interval")]
+    refresh_interval: Duration,
+}
+
+impl JwksConfig {
+    fn default_refresh_interval() -> Duration {
+        Duration::from_secs(300)
+    }
+
+    pub(crate) fn jwks_url(&self) -> &str {
+        &self.jwks_url
+    }
+
+    pub(crate) fn refresh_interval(&self) -> Duration {
+        self.refresh_interval
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Verifies API keys and tokens of JWKS issuers and checks tokens for revocation.
+/// Passed to the HTTP server as a config.
+#[derive(Clone)]
+pub(crate) struct Authenticator {
+    jwks: HashMap<String, JwksConfig>,
+    store: JwksStore,
+    api_keys: Arc<ApiKeys>,
+    revocations: Arc<Revocations>,
+}
+
+impl Authenticator {
+    pub(crate) fn new(
+        config: &ConfigMap,
+        store: JwksStore,
+        api_keys: Arc<ApiKeys>,
+        revocations: Arc<Revocations>,
+    ) -> Self {
+        Self {
+            jwks: config.jwks.clone(),
+            store,
+            api_keys,
+            revocations,
+        }
+    }
+
+    // `None` when neither an API key is passed nor the token is issued by a JWKS issuer.
+    fn authenticate(&self, headers: &HeaderMap) -> Option<Result<Subject, tower_web::Error>> {
+        if let Some(value) = headers.get(api_key::HEADER) {
+            let api_key = value
+                .to_str()
+                .ok()
+                .and_then(|value| self.api_keys.find(value));
+
+            let result = match api_key {
+                Some(api_key) => Ok(Subject {
+                    account_id: api_key.account_id().to_owned(),
+                    api_key: Some(api_key),
+                    claims: None,
+                }),
+                None => Err(unauthorized("invalid API key")),
+            };
+
+            return Some(result);
+        }
+
+        self.authenticate_jwks(headers).map(|result| {
+            result.map(|(account_id, claims)| Subject {
+                account_id,
+                api_key: None,
+                claims: Some(claims),
+            })
+        })
+    }
+
+    fn authenticate_jwks(
+        &self,
+        headers: &HeaderMap,
+    ) -> Option<Result<(AccountId, JsonValue), tower_web::Error>> {
+        let token = bearer_token(headers)?;
+        let claims = jwks::decode_claims(token).ok()?;
+        let issuer = claims.get("iss").and_then(JsonValue::as_str)?;
+        let config = self.jwks.get(issuer)?;
+
+        let result = match self.store.keys(&config.jwks_url) {
+            Some(keys) => jwks::verify(token, &keys)
+                .map_err(|err| format!("{:#}", err))
+                .and_then(|claims| {
+                    account_id(&claims, &config.audience).map(|account_id| (account_id, claims))
+                }),
+            None => Err(format!("keys of issuer = '{}' are not fetched yet", issuer)),
+        };
+
+        let result = result
+            .map_err(|detail| unauthorized(&detail))
+            .and_then(|verified| {
+                self.revocations
+                    .check(&verified.1)
+                    .map(|()| verified)
+                    .map_err(|detail| revoked(&detail))
+            });
+
+        Some(result)
+    }
+}
+
+fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+}
+
+fn unauthorized(detail: &str) -> tower_web::Error {
+    error(
+        StatusCode::UNAUTHORIZED,
+        "authn_error",
+        "Authentication failed",
+        detail,
+    )
+}
+
+fn csrf_failed(detail: &str) -> tower_web::Error {
+    error(
+        StatusCode::FORBIDDEN,
+        "csrf_error",
+        "CSRF check failed",
+        detail,
+    )
+}
+
+fn revoked(detail: &str) -> tower_web::Error {
+    error(
+        StatusCode::UNAUTHORIZED,
+        "token_revoked",
+        "Token revoked",
+        detail,
+    )
+}
+
+// Same as svc-authn does: the label is the subject and the audience is one of the allowed ones.
+fn account_id(claims: &JsonValue, allowed: &HashSet<String>) -> Result<AccountId, String> {
+    let label = claims
+        .get("sub")
+        .and_then(JsonValue::as_str)
+        .ok_or("missing 'sub' claim")?;
+
+    let audience = match claims.get("aud") {
+        Some(JsonValue::String(aud)) => vec![aud.as_str()],
+        Some(JsonValue::Array(auds)) => auds.iter().filter_map(JsonValue::as_str).collect(),
+        _ => vec![],
+    };
+
+    audience
+        .into_iter()
+        .find(|aud| allowed.contains(*aud))
+        .map(|aud| AccountId::new(label, aud))
+        .ok_or_else(|| String::from("audience is not allowed"))
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// The authenticated caller.
+#[derive(Debug)]
+pub(crate) struct Subject {
+    account_id: AccountId,
+    api_key: Option<Arc<ApiKey>>,
+    claims: Option<JsonValue>,
+}
+
+impl Subject {
+    pub(crate) fn account_id(&self) -> &AccountId {
+        &self.account_id
+    }
+
+    /// The key the caller has authenticated with if not a token.
+    pub(crate) fn api_key(&self) -> Option<&Arc<ApiKey>> {
+        self.api_key.as_ref()
+    }
+
+    /// Verified claims of the caller's token.
+    pub(crate) fn claims(&self) -> Option<&JsonValue> {
+        self.claims.as_ref()
+    }
+}
+
+impl<B: BufStream> Extract<B> for Subject
+where
+    AccountId: Extract<B>,
+{
+    type Future = SubjectFuture<<AccountId as Extract<B>>::Future>;
+
+    fn extract(context: &Context) -> Self::Future {
+        let cookie_token = context.request().extensions().get::<CookieToken>();
+
+        if let Some(Err(detail)) = cookie_token.map(CookieToken::csrf) {
+            let err = extract::Error::from(csrf_failed(detail));
+            return SubjectFuture::Verified(Immediate::err(err));
+        }
+
+        let authenticator = context.config::<Authenticator>().ok();
+
+        let result = authenticator
+            .and_then(|authenticator| authenticator.authenticate(context.request().headers()));
+
+        match result {
+            Some(Ok(subject)) => SubjectFuture::Verified(Immediate::ok(subject)),
+            Some(Err(err)) => SubjectFuture::Verified(Immediate::err(extract::Error::from(err))),
+            None => {
+                // Claims are decoded here and only used once svc-authn verifies the token.
+                let claims = bearer_token(context.request().headers())
+                    .and_then(|token| jwks::decode_claims(token).ok());
+
+                let revocations =
+                    authenticator.map(|authenticator| authenticator.revocations.clone());
+
+                SubjectFuture::Delegated(
+                    <AccountId as Extract<B>>::extract(context),
+                    claims,
+                    revocations,
+                )
+            }
+        }
+    }
+}
+
+pub(crate) enum SubjectFuture<F> {
+    Verified(Immediate<Subject>),
+    // Tokens of issuers with static keys are verified by svc-authn and checked for revocation
+    // once verified.
+    Delegated(F, Option<JsonValue>, Option<Arc<Revocations>>),
+}
+
+impl<F> ExtractFuture for SubjectFuture<F>
+where
+    F: ExtractFuture<Item = AccountId>,
+{
+    type Item = Subject;
+
+    fn poll(&mut self) -> Poll<(), extract::Error> {
+        match self {
+            SubjectFuture::Verified(future) => future.poll(),
+            SubjectFuture::Delegated(future, claims, revocations) => {
+                try_ready!(future.poll());
+
+                if let (Some(claims), Some(revocations)) = (claims, revocations) {
+                    revocations
+                        .check(claims)
+                        .map_err(|detail| extract::Error::from(revoked(&detail)))?;
+                }
+
+                Ok(Async::Ready(()))
+            }
+        }
+    }
+
+    fn extract(self) -> Self::Item {
+        match self {
+            SubjectFuture::Verified(future) => future.extract(),
+            SubjectFuture::Delegated(future, claims, _) => Subject {
+                account_id: future.extract(),
+                api_key: None,
+                claims,
+            },
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn builds_account_id_from_allowed_audience() {
+        let allowed = vec![String::from("example.org")].into_iter().collect();
+        let claims = json!({ "sub": "john", "aud": ["other.org", "example.org"] });
+
+        assert_eq!(
+            account_id(&claims, &allowed),
+            Ok(AccountId::new("john", "example.org"))
+        );
+
+        let claims = json!({ "sub": "john", "aud": "other.org" });
+        assert!(account_id(&claims, &allowed).is_err());
+    }
+
+    #[test]
+    fn splits_jwks_issuers() {
+        let config = serde_json::from_value::<ConfigMap>(json!({
+            "iam.example.org": {
+                "jwks_url": "https://iam.example.org/.well-known/jwks.json",
+                "audience": ["example.org"],
+            },
+        }))
+        .expect("Failed to parse authn config");
+
+        assert!(config.keys().is_empty());
+
+        let jwks = config
+            .jwks()
+            .get("iam.example.org")
+            .expect("Missing JWKS issuer");
+
+        assert_eq!(jwks.refresh_interval(), Duration::from_secs(300));
+    }
+}
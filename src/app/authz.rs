@@ -0,0 +1,191 @@
+use std::collections::HashMap;
+
+use serde_derive::Deserialize;
+use serde_json::Value as JsonValue;
+use svc_agent::AccountId;
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Envelope property carrying the caller's verified claims.
+pub(crate) const CLAIMS_PROPERTY: &str = "claims";
+/// Envelope property carrying the account which has sent the request on behalf of `me`.
+pub(crate) const IMPERSONATED_BY_PROPERTY: &str = "impersonated_by";
+
+/// Claims required from callers of `/api/v1/request`.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub(crate) struct Config {
+    #[serde(default)]
+    rules: Vec<Rule>,
+    /// Accounts allowed to send requests on behalf of other accounts' agents.
+    #[serde(default)]
+    impersonators: Vec<AccountId>,
+    /// Pass the caller's claims to the backend in the request envelope.
+    #[serde(default)]
+    forward_claims: bool,
+}
+
+/// Requirements for requests to the destination and method, any if not set.
+///
+/// The token must have all `scopes`, at least one of `roles` and all `claims` with the given
+/// values. A claim holding an array matches if the array contains the value.
+#[derive(Debug, Clone, Deserialize)]
+struct Rule {
+    destination: Option<AccountId>,
+    method: Option<String>,
+    #[serde(default)]
+    scopes: Vec<String>,
+    #[serde(default)]
+    roles: Vec<String>,
+    #[serde(default)]
+    claims: HashMap<String, JsonValue>,
+}
+
+impl Config {
+    pub(crate) fn may_impersonate(&self, account_id: &AccountId) -> bool {
+        self.impersonators.contains(account_id)
+    }
+
+    /// Claims to put into the outgoing request, none unless `forward_claims` is set.
+    pub(crate) fn forwarded_claims(&self, claims: Option<JsonValue>) -> Option<JsonValue> {
+        claims.filter(|_| self.forward_claims)
+    }
+
+    /// Checks the caller's claims against all rules matching the request.
+    ///
+    /// Callers without claims, i.e. ones using API keys, only pass rules without requirements.
+    pub(crate) fn authorize(
+        &self,
+        claims: Option<&JsonValue>,
+        destination: &AccountId,
+        method: &str,
+    ) -> Result<(), String> {
+        let empty = JsonValue::Null;
+        let claims = claims.unwrap_or(&empty);
+
+        self.rules
+            .iter()
+            .filter(|rule| rule.matches(destination, method))
+            .try_for_each(|rule| rule.check(claims))
+    }
+}
+
+impl Rule {
+    fn matches(&self, destination: &AccountId, method: &str) -> bool {
+        self.destination.iter().all(|d| d == destination) && self.method.iter().all(|m| m == method)
+    }
+
+    fn check(&self, claims: &JsonValue) -> Result<(), String> {
+        let scopes = scopes(claims);
+
+        if let Some(missing) = self.scopes.iter().find(|s| !scopes.contains(&s.as_str())) {
+            return Err(format!("scope = '{}' is required", missing));
+        }
+
+        if !self.roles.is_empty() {
+            let roles = strings(claims.get("roles"));
+
+            if !self.roles.iter().any(|role| roles.contains(&role.as_str())) {
+                return Err(format!(
+                    "one of roles = '{}' is required",
+                    self.roles.join(", ")
+                ));
+            }
+        }
+
+        for (name, expected) in &self.claims {
+            let matches = match claims.get(name) {
+                Some(JsonValue::Array(values)) => values.contains(expected),
+                Some(value) => value == expected,
+                None => false,
+            };
+
+            if !matches {
+                return Err(format!("claim = '{}' must be {}", name, expected));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// Either a space separated `scope` string or an `scp` array.
+fn scopes(claims: &JsonValue) -> Vec<&str> {
+    match claims.get("scope") {
+        Some(JsonValue::String(scope)) => scope.split_whitespace().collect(),
+        _ => strings(claims.get("scp")),
+    }
+}
+
+fn strings(value: Option<&JsonValue>) -> Vec<&str> {
+    match value {
+        Some(JsonValue::String(value)) => vec![value.as_str()],
+        Some(JsonValue::Array(values)) => values.iter().filter_map(JsonValue::as_str).collect(),
+        _ => vec![],
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use super::*;
+
+    fn config() -> Config {
+        serde_json::from_value(json!({
+            "rules": [
+                {
+                    "destination": "conference.svc.example.org",
+                    "scopes": ["conference"],
+                },
+                {
+                    "destination": "conference.svc.example.org",
+                    "method": "room.delete",
+                    "roles": ["admin", "owner"],
+                    "claims": { "tenant": "example" },
+                },
+            ],
+        }))
+        .expect("Failed to parse config")
+    }
+
+    #[test]
+    fn requires_claims_of_matching_rules() {
+        let config = config();
+        let conference = AccountId::new("conference", "svc.example.org");
+        let storage = AccountId::new("storage", "svc.example.org");
+
+        let user = json!({ "scope": "conference storage" });
+        assert!(config
+            .authorize(Some(&user), &conference, "room.create")
+            .is_ok());
+        assert!(config
+            .authorize(Some(&user), &conference, "room.delete")
+            .is_err());
+
+        let owner = json!({ "scp": ["conference"], "roles": ["owner"], "tenant": ["example"] });
+        assert!(config
+            .authorize(Some(&owner), &conference, "room.delete")
+            .is_ok());
+
+        let other_tenant = json!({ "scope": "conference", "roles": "owner", "tenant": "other" });
+        assert!(config
+            .authorize(Some(&other_tenant), &conference, "room.delete")
+            .is_err());
+
+        assert!(config.authorize(None, &conference, "room.create").is_err());
+        assert!(config.authorize(None, &storage, "file.upload").is_ok());
+    }
+
+    #[test]
+    fn forwards_claims_if_enabled() {
+        let claims = json!({ "sub": "john", "roles": ["owner"] });
+        assert_eq!(config().forwarded_claims(Some(claims.clone())), None);
+
+        let config = serde_json::from_value::<Config>(json!({ "forward_claims": true }))
+            .expect("Failed to parse config");
+        assert_eq!(config.forwarded_claims(Some(claims.clone())), Some(claims));
+        assert_eq!(config.forwarded_claims(None), None);
+    }
+}
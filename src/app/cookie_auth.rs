@@ -0,0 +1,206 @@
+use std::sync::Arc;
+
+use http::header::{self, HeaderMap, HeaderName, HeaderValue};
+use http::{Method, Request, Response};
+use ring::constant_time;
+use serde_derive::Deserialize;
+use tower_service::Service;
+use tower_web::middleware::Middleware;
+
+use crate::app::api_key;
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Reading the access token from an HttpOnly cookie for browser clients.
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct Config {
+    /// The cookie holding the access token.
+    name: String,
+    /// The cookie readable by scripts which value must be sent in `csrf_header`.
+    #[serde(default = "Config::default_csrf_cookie")]
+    csrf_cookie: String,
+    #[serde(deserialize_with = "crate::serde::header_name")]
+    #[serde(default = "Config::default_csrf_header")]
+    csrf_header: HeaderName,
+}
+
+impl Config {
+    fn default_csrf_cookie() -> String {
+        String::from("csrf_token")
+    }
+
+    fn default_csrf_header() -> HeaderName {
+        HeaderName::from_static("x-csrf-token")
+    }
+
+    pub(crate) fn csrf_header(&self) -> &HeaderName {
+        &self.csrf_header
+    }
+
+    // Double submit: the header must repeat the CSRF cookie which only same-site scripts can read.
+    fn check_csrf(&self, method: &Method, headers: &HeaderMap) -> Result<(), String> {
+        if method.is_safe() {
+            return Ok(());
+        }
+
+        let expected = cookie(headers, &self.csrf_cookie)
+            .filter(|value| !value.is_empty())
+            .ok_or_else(|| format!("missing '{}' cookie", self.csrf_cookie))?;
+
+        let actual = headers
+            .get(&self.csrf_header)
+            .ok_or_else(|| format!("missing '{}' header", self.csrf_header))?;
+
+        constant_time::verify_slices_are_equal(expected.as_bytes(), actual.as_bytes())
+            .map_err(|_| format!("'{}' header doesn't match the cookie", self.csrf_header))
+    }
+}
+
+fn cookie<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|pair| {
+            let mut parts = pair.trim().splitn(2, '=');
+            Some((parts.next()?, parts.next()?))
+        })
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Marks requests authenticated with the cookie and holds the result of the CSRF check.
+#[derive(Debug, Clone)]
+pub(crate) struct CookieToken(Result<(), String>);
+
+impl CookieToken {
+    pub(crate) fn csrf(&self) -> Result<(), &str> {
+        self.0.as_ref().map(|_| ()).map_err(String::as_str)
+    }
+}
+
+/// Passes the token from the cookie as a Bearer token so it's verified as usual.
+///
+/// Requests with an `Authorization` or `X-Api-Key` header are left as is.
+#[derive(Debug, Clone)]
+pub(crate) struct CookieAuthMiddleware {
+    config: Option<Arc<Config>>,
+}
+
+impl CookieAuthMiddleware {
+    pub(crate) fn new(config: Option<&Config>) -> Self {
+        Self {
+            config: config.map(|config| Arc::new(config.to_owned())),
+        }
+    }
+}
+
+impl<S, ReqBody, RespBody> Middleware<S> for CookieAuthMiddleware
+where
+    S: Service<Request = Request<ReqBody>, Response = Response<RespBody>>,
+{
+    type Request = Request<ReqBody>;
+    type Response = Response<RespBody>;
+    type Error = S::Error;
+    type Service = CookieAuthService<S>;
+
+    fn wrap(&self, inner: S) -> Self::Service {
+        CookieAuthService {
+            inner,
+            config: self.config.clone(),
+        }
+    }
+}
+
+pub(crate) struct CookieAuthService<S> {
+    inner: S,
+    config: Option<Arc<Config>>,
+}
+
+impl<S, ReqBody, RespBody> Service for CookieAuthService<S>
+where
+    S: Service<Request = Request<ReqBody>, Response = Response<RespBody>>,
+{
+    type Request = Request<ReqBody>;
+    type Response = Response<RespBody>;
+    type Error = S::Error;
+    type Future = S::Future;
+
+    fn poll_ready(&mut self) -> futures::Poll<(), Self::Error> {
+        self.inner.poll_ready()
+    }
+
+    fn call(&mut self, mut request: Self::Request) -> Self::Future {
+        if let Some(ref config) = self.config {
+            let headers = request.headers();
+
+            if !headers.contains_key(header::AUTHORIZATION)
+                && !headers.contains_key(api_key::HEADER)
+            {
+                let authorization = cookie(headers, &config.name)
+                    .and_then(|token| HeaderValue::from_str(&format!("Bearer {}", token)).ok());
+
+                if let Some(authorization) = authorization {
+                    let csrf = config.check_csrf(request.method(), request.headers());
+
+                    request
+                        .headers_mut()
+                        .insert(header::AUTHORIZATION, authorization);
+
+                    request.extensions_mut().insert(CookieToken(csrf));
+                }
+            }
+        }
+
+        self.inner.call(request)
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use super::*;
+
+    fn headers(pairs: &[(&str, &str)]) -> HeaderMap {
+        pairs
+            .iter()
+            .map(|(name, value)| {
+                (
+                    HeaderName::from_bytes(name.as_bytes()).expect("Invalid header name"),
+                    HeaderValue::from_str(value).expect("Invalid header value"),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn checks_csrf_of_unsafe_requests() {
+        let config = serde_json::from_value::<Config>(json!({ "name": "access_token" }))
+            .expect("Failed to parse config");
+
+        let valid = headers(&[
+            ("cookie", "access_token=abc; csrf_token=123"),
+            ("x-csrf-token", "123"),
+        ]);
+
+        assert_eq!(cookie(&valid, "access_token"), Some("abc"));
+        assert!(config.check_csrf(&Method::POST, &valid).is_ok());
+
+        let mismatch = headers(&[
+            ("cookie", "access_token=abc; csrf_token=123"),
+            ("x-csrf-token", "456"),
+        ]);
+
+        assert!(config.check_csrf(&Method::POST, &mismatch).is_err());
+        assert!(config.check_csrf(&Method::GET, &mismatch).is_ok());
+
+        let missing = headers(&[("cookie", "access_token=abc")]);
+        assert!(config.check_csrf(&Method::POST, &missing).is_err());
+    }
+}
@@ -0,0 +1,171 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use http::{Response as HttpResponse, StatusCode};
+use serde::ser::Serialize;
+use serde_derive::Deserialize;
+use svc_agent::AccountId;
+
+use crate::app::authn::Subject;
+use crate::app::endpoint::event::ConfigMap as EventsConfigMap;
+use crate::util::sink;
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct Config {
+    accounts: Vec<AccountId>,
+    events_path: Option<PathBuf>,
+    /// Directory `file` and `unix` sinks set through the admin API must be in.
+    /// Such sinks are only allowed in App.toml if it's not set.
+    sink_dir: Option<PathBuf>,
+}
+
+impl Config {
+    /// Checks that the account is allowed to call admin endpoints.
+    pub(crate) fn authorize(&self, account_id: &AccountId) -> Result<(), tower_web::Error> {
+        if self.accounts.contains(account_id) {
+            Ok(())
+        } else {
+            let detail = format!("account id = '{}' is not an admin", account_id);
+            Err(error(
+                StatusCode::FORBIDDEN,
+                "access_denied",
+                "Access denied",
+                &detail,
+            ))
+        }
+    }
+
+    /// Checks that a sink set through the admin API doesn't write outside of `sink_dir`.
+    pub(crate) fn check_sink(&self, sink: &sink::Config) -> Result<()> {
+        if sink.path().is_none() {
+            return Ok(());
+        }
+
+        match self.sink_dir {
+            Some(ref sink_dir) => sink.check_base_dir(sink_dir),
+            None => bail!(
+                "sink = '{}' is only allowed in App.toml unless 'admin.sink_dir' is set",
+                sink
+            ),
+        }
+    }
+
+    /// Loads events config previously persisted by the admin API if any.
+    pub(crate) fn load_events(&self) -> Result<Option<EventsConfigMap>> {
+        let path = match self.events_path {
+            Some(ref path) if path.exists() => path,
+            _ => return Ok(None),
+        };
+
+        let data = fs::read(path)
+            .with_context(|| format!("Failed to read events config from {}", path.display()))?;
+
+        let events = serde_json::from_slice::<EventsConfigMap>(&data)
+            .with_context(|| format!("Failed to parse events config from {}", path.display()))?;
+
+        Ok(Some(events))
+    }
+
+    /// Persists events config so that runtime changes survive restarts.
+    pub(crate) fn persist_events(&self, events: &EventsConfigMap) -> Result<()> {
+        let path = match self.events_path {
+            Some(ref path) => path,
+            None => return Ok(()),
+        };
+
+        let data =
+            serde_json::to_vec_pretty(events).context("Failed to serialize events config")?;
+
+        // Write to a temporary file first to avoid leaving a partially written config on failure.
+        let tmp_path = tmp_path(path);
+
+        fs::write(&tmp_path, data)
+            .with_context(|| format!("Failed to write events config to {}", tmp_path.display()))?;
+
+        fs::rename(&tmp_path, path)
+            .with_context(|| format!("Failed to move events config to {}", path.display()))
+    }
+}
+
+fn tmp_path(path: &Path) -> PathBuf {
+    let mut file_name = path.file_name().unwrap_or_default().to_owned();
+    file_name.push(".tmp");
+    path.with_file_name(file_name)
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Checks that the admin API is configured and the caller is allowed to call it.
+///
+/// API keys are only meant for `/api/v1/request` so they're denied regardless of the account.
+pub(crate) fn authorize(config: Option<&Config>, sub: &Subject) -> Result<(), tower_web::Error> {
+    if sub.api_key().is_some() {
+        return Err(error(
+            StatusCode::FORBIDDEN,
+            "access_denied",
+            "Access denied",
+            "API keys are not allowed to call admin API",
+        ));
+    }
+
+    match config {
+        Some(config) => config.authorize(sub.account_id()),
+        None => Err(error(
+            StatusCode::NOT_FOUND,
+            "admin_api_disabled",
+            "Admin API disabled",
+            "admin API is not configured",
+        )),
+    }
+}
+
+/// Responds as if there were no route of the group the listener doesn't serve.
+pub(crate) fn ensure_enabled(enabled: bool) -> Result<(), tower_web::Error> {
+    if enabled {
+        Ok(())
+    } else {
+        Err(error(
+            StatusCode::NOT_FOUND,
+            "not_found",
+            "Not found",
+            "the route is not served on this listener",
+        ))
+    }
+}
+
+pub(crate) fn error(status: StatusCode, kind: &str, title: &str, detail: &str) -> tower_web::Error {
+    tower_web::Error::builder()
+        .status(status)
+        .kind(kind, title)
+        .detail(detail)
+        .build()
+}
+
+pub(crate) fn json_response<T: Serialize>(
+    status: StatusCode,
+    value: &T,
+) -> Result<HttpResponse<String>, tower_web::Error> {
+    let body = serde_json::to_string(value).map_err(|err| {
+        error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "serialization_error",
+            "Serialization error",
+            &err.to_string(),
+        )
+    })?;
+
+    HttpResponse::builder()
+        .status(status)
+        .body(body)
+        .map_err(|err| {
+            error(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "http_response_build_error",
+                "Failed to build HTTP response",
+                &err.to_string(),
+            )
+        })
+}
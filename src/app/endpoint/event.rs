@@ -1,34 +1,143 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::convert::TryFrom;
 
-use anyhow::{bail, format_err, Result};
+use anyhow::{bail, Context, Result};
+use log::{debug, error, info};
+use serde_derive::{Deserialize, Serialize};
 use serde_json::Value as JsonValue;
-use svc_agent::{AccountId, Authenticable};
+use svc_agent::mqtt::{Agent, QoS};
+use svc_agent::{AccountId, Authenticable, SharedGroup, Subscription};
 
+use super::reply;
 use crate::util::headers::Headers;
+use crate::util::health::Health;
 use crate::util::http_stream::OutgoingMessage;
+use crate::util::sink;
+use crate::util::topic::{self, TopicPattern, Variables};
 
 ////////////////////////////////////////////////////////////////////////////////
 
+const DEFAULT_TOPIC: &str = "audiences/{audience}/events";
+const AUDIENCE_VARIABLE: &str = "audience";
+
 pub(crate) type ConfigMap = HashMap<String, Config>;
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub(crate) struct Config {
-    callback: String,
+    #[serde(default)]
+    callback: Option<String>,
+    #[serde(default)]
+    sink: sink::Config,
     sources: Vec<SourceConfig>,
+    #[serde(deserialize_with = "crate::serde::topic_pattern")]
+    #[serde(serialize_with = "crate::serde::display")]
+    #[serde(default = "Config::default_topic")]
+    topic: TopicPattern,
+    #[serde(default)]
+    filter: HashMap<String, Vec<String>>,
+    replies: Option<reply::Config>,
 }
 
 impl Config {
-    pub(crate) fn callback(&self) -> &str {
-        &self.callback
+    pub(crate) fn callback(&self) -> Option<&str> {
+        self.callback.as_deref()
+    }
+
+    pub(crate) fn sink(&self) -> &sink::Config {
+        &self.sink
+    }
+
+    /// Allowlist of MQTT messages the tenant may publish by replying to callbacks if enabled.
+    pub(crate) fn replies(&self) -> Option<&reply::Config> {
+        self.replies.as_ref()
     }
 
     pub(crate) fn sources(&self) -> &Vec<SourceConfig> {
         &self.sources
     }
+
+    /// Topic pattern with the `{audience}` placeholder bound to the tenant audience.
+    pub(crate) fn topic(&self, audience: &str) -> TopicPattern {
+        let mut vars = Variables::new();
+        vars.insert(AUDIENCE_VARIABLE.to_owned(), audience.to_owned());
+        self.topic.bind(&vars)
+    }
+
+    /// Checks the fields which depend on each other or can't be checked by the deserializer.
+    pub(crate) fn validate(&self) -> Result<()> {
+        match self.callback {
+            Some(ref callback) => {
+                // Placeholders are bound on delivery to the audience and values captured from
+                // the topic so a sample value is put in their place.
+                let vars = self
+                    .topic
+                    .variables()
+                    .chain(std::iter::once(AUDIENCE_VARIABLE))
+                    .map(|name| (name.to_owned(), "sample".to_owned()))
+                    .collect::<Variables>();
+
+                let sample = topic::render(callback, &vars).with_context(|| {
+                    format!(
+                        "invalid callback = '{}' for topic pattern = '{}'",
+                        callback, self.topic
+                    )
+                })?;
+
+                let url = reqwest::Url::parse(&sample)
+                    .with_context(|| format!("invalid callback = '{}'", callback))?;
+
+                if url.scheme() != "http" && url.scheme() != "https" {
+                    bail!("callback = '{}' must be an HTTP(S) URL", callback);
+                }
+            }
+            None if self.sink == sink::Config::Http => bail!("missing callback for HTTP sink"),
+            None => (),
+        }
+
+        for source in &self.sources {
+            if source.version.trim().is_empty() {
+                bail!("missing version of source = '{}'", source.account_id);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn default_topic() -> TopicPattern {
+        DEFAULT_TOPIC
+            .parse()
+            .expect("Failed to parse default topic pattern")
+    }
+
+    fn is_allowed(&self, vars: &Variables) -> bool {
+        self.filter
+            .iter()
+            .all(|(name, allowed)| match vars.get(name) {
+                Some(value) => allowed.contains(value),
+                None => false,
+            })
+    }
+}
+
+/// Checks that the audience has no MQTT wildcards or level separators since it's put into topic
+/// filters and would otherwise subscribe to topics of other tenants.
+pub(crate) fn validate_audience(audience: &str) -> Result<()> {
+    let is_valid = !audience.is_empty()
+        && audience
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-' || c == '_');
+
+    if !is_valid {
+        bail!(
+            "invalid audience = '{}', only letters, digits, '.', '-' and '_' are allowed",
+            audience
+        );
+    }
+
+    Ok(())
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub(crate) struct SourceConfig {
     account_id: AccountId,
     version: String,
@@ -50,119 +159,437 @@ type IncomingEvent = svc_agent::mqtt::IncomingEvent<JsonValue>;
 
 ////////////////////////////////////////////////////////////////////////////////
 
+struct Route {
+    audience: String,
+    source: AccountId,
+    pattern: TopicPattern,
+}
+
+impl Route {
+    /// Values of the topic's placeholders and the audience, bound before building the pattern.
+    fn captures(&self, topic: &str) -> Option<Variables> {
+        let mut vars = self.pattern.captures(topic)?;
+        vars.insert(AUDIENCE_VARIABLE.to_owned(), self.audience.to_owned());
+        Some(vars)
+    }
+}
+
 pub(crate) struct State {
     config: ConfigMap,
     tokens: HashMap<String, String>,
+    routes: Vec<Route>,
 }
 
 impl State {
     pub(crate) fn new(config: ConfigMap, tokens: HashMap<String, String>) -> Self {
-        Self { config, tokens }
+        let routes = Self::build_routes(&config);
+
+        Self {
+            config,
+            tokens,
+            routes,
+        }
     }
 
-    pub(crate) fn handle(&self, topic: &str, inev: &IncomingEvent) -> Result<OutgoingMessage> {
-        let from_account_id = inev.properties().as_account_id();
-        let audience = extract_audience(topic)?;
+    pub(crate) fn config(&self) -> &ConfigMap {
+        &self.config
+    }
 
-        let config = self.config.get(audience).ok_or_else(|| {
-            format_err!(
-                "sending events for audience = '{}' is not allowed",
-                audience
-            )
-        })?;
+    pub(crate) fn token(&self, audience: &str) -> Option<&str> {
+        self.tokens.get(audience).map(|token| token.as_str())
+    }
 
-        if !config
-            .sources()
+    /// Adds or replaces the tenant's events config.
+    pub(crate) fn update(&mut self, audience: &str, config: Config, token: String) {
+        self.config.insert(audience.to_owned(), config);
+        self.tokens.insert(audience.to_owned(), token);
+        self.routes = Self::build_routes(&self.config);
+    }
+
+    pub(crate) fn remove(&mut self, audience: &str) -> Option<Config> {
+        let config = self.config.remove(audience);
+        self.tokens.remove(audience);
+        self.routes = Self::build_routes(&self.config);
+        config
+    }
+
+    fn build_routes(config: &ConfigMap) -> Vec<Route> {
+        let mut routes = Vec::new();
+
+        for (audience, tenant_config) in config {
+            for source in tenant_config.sources() {
+                // Events are published by services to `apps/ACCOUNT_ID/api/VERSION/URI`.
+                let prefix = [
+                    "apps".to_owned(),
+                    source.account_id().to_string(),
+                    "api".to_owned(),
+                    source.version().to_owned(),
+                ];
+
+                routes.push(Route {
+                    audience: audience.to_owned(),
+                    source: source.account_id().to_owned(),
+                    pattern: tenant_config.topic(audience).prefixed(&prefix),
+                });
+            }
+        }
+
+        routes
+    }
+
+    /// Audiences the event sent to the topic by the account is routed to, sorted.
+    pub(crate) fn audiences(&self, topic: &str, from: &AccountId) -> Vec<&str> {
+        let mut audiences = self
+            .routes
             .iter()
-            .any(|s| s.account_id() == from_account_id)
-        {
+            .filter(|route| &route.source == from && route.pattern.captures(topic).is_some())
+            .map(|route| route.audience.as_str())
+            .collect::<Vec<_>>();
+
+        audiences.sort_unstable();
+        audiences.dedup();
+        audiences
+    }
+
+    pub(crate) fn handle(&self, topic: &str, inev: &IncomingEvent) -> Result<Vec<OutgoingMessage>> {
+        let from_account_id = inev.properties().as_account_id();
+        let mut matched = false;
+        let mut messages = Vec::new();
+
+        for route in &self.routes {
+            if &route.source != from_account_id {
+                continue;
+            }
+
+            let vars = match route.captures(topic) {
+                Some(vars) => vars,
+                None => continue,
+            };
+
+            matched = true;
+
+            let config = match self.config.get(&route.audience) {
+                Some(config) => config,
+                None => bail!("missing config for audience = '{}'", route.audience),
+            };
+
+            if !config.is_allowed(&vars) {
+                debug!(
+                    "Event sent to the topic = '{}' is filtered out for audience = '{}'",
+                    topic, route.audience
+                );
+
+                continue;
+            }
+
+            let token = match self.tokens.get(&route.audience) {
+                Some(value) => value,
+                None => bail!("missing token for audience = '{}'", route.audience),
+            };
+
+            let callback = match config.callback() {
+                Some(callback) => Some(topic::render(callback, &vars)?),
+                None if config.sink() == &sink::Config::Http => {
+                    bail!("missing callback for audience = '{}'", route.audience)
+                }
+                None => None,
+            };
+
+            let message = OutgoingMessage::new(
+                inev.payload().clone(),
+                Headers::try_from(inev)?,
+                &route.audience,
+                config.sink(),
+                callback,
+                token,
+            );
+
+            messages.push(match config.replies() {
+                Some(_) => message.expect_reply(vars),
+                None => message,
+            });
+        }
+
+        if !matched {
             bail!(
-                "sending events for audience = '{}' from application = '{}' is not allowed",
-                audience,
+                "sending events to the topic = '{}' from application = '{}' is not allowed",
+                topic,
                 from_account_id
             );
         }
 
-        let token = match self.tokens.get(audience) {
-            Some(value) => value,
-            None => bail!("missing token for audience = '{}'", audience),
-        };
-
-        Ok(OutgoingMessage::new(
-            inev.payload().clone(),
-            Headers::try_from(inev)?,
-            config.callback(),
-            token,
-        ))
+        Ok(messages)
     }
 }
 
 //////////////////////////////////////////////////////////////////////////////////
 
-fn extract_audience(topic: &str) -> Result<&str> {
-    use std::ffi::OsStr;
-    use std::path::{Component, Path};
+/// Broker subscription to events of a single source.
+#[derive(Debug, Clone)]
+pub(crate) struct EventSubscription {
+    account_id: AccountId,
+    version: String,
+    uri: String,
+}
 
-    let topic_path = Path::new(topic);
-    let mut topic = topic_path.components();
+impl EventSubscription {
+    pub(crate) fn subscribe(&self, agent: &mut Agent, group: &SharedGroup) -> Result<()> {
+        agent
+            .subscribe(
+                &Subscription::broadcast_events(&self.account_id, &self.version, &self.uri),
+                QoS::AtLeastOnce,
+                Some(group),
+            )
+            .with_context(|| format!("Error subscribing to app's events topic = '{}'", self.uri))
+    }
 
-    let events_literal = Some(Component::Normal(OsStr::new("events")));
+    pub(crate) fn unsubscribe(&self, agent: &mut Agent, group: &SharedGroup) -> Result<()> {
+        agent
+            .unsubscribe(
+                &Subscription::broadcast_events(&self.account_id, &self.version, &self.uri),
+                Some(group),
+            )
+            .with_context(|| {
+                format!(
+                    "Error unsubscribing from app's events topic = '{}'",
+                    self.uri
+                )
+            })
+    }
+}
 
-    if topic.next_back() != events_literal {
-        bail!(
-            "topic does not match the pattern 'audiences/AUDIENCE/events': {}",
-            topic_path.display()
-        );
+/// Broker subscriptions required by the config keyed by their topic filters.
+///
+/// Tenants having the same topic pattern and sources share a subscription.
+pub(crate) fn subscriptions(config: &ConfigMap) -> BTreeMap<String, EventSubscription> {
+    let mut subscriptions = BTreeMap::new();
+
+    for (audience, tenant_config) in config {
+        let uri = tenant_config.topic(audience).to_filter();
+
+        for source in tenant_config.sources() {
+            let key = format!("{}/{}/{}", source.account_id(), source.version(), uri);
+
+            subscriptions.insert(
+                key,
+                EventSubscription {
+                    account_id: source.account_id().to_owned(),
+                    version: source.version().to_owned(),
+                    uri: uri.clone(),
+                },
+            );
+        }
     }
 
-    let maybe_audience = topic.next_back();
+    subscriptions
+}
 
-    let audiences_literal = Some(Component::Normal(OsStr::new("audiences")));
-    if topic.next_back() == audiences_literal {
-        match maybe_audience {
-            Some(Component::Normal(audience)) => {
-                let audience = audience.to_str().ok_or_else(|| {
-                    format_err!(
-                        "non utf-8 characters in audience name: {}",
-                        topic_path.display()
-                    )
-                })?;
+/// Subscribes to topics required by the new config and unsubscribes from the stale ones.
+pub(crate) fn update_subscriptions(
+    agent: &mut Agent,
+    group: &SharedGroup,
+    health: &Health,
+    old: &ConfigMap,
+    new: &ConfigMap,
+) -> Result<()> {
+    let old_subscriptions = subscriptions(old);
+    let new_subscriptions = subscriptions(new);
+
+    for (key, subscription) in &new_subscriptions {
+        if !old_subscriptions.contains_key(key) {
+            subscription.subscribe(agent, group)?;
+            health.subscription_requested(key);
+            info!("Subscribed to events = '{}'", key);
+        }
+    }
+
+    for (key, subscription) in &old_subscriptions {
+        if !new_subscriptions.contains_key(key) {
+            health.subscription_removed(key);
 
-                Ok(audience)
+            // Stale subscriptions only cause filtered out events so just report the failure.
+            match subscription.unsubscribe(agent, group) {
+                Ok(()) => info!("Unsubscribed from events = '{}'", key),
+                Err(err) => error!("{}", err),
             }
-            _ => bail!(
-                "topic does not match the pattern 'audiences/AUDIENCE/events': {}",
-                topic_path.display()
-            ),
         }
-    } else {
-        bail!(
-            "topic does not match the pattern 'audiences/AUDIENCE/events': {}",
-            topic_path.display()
-        );
     }
+
+    Ok(())
 }
 
 //////////////////////////////////////////////////////////////////////////////////
 
 #[cfg(test)]
 mod test {
+    use super::*;
+
+    fn parse_config(toml: &str) -> ConfigMap {
+        let mut parser = config::Config::default();
+        parser
+            .merge(config::File::from_str(toml, config::FileFormat::Toml))
+            .expect("Failed to parse config");
+
+        parser.try_into().expect("Failed to deserialize config")
+    }
+
     #[test]
-    fn extracts_audience() {
-        let topic = "test/test/test/audiences/test-audience/events";
-        let res = super::extract_audience(topic);
-
-        assert!(res.is_ok());
-        assert_eq!("test-audience", res.unwrap());
-
-        let topic = "test/test/test/audiences";
-        let res = super::extract_audience(topic);
-        assert!(res.is_err());
-        dbg!(res);
-
-        let topic = "test/test/test/audiences/events";
-        let res = super::extract_audience(topic);
-        assert!(res.is_err());
-        dbg!(res);
+    fn defaults_to_audience_topic() {
+        let config = parse_config(
+            r#"
+            ["example.org"]
+            callback = "https://example.org/callback"
+
+            [["example.org".sources]]
+            account_id = "conference.svc.example.org"
+            version = "v1"
+            "#,
+        );
+
+        let tenant_config = config.get("example.org").expect("Missing tenant config");
+
+        assert_eq!(
+            tenant_config.topic("example.org").to_filter(),
+            "audiences/example.org/events"
+        );
+    }
+
+    #[test]
+    fn routes_by_topic_pattern() {
+        let config = parse_config(
+            r#"
+            ["example.org"]
+            callback = "https://example.org/rooms/{room_id}/callback"
+            topic = "rooms/{room_id}/events"
+            filter = { room_id = ["123"] }
+
+            [["example.org".sources]]
+            account_id = "conference.svc.example.org"
+            version = "v1"
+            "#,
+        );
+
+        let state = State::new(config, HashMap::new());
+        let route = &state.routes[0];
+
+        let vars = route
+            .pattern
+            .captures("apps/conference.svc.example.org/api/v1/rooms/123/events")
+            .expect("Topic doesn't match");
+
+        let tenant_config = state.config.get(&route.audience).unwrap();
+        assert!(tenant_config.is_allowed(&vars));
+
+        assert_eq!(
+            topic::render(tenant_config.callback().unwrap(), &vars).unwrap(),
+            "https://example.org/rooms/123/callback"
+        );
+
+        let vars = route
+            .pattern
+            .captures("apps/conference.svc.example.org/api/v1/rooms/456/events")
+            .expect("Topic doesn't match");
+
+        assert!(!tenant_config.is_allowed(&vars));
+
+        let conference = AccountId::new("conference", "svc.example.org");
+        let topic = "apps/conference.svc.example.org/api/v1/rooms/123/events";
+        assert_eq!(state.audiences(topic, &conference), vec!["example.org"]);
+
+        let other = AccountId::new("other", "svc.example.org");
+        assert!(state.audiences(topic, &other).is_empty());
+    }
+
+    #[test]
+    fn renders_audience_into_callback() {
+        let config = parse_config(
+            r#"
+            ["example.org"]
+            callback = "https://{audience}/rooms/{room_id}/callback"
+            topic = "audiences/{audience}/rooms/{room_id}/events"
+
+            [["example.org".sources]]
+            account_id = "conference.svc.example.org"
+            version = "v1"
+            "#,
+        );
+
+        assert!(config["example.org"].validate().is_ok());
+
+        let state = State::new(config, HashMap::new());
+        let route = &state.routes[0];
+
+        let vars = route
+            .captures(
+                "apps/conference.svc.example.org/api/v1/audiences/example.org/rooms/123/events",
+            )
+            .expect("Topic doesn't match");
+
+        let tenant_config = state.config.get(&route.audience).unwrap();
+
+        assert_eq!(
+            topic::render(tenant_config.callback().unwrap(), &vars).unwrap(),
+            "https://example.org/rooms/123/callback"
+        );
+    }
+
+    #[test]
+    fn validates_callback_and_sources() {
+        let config = parse_config(
+            r#"
+            ["example.org"]
+            callback = "https://{audience}.example.org/rooms/{room_id}/callback"
+            topic = "rooms/{room_id}/events"
+
+            [["example.org".sources]]
+            account_id = "conference.svc.example.org"
+            version = "v1"
+
+            ["example.ru"]
+            callback = "https://example.ru/rooms/{room_id}/callback"
+
+            [["example.ru".sources]]
+            account_id = "conference.svc.example.org"
+            version = "v1"
+
+            ["example.net"]
+            callback = "example.net/callback"
+
+            [["example.net".sources]]
+            account_id = "conference.svc.example.org"
+            version = "v1"
+
+            ["example.com"]
+            sink = { type = "stdout" }
+
+            [["example.com".sources]]
+            account_id = "conference.svc.example.org"
+            version = ""
+            "#,
+        );
+
+        assert!(config["example.org"].validate().is_ok());
+        // The default topic pattern has no `{room_id}` placeholder.
+        assert!(config["example.ru"].validate().is_err());
+        assert!(config["example.net"].validate().is_err());
+        assert!(config["example.com"].validate().is_err());
+    }
+
+    #[test]
+    fn rejects_audiences_with_wildcards() {
+        assert!(validate_audience("example.org").is_ok());
+        assert!(validate_audience("usr_1.example-test.org").is_ok());
+
+        for audience in &[
+            "",
+            "+",
+            "#",
+            "example.org/#",
+            "a/b",
+            "example+org",
+            "{audience}",
+        ] {
+            assert!(validate_audience(audience).is_err(), "{}", audience);
+        }
     }
 }
@@ -0,0 +1,129 @@
+use std::sync::Arc;
+
+use http::{Response as HttpResponse, StatusCode};
+use serde_derive::{Deserialize, Serialize};
+
+use crate::app::endpoint::admin::{ensure_enabled, json_response};
+use crate::app::State;
+use crate::util::metrics::Metrics;
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct Config {
+    /// Readiness fails while more events than this are waiting to be delivered.
+    #[serde(default = "Config::default_max_callback_backlog")]
+    max_callback_backlog: i64,
+}
+
+impl Config {
+    fn default_max_callback_backlog() -> i64 {
+        10_000
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            max_callback_backlog: Self::default_max_callback_backlog(),
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Debug, Serialize)]
+struct Readiness {
+    ready: bool,
+    reasons: Vec<String>,
+}
+
+/// Liveness and readiness probes.
+pub(crate) struct Health {
+    config: Config,
+    state: Arc<State>,
+    metrics: Arc<Metrics>,
+    enabled: bool,
+}
+
+impl Health {
+    pub(crate) fn new(
+        config: Config,
+        state: Arc<State>,
+        metrics: Arc<Metrics>,
+        enabled: bool,
+    ) -> Self {
+        Self {
+            config,
+            state,
+            metrics,
+            enabled,
+        }
+    }
+
+    fn readiness(&self) -> Readiness {
+        let mut reasons = Vec::new();
+
+        if self.state.health.is_shutting_down() {
+            reasons.push("shutting down".to_owned());
+        }
+
+        if !self.state.health.is_connected() {
+            reasons.push("disconnected from broker".to_owned());
+        }
+
+        if !self.state.health.is_subscribed() {
+            let missing = self.state.health.missing_subscriptions();
+
+            if missing.is_empty() {
+                reasons.push("no subscriptions".to_owned());
+            } else {
+                reasons.push(format!(
+                    "subscriptions = '{}' are not confirmed",
+                    missing.join(", ")
+                ));
+            }
+        }
+
+        let backlog = self.metrics.outgoing_queue_depth();
+
+        if backlog > self.config.max_callback_backlog {
+            reasons.push(format!(
+                "callback backlog = '{}' is over the threshold = '{}'",
+                backlog, self.config.max_callback_backlog
+            ));
+        }
+
+        Readiness {
+            ready: reasons.is_empty(),
+            reasons,
+        }
+    }
+}
+
+impl_web! {
+    impl Health {
+        #[get("/healthz")]
+        #[content_type("application/json")]
+        fn healthz(&self) -> Result<HttpResponse<String>, tower_web::Error> {
+            ensure_enabled(self.enabled)?;
+            json_response(StatusCode::OK, &serde_json::json!({"alive": true}))
+        }
+
+        #[get("/readyz")]
+        #[content_type("application/json")]
+        fn readyz(&self) -> Result<HttpResponse<String>, tower_web::Error> {
+            ensure_enabled(self.enabled)?;
+
+            let readiness = self.readiness();
+
+            let status = if readiness.ready {
+                StatusCode::OK
+            } else {
+                StatusCode::SERVICE_UNAVAILABLE
+            };
+
+            json_response(status, &readiness)
+        }
+    }
+}
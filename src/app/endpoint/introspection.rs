@@ -0,0 +1,142 @@
+use std::sync::Arc;
+
+use futures::Future;
+use futures_locks::Mutex;
+use http::{Response as HttpResponse, StatusCode};
+use serde_derive::Serialize;
+
+use crate::app::authn::Subject;
+use crate::app::config::Config as AppConfig;
+use crate::app::endpoint::admin::{self, error, json_response};
+use crate::app::State;
+use crate::util::health::SubscriptionStatus;
+use crate::util::http_stream::OutgoingStream;
+use crate::util::mqtt_request::Adapter;
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Debug, Serialize)]
+struct SubscriptionInfo {
+    topic: String,
+    status: SubscriptionStatus,
+}
+
+#[derive(Debug, Serialize)]
+struct Version {
+    version: &'static str,
+}
+
+/// Admin API showing the gateway's internal state for debugging.
+pub(crate) struct Introspection {
+    config: Arc<AppConfig>,
+    state: Arc<State>,
+    req_tx: Mutex<Adapter>,
+    hq_tx: OutgoingStream,
+    enabled: bool,
+}
+
+impl Introspection {
+    pub(crate) fn new(
+        config: Arc<AppConfig>,
+        state: Arc<State>,
+        req_tx: Mutex<Adapter>,
+        hq_tx: OutgoingStream,
+        enabled: bool,
+    ) -> Self {
+        Self {
+            config,
+            state,
+            req_tx,
+            hq_tx,
+            enabled,
+        }
+    }
+
+    fn authorize(&self, sub: &Subject) -> Result<(), tower_web::Error> {
+        admin::ensure_enabled(self.enabled)?;
+        admin::authorize(self.config.admin.as_ref(), sub)
+    }
+
+    fn lock_error() -> tower_web::Error {
+        error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "lock_error",
+            "Lock error",
+            "error acquiring a lock for MQTT request adapter",
+        )
+    }
+}
+
+impl_web! {
+    impl Introspection {
+        #[get("/api/v1/admin/subscriptions")]
+        #[content_type("application/json")]
+        fn subscriptions(&self, sub: Subject) -> Result<HttpResponse<String>, tower_web::Error> {
+            self.authorize(&sub)?;
+
+            let subscriptions = self
+                .state
+                .health
+                .subscriptions()
+                .into_iter()
+                .map(|(topic, status)| SubscriptionInfo { topic, status })
+                .collect::<Vec<_>>();
+
+            json_response(StatusCode::OK, &subscriptions)
+        }
+
+        #[get("/api/v1/admin/requests")]
+        #[content_type("application/json")]
+        fn requests(
+            &self,
+            sub: Subject,
+        ) -> impl Future<Item = Result<HttpResponse<String>, tower_web::Error>, Error = ()> {
+            let authorized = self.authorize(&sub);
+
+            self.req_tx.lock().then(move |adapter| {
+                let response = authorized.and_then(|()| {
+                    let adapter = adapter.map_err(|()| Self::lock_error())?;
+                    json_response(StatusCode::OK, &adapter.pending())
+                });
+
+                Ok(response)
+            })
+        }
+
+        #[get("/api/v1/admin/callbacks")]
+        #[content_type("application/json")]
+        fn callbacks(&self, sub: Subject) -> Result<HttpResponse<String>, tower_web::Error> {
+            self.authorize(&sub)?;
+            json_response(StatusCode::OK, &self.hq_tx.audiences())
+        }
+
+        #[get("/api/v1/admin/config")]
+        #[content_type("application/json")]
+        fn config(&self, sub: Subject) -> Result<HttpResponse<String>, tower_web::Error> {
+            self.authorize(&sub)?;
+
+            let mut config = self.config.redacted.value().to_owned();
+
+            // Events may have been changed at runtime through the admin API.
+            if let Ok(event_state) = self.state.event.read() {
+                if let Ok(events) = serde_json::to_value(event_state.config()) {
+                    config["events"] = events;
+                }
+            }
+
+            json_response(StatusCode::OK, &config)
+        }
+
+        #[get("/api/v1/admin/version")]
+        #[content_type("application/json")]
+        fn version(&self, sub: Subject) -> Result<HttpResponse<String>, tower_web::Error> {
+            self.authorize(&sub)?;
+
+            let version = Version {
+                version: env!("CARGO_PKG_VERSION"),
+            };
+
+            json_response(StatusCode::OK, &version)
+        }
+    }
+}
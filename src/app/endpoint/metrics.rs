@@ -0,0 +1,50 @@
+use std::sync::Arc;
+
+use http::{header, Response as HttpResponse, StatusCode};
+
+use crate::app::endpoint::admin::{ensure_enabled, error};
+use crate::app::State;
+use crate::util::metrics::Metrics;
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Prometheus scrape endpoint.
+pub(crate) struct MetricsResource {
+    metrics: Arc<Metrics>,
+    state: Arc<State>,
+    enabled: bool,
+}
+
+impl MetricsResource {
+    pub(crate) fn new(metrics: Arc<Metrics>, state: Arc<State>, enabled: bool) -> Self {
+        Self {
+            metrics,
+            state,
+            enabled,
+        }
+    }
+}
+
+impl_web! {
+    impl MetricsResource {
+        #[get("/metrics")]
+        fn metrics(&self) -> Result<HttpResponse<String>, tower_web::Error> {
+            ensure_enabled(self.enabled)?;
+
+            // Subscriptions change in several places so their state is collected on scrape.
+            self.metrics.set_subscriptions(&self.state.health.subscriptions());
+
+            let text = self.metrics.encode().map_err(|err| {
+                error(StatusCode::INTERNAL_SERVER_ERROR, "metrics_error", "Metrics error", &err.to_string())
+            })?;
+
+            HttpResponse::builder()
+                .status(StatusCode::OK)
+                .header(header::CONTENT_TYPE, "text/plain; version=0.0.4")
+                .body(text)
+                .map_err(|err| {
+                    error(StatusCode::INTERNAL_SERVER_ERROR, "metrics_error", "Metrics error", &err.to_string())
+                })
+        }
+    }
+}
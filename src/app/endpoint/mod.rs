@@ -1 +1,7 @@
+pub(crate) mod admin;
 pub(crate) mod event;
+pub(crate) mod health;
+pub(crate) mod introspection;
+pub(crate) mod metrics;
+pub(crate) mod reply;
+pub(crate) mod tenant;
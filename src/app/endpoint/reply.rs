@@ -0,0 +1,181 @@
+use anyhow::{bail, format_err, Context, Result};
+use chrono::Utc;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value as JsonValue;
+use svc_agent::mqtt::{
+    Agent, OutgoingEvent, OutgoingEventProperties, OutgoingRequest, OutgoingRequestProperties,
+    ShortTermTimingProperties, SubscriptionTopic,
+};
+use svc_agent::{AccountId, AgentId, ResponseSubscription, Source};
+use uuid::Uuid;
+
+use crate::app::{tenant_account_id, State, API_VERSION};
+use crate::util::http_stream::CallbackReply;
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Allowlist of MQTT messages a tenant may publish by replying to a callback.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub(crate) struct Config {
+    #[serde(default)]
+    requests: Vec<RequestRule>,
+    #[serde(default)]
+    events: Vec<String>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub(crate) struct RequestRule {
+    destination: AccountId,
+    methods: Vec<String>,
+}
+
+impl Config {
+    fn allows_request(&self, destination: &AccountId, method: &str) -> bool {
+        self.requests.iter().any(|rule| {
+            &rule.destination == destination && rule.methods.iter().any(|m| m == method)
+        })
+    }
+
+    fn allows_event(&self, label: &str) -> bool {
+        self.events.iter().any(|l| l == label)
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Reply {
+    Request {
+        method: String,
+        destination: AccountId,
+        #[serde(default)]
+        payload: JsonValue,
+    },
+    Event {
+        label: String,
+        #[serde(default)]
+        payload: JsonValue,
+    },
+}
+
+/// Publishes the tenant's callback reply to MQTT as the gateway's agent on behalf of the tenant.
+pub(crate) fn handle(
+    agent: &mut Agent,
+    state: &State,
+    id: &AccountId,
+    agent_label: &str,
+    reply: CallbackReply,
+) -> Result<()> {
+    let audience = reply.audience();
+
+    let (config, topic) = {
+        let event_state = state
+            .event
+            .read()
+            .map_err(|_| format_err!("error acquiring a lock for events state"))?;
+
+        let tenant_config = event_state
+            .config()
+            .get(audience)
+            .ok_or_else(|| format_err!("missing config for audience = '{}'", audience))?;
+
+        let config = tenant_config
+            .replies()
+            .cloned()
+            .ok_or_else(|| format_err!("replies are not enabled for audience = '{}'", audience))?;
+
+        (config, tenant_config.topic(audience).bind(reply.vars()))
+    };
+
+    let tenant_agent_id = AgentId::new(agent_label, tenant_account_id(id, audience));
+
+    match serde_json::from_value::<Reply>(reply.payload().clone())
+        .context("Failed to parse callback reply")?
+    {
+        Reply::Request {
+            method,
+            destination,
+            payload,
+        } => {
+            if !config.allows_request(&destination, &method) {
+                bail!(
+                    "request with method = '{}' to '{}' is not allowed for audience = '{}'",
+                    method,
+                    destination,
+                    audience
+                );
+            }
+
+            // Responses are delivered to the gateway and dropped as there's no one waiting for them.
+            let response_topic = ResponseSubscription::new(Source::Unicast(Some(&destination)))
+                .subscription_topic(agent.id(), API_VERSION)
+                .map_err(|err| format_err!("error building response topic: {}", err))?;
+
+            let mut props = OutgoingRequestProperties::new(
+                &method,
+                &response_topic,
+                &Uuid::new_v4().to_string(),
+                ShortTermTimingProperties::new(Utc::now()),
+            );
+
+            props.set_agent_id(tenant_agent_id);
+            let req = OutgoingRequest::multicast(payload, props, &destination);
+            agent.publish(req).context("Failed to publish request")
+        }
+        Reply::Event { label, payload } => {
+            if !config.allows_event(&label) {
+                bail!(
+                    "event with label = '{}' is not allowed for audience = '{}'",
+                    label,
+                    audience
+                );
+            }
+
+            let props =
+                OutgoingEventProperties::new(&label, ShortTermTimingProperties::new(Utc::now()));
+
+            let uri = topic.to_topic()?;
+            let event = OutgoingEvent::broadcast(payload, props, &uri);
+            agent.publish(event).context("Failed to publish event")
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn checks_allowlist() {
+        let config = serde_json::from_value::<Config>(json!({
+            "requests": [{"destination": "conference.svc.example.org", "methods": ["room.update"]}],
+            "events": ["room.notify"],
+        }))
+        .unwrap();
+
+        let destination = AccountId::new("conference", "svc.example.org");
+        assert!(config.allows_request(&destination, "room.update"));
+        assert!(!config.allows_request(&destination, "room.delete"));
+        assert!(config.allows_event("room.notify"));
+        assert!(!config.allows_event("room.close"));
+    }
+
+    #[test]
+    fn parses_reply() {
+        let reply = serde_json::from_value::<Reply>(json!({
+            "method": "room.update",
+            "destination": "conference.svc.example.org",
+            "payload": {"id": 1},
+        }))
+        .unwrap();
+
+        assert!(matches!(reply, Reply::Request { .. }));
+
+        let reply = serde_json::from_value::<Reply>(json!({"label": "room.notify"})).unwrap();
+        assert!(matches!(reply, Reply::Event { .. }));
+    }
+}
@@ -0,0 +1,209 @@
+use std::sync::{Arc, RwLockReadGuard, RwLockWriteGuard};
+
+use http::{Response as HttpResponse, StatusCode};
+use log::{error, info};
+
+use crate::app::authn::Subject;
+use crate::app::config::Config as AppConfig;
+use crate::app::endpoint::admin::{self, error, json_response};
+use crate::app::endpoint::event;
+use crate::util::agent::AgentHandle;
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Admin API to manage tenants' events config at runtime.
+pub(crate) struct Tenants {
+    config: Arc<AppConfig>,
+    state: Arc<crate::app::State>,
+    agent: AgentHandle,
+    enabled: bool,
+}
+
+impl Tenants {
+    pub(crate) fn new(
+        config: Arc<AppConfig>,
+        state: Arc<crate::app::State>,
+        agent: AgentHandle,
+        enabled: bool,
+    ) -> Self {
+        Self {
+            config,
+            state,
+            agent,
+            enabled,
+        }
+    }
+
+    fn authorize(&self, sub: &Subject) -> Result<(), tower_web::Error> {
+        admin::ensure_enabled(self.enabled)?;
+        admin::authorize(self.config.admin.as_ref(), sub)
+    }
+
+    fn read_state(&self) -> Result<RwLockReadGuard<event::State>, tower_web::Error> {
+        self.state.event.read().map_err(|_| {
+            let detail = "error acquiring a lock for events state";
+            error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "lock_error",
+                "Lock error",
+                detail,
+            )
+        })
+    }
+
+    fn write_state(&self) -> Result<RwLockWriteGuard<event::State>, tower_web::Error> {
+        self.state.event.write().map_err(|_| {
+            let detail = "error acquiring a lock for events state";
+            error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "lock_error",
+                "Lock error",
+                detail,
+            )
+        })
+    }
+
+    fn update_subscriptions(
+        &self,
+        old: &event::ConfigMap,
+        new: &event::ConfigMap,
+    ) -> Result<(), tower_web::Error> {
+        let mut agent = self.agent.get();
+        let group = crate::app::shared_group(self.agent.id());
+
+        event::update_subscriptions(&mut agent, &group, &self.state.health, old, new).map_err(
+            |err| {
+                error(
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    "subscription_error",
+                    "Subscription error",
+                    &err.to_string(),
+                )
+            },
+        )
+    }
+
+    fn validate_audience(&self, audience: &str) -> Result<(), tower_web::Error> {
+        event::validate_audience(audience).map_err(|err| {
+            error(
+                StatusCode::BAD_REQUEST,
+                "invalid_audience",
+                "Invalid audience",
+                &err.to_string(),
+            )
+        })
+    }
+
+    fn persist(&self, events: &event::ConfigMap) -> Result<(), tower_web::Error> {
+        if let Some(ref admin) = self.config.admin {
+            admin.persist_events(events).map_err(|err| {
+                error(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "persistence_error",
+                    "Failed to persist events config",
+                    &err.to_string(),
+                )
+            })?;
+        }
+
+        Ok(())
+    }
+
+    /// Persists the new events config and subscribes to its topics, the caller updates the state
+    /// after that. Failures leave the current config both live and persisted.
+    fn apply(
+        &self,
+        state: &event::State,
+        events: &event::ConfigMap,
+    ) -> Result<(), tower_web::Error> {
+        self.persist(events)?;
+
+        if let Err(err) = self.update_subscriptions(state.config(), events) {
+            if let Some(ref admin) = self.config.admin {
+                if let Err(err) = admin.persist_events(state.config()) {
+                    error!("Failed to restore the persisted events config, {:#}", err);
+                }
+            }
+
+            return Err(err);
+        }
+
+        Ok(())
+    }
+}
+
+impl_web! {
+    impl Tenants {
+        #[get("/api/v1/admin/tenants")]
+        #[content_type("application/json")]
+        fn list(&self, sub: Subject) -> Result<HttpResponse<String>, tower_web::Error> {
+            self.authorize(&sub)?;
+            let state = self.read_state()?;
+            json_response(StatusCode::OK, state.config())
+        }
+
+        #[put("/api/v1/admin/tenants/:audience")]
+        #[content_type("application/json")]
+        fn update(
+            &self,
+            audience: String,
+            body: Vec<u8>,
+            sub: Subject,
+        ) -> Result<HttpResponse<String>, tower_web::Error> {
+            self.authorize(&sub)?;
+            self.validate_audience(&audience)?;
+
+            let tenant_config = serde_json::from_slice::<event::Config>(&body).map_err(|err| {
+                error(StatusCode::BAD_REQUEST, "invalid_payload", "Invalid payload", &err.to_string())
+            })?;
+
+            tenant_config.validate().map_err(|err| {
+                error(StatusCode::BAD_REQUEST, "invalid_payload", "Invalid payload", &format!("{:#}", err))
+            })?;
+
+            if let Some(ref admin) = self.config.admin {
+                admin.check_sink(tenant_config.sink()).map_err(|err| {
+                    error(StatusCode::BAD_REQUEST, "invalid_sink", "Invalid sink", &format!("{:#}", err))
+                })?;
+            }
+
+            let token = crate::app::tenant_token(&self.config, &audience).map_err(|err| {
+                error(StatusCode::INTERNAL_SERVER_ERROR, "token_error", "Token error", &err.to_string())
+            })?;
+
+            let mut state = self.write_state()?;
+            let mut events = state.config().clone();
+            events.insert(audience.clone(), tenant_config.clone());
+            self.apply(&state, &events)?;
+            state.update(&audience, tenant_config.clone(), token);
+
+            info!("Tenant = '{}' updated by account = '{}'", audience, sub.account_id());
+            json_response(StatusCode::OK, &tenant_config)
+        }
+
+        #[delete("/api/v1/admin/tenants/:audience")]
+        #[content_type("application/json")]
+        fn delete(
+            &self,
+            audience: String,
+            sub: Subject,
+        ) -> Result<HttpResponse<String>, tower_web::Error> {
+            self.authorize(&sub)?;
+            self.validate_audience(&audience)?;
+
+            let mut state = self.write_state()?;
+            let mut events = state.config().clone();
+
+            let tenant_config = events.remove(&audience).ok_or_else(|| {
+                let detail = format!("tenant = '{}' is not found", audience);
+                error(StatusCode::NOT_FOUND, "tenant_not_found", "Tenant not found", &detail)
+            })?;
+
+            self.apply(&state, &events)?;
+            state.remove(&audience);
+
+            info!("Tenant = '{}' removed by account = '{}'", audience, sub.account_id());
+            json_response(StatusCode::OK, &tenant_config)
+        }
+    }
+}
@@ -1,41 +1,57 @@
 use std::collections::HashMap;
 use std::convert::TryFrom;
+use std::fmt;
 use std::net::SocketAddr;
-use std::time::Duration;
-use std::{sync::Arc, thread};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
 
-use anyhow::{format_err, Context, Result};
+use anyhow::{format_err, Result};
 use chrono::Utc;
-use futures::{sync::mpsc, Future, Stream};
+use futures::{future, sync::mpsc, sync::oneshot, Future, Stream};
 use futures_locks::Mutex;
-use http::{header, Method, Response as HttpResponse, StatusCode};
+use http::{Response as HttpResponse, StatusCode};
 use log::{error, info, warn};
+use rumq_core::SubscribeReturnCodes;
 use serde_derive::Deserialize;
 use serde_json::Value as JsonValue;
 use svc_agent::mqtt::{
-    AgentBuilder, AgentNotification, ConnectionMode, IncomingEvent, IncomingMessage,
-    IncomingResponse, OutgoingRequest, OutgoingRequestProperties, QoS, SubscriptionTopic,
+    AgentBuilder, AgentConfig, AgentNotification, ConnectionMode, IncomingEvent, IncomingMessage,
+    IncomingResponse, OutgoingRequest, OutgoingRequestProperties, SubscriptionTopic,
 };
 use svc_agent::{
     mqtt::{Agent, ShortTermTimingProperties},
-    AccountId, AgentId, Authenticable, ResponseSubscription, SharedGroup, Source, Subscription,
+    AccountId, Addressable, AgentId, Authenticable, ResponseSubscription, SharedGroup, Source,
 };
 use svc_authn::{jose::Algorithm, token::jws_compact};
 use svc_error::{extension::sentry, Error as SvcError};
-use tokio::net::TcpListener;
 use tokio::prelude::FutureExt;
-use tower_web::{
-    impl_web, middleware::cors::CorsBuilder, middleware::log::LogMiddleware, Extract,
-    ServiceBuilder,
-};
+use tokio::runtime::Runtime;
+use tower_web::{impl_web, Extract};
 use uuid::Uuid;
 
+use self::authn::Subject;
+use self::authz::{CLAIMS_PROPERTY, IMPERSONATED_BY_PROPERTY};
 use self::config::Config;
+use self::reload::Reloader;
+use self::server::HttpServer;
+use self::shutdown::Shutdown;
+use crate::util::agent::{Connector, Notifications, Supervisor};
+use crate::util::envelope::ExtendedRequest;
 use crate::util::headers::Headers;
-use crate::util::http_stream::OutgoingStream;
+use crate::util::health::Health;
+use crate::util::http_stream::{CallbackReply, OutgoingStream};
+use crate::util::logging::{self, Fields};
+use crate::util::metrics::Metrics;
 use crate::util::mqtt_request::Adapter;
+use crate::util::poll::{until, wait_until};
+use crate::util::tls;
+use crate::util::trace::{SpanKind, TraceContext, Tracer, TRACEPARENT_PROPERTY};
+use crate::util::unix_socket;
 
 const API_VERSION: &str = "v1";
+const RECONNECTION_POLL_INTERVAL: Duration = Duration::from_millis(50);
 
 ////////////////////////////////////////////////////////////////////////////////
 
@@ -52,14 +68,74 @@ struct RequestPayload {
 struct Request {
     tx: Mutex<Adapter>,
     timeout: Duration,
+    state: Arc<State>,
+    disconnection: DisconnectionConfig,
+    authz: Arc<authz::Config>,
+    metrics: Arc<Metrics>,
+    tracer: Tracer,
+    enabled: bool,
 }
 
 impl Request {
-    fn new(tx: Mutex<Adapter>, timeout: Duration) -> Self {
-        Self { tx, timeout }
+    fn new(
+        tx: Mutex<Adapter>,
+        config: &Config,
+        state: Arc<State>,
+        metrics: Arc<Metrics>,
+        tracer: Tracer,
+        enabled: bool,
+    ) -> Self {
+        Self {
+            tx,
+            timeout: config.http_client.timeout(),
+            state,
+            disconnection: config.disconnection.clone(),
+            authz: Arc::new(config.authz.clone()),
+            metrics,
+            tracer,
+            enabled,
+        }
+    }
+
+    fn wait_connected(&self) -> impl Future<Item = (), Error = SvcError> {
+        wait_connected(self.state.clone(), &self.disconnection)
     }
 }
 
+/// Resolves when the broker is connected. While it's not, requests are either rejected
+/// or held until reconnection for `disconnection.queue_timeout` at most.
+fn wait_connected(
+    state: Arc<State>,
+    disconnection: &DisconnectionConfig,
+) -> impl Future<Item = (), Error = SvcError> {
+    if state.health.is_connected() {
+        return future::Either::A(future::ok(()));
+    }
+
+    match disconnection.requests {
+        DisconnectionMode::Reject => future::Either::A(future::err(broker_unavailable(
+            "not connected to the broker",
+        ))),
+        DisconnectionMode::Queue => {
+            let future = wait_until(RECONNECTION_POLL_INTERVAL, move || {
+                state.health.is_connected()
+            })
+            .timeout(disconnection.queue_timeout)
+            .map_err(|_| broker_unavailable("timeout waiting for reconnection to the broker"));
+
+            future::Either::B(future)
+        }
+    }
+}
+
+fn broker_unavailable(detail: &str) -> SvcError {
+    SvcError::builder()
+        .kind("broker_unavailable", "Broker unavailable")
+        .status(StatusCode::SERVICE_UNAVAILABLE)
+        .detail(detail)
+        .build()
+}
+
 impl_web! {
     impl Request {
         #[post("/api/v1/request")]
@@ -67,49 +143,150 @@ impl_web! {
         fn request(
             &self,
             body: RequestPayload,
-            sub: AccountId,
+            sub: Subject,
             gateway_local_tracking_label: Option<String>,
+            traceparent: Option<String>,
         ) -> impl Future<Item = Result<HttpResponse<String>, tower_web::Error>, Error = ()> {
+            if let Err(err) = endpoint::admin::ensure_enabled(self.enabled) {
+                return future::Either::A(future::ok(Err(err)));
+            }
+
+            let api_key = sub.api_key().cloned();
+            let claims = sub.claims().cloned();
+            let authz = self.authz.clone();
+            let sub = sub.account_id().to_owned();
+
             let error = || SvcError::builder().kind("request_error", "Error sending a request");
             let timeout = self.timeout;
+            let metrics = self.metrics.clone();
+            let started_at = Instant::now();
+            let destination = body.destination.to_string();
+            let method = body.method.clone();
+            let correlation_data = Uuid::new_v4().to_string();
+
+            // The caller acts on behalf of another account's agent.
+            let impersonator = if &sub != body.me.as_account_id() { Some(sub) } else { None };
+
+            let mut log_fields = Fields::default()
+                .account(body.me.as_account_id())
+                .agent(&body.me)
+                .method(&method)
+                .correlation_id(&correlation_data);
+
+            let propagate_trace = self.tracer.is_enabled();
+            let parent = traceparent.as_ref().and_then(|value| TraceContext::parse(value));
+            let mut span = self.tracer.start(&method, SpanKind::Server, parent.as_ref());
+            span.set_attribute("destination", &destination);
+            span.set_attribute("agent_id", &body.me.to_string());
+
+            if let Some(ref impersonator) = impersonator {
+                log_fields = log_fields.impersonated_by(impersonator);
+                span.set_attribute("impersonated_by", &impersonator.to_string());
+            }
+
+            let tx = self.tx.clone();
+            let pending_tx = self.tx.clone();
+            let pending_id = correlation_data.clone();
 
-            self.tx.lock()
-                .map_err(move |_| {
+            let response = self.wait_connected()
+                .and_then(move |()| tx.lock().map_err(move |_| {
                     let detail = "error acquiring a mutex for outgoing MQTT request";
                     error().status(StatusCode::UNPROCESSABLE_ENTITY).detail(detail).build()
+                }))
+                .then(move |result| match result {
+                    Ok(tx) => Ok((tx, span)),
+                    Err(err) => {
+                        span.end(false);
+                        Err(err)
+                    }
                 })
-                .and_then(move |mut tx| {
-                    let payload_account_id = body.me.as_account_id();
-                    if &sub != payload_account_id {
-                        let detail = format!("account id = '{}' from the access token doesn't match one in payload.me = '{}' payload", &sub, payload_account_id);
+                .and_then(move |(mut tx, span)| {
+                    if let Some(ref impersonator) = impersonator {
+                        // API keys are bound to their accounts so they never impersonate.
+                        if api_key.is_some() || !authz.may_impersonate(impersonator) {
+                            span.end(false);
+                            let detail = format!("account id = '{}' from the access token doesn't match one in payload.me = '{}' payload", impersonator, body.me.as_account_id());
+                            return Err(error().status(StatusCode::FORBIDDEN).detail(&detail).build());
+                        }
+                    }
+
+                    if let Err(detail) = authz.authorize(claims.as_ref(), &body.destination, &body.method) {
+                        span.end(false);
                         return Err(error().status(StatusCode::FORBIDDEN).detail(&detail).build());
                     }
 
+                    let forwarded_claims = authz.forwarded_claims(claims);
+
+                    if let Some(ref api_key) = api_key {
+                        if let Err(detail) = api_key.authorize(&body.me, &body.destination, &body.method) {
+                            span.end(false);
+                            return Err(error().status(StatusCode::FORBIDDEN).detail(&detail).build());
+                        }
+                    }
+
+                    // Only authorized requests are audited as performed on behalf of the agent.
+                    if let Some(ref impersonator) = impersonator {
+                        let fields = Fields::default()
+                            .account(body.me.as_account_id())
+                            .agent(&body.me)
+                            .method(&body.method)
+                            .correlation_id(&correlation_data)
+                            .impersonated_by(impersonator);
+
+                        logging::with_fields(fields, || {
+                            info!(target: logging::AUDIT_TARGET, "Request on behalf of agent = '{}' by account = '{}'", body.me, impersonator)
+                        });
+                    }
+
                     let response_topic = {
                         let src = Source::Unicast(Some(&body.destination));
                         let sub = ResponseSubscription::new(src);
 
-                        sub.subscription_topic(tx.id(), API_VERSION).map_err(|err| {
-                            error()
-                                .status(StatusCode::UNPROCESSABLE_ENTITY)
-                                .detail(&err.to_string()).build()
-                        })?
+                        match sub.subscription_topic(tx.id(), API_VERSION) {
+                            Ok(topic) => topic,
+                            Err(err) => {
+                                span.end(false);
+
+                                return Err(error()
+                                    .status(StatusCode::UNPROCESSABLE_ENTITY)
+                                    .detail(&err.to_string()).build());
+                            }
+                        }
                     };
 
                     let mut props = OutgoingRequestProperties::new(
                         &body.method,
                         &response_topic,
-                        &Uuid::new_v4().to_string(),
+                        &correlation_data,
                         ShortTermTimingProperties::new(Utc::now()),
                     );
                     props.set_agent_id(body.me);
+
                     if let Some(tracking_label) = gateway_local_tracking_label {
                         props.set_local_tracking_label(tracking_label);
                     }
+
                     let req = OutgoingRequest::multicast(body.payload, props, &body.destination);
+                    let mut req = ExtendedRequest::new(req);
+
+                    if propagate_trace {
+                        let traceparent = span.context().to_traceparent();
+                        req.set_property(TRACEPARENT_PROPERTY, JsonValue::String(traceparent));
+                    }
+
+                    if let Some(claims) = forwarded_claims {
+                        req.set_property(CLAIMS_PROPERTY, claims);
+                    }
+
+                    if let Some(ref impersonator) = impersonator {
+                        let impersonator = JsonValue::String(impersonator.to_string());
+                        req.set_property(IMPERSONATED_BY_PROPERTY, impersonator);
+                    }
 
                     // Send request
-                    tx.request(req).map_err(|err| {
+                    let destination = body.destination.to_string();
+
+                    tx.request(req, &destination, &body.method, span).map_err(|err| {
                         error()
                             .status(StatusCode::UNPROCESSABLE_ENTITY)
                             .detail(&err.to_string())
@@ -119,75 +296,265 @@ impl_web! {
                 .and_then(move |req| {
                     req
                         .timeout(timeout)
-                        .map_err(move |_| {
-                            let detail = "timeout on an outgoing HTTP response";
-                            error().status(StatusCode::GATEWAY_TIMEOUT).detail(detail).build()
+                        .or_else(move |err| {
+                            // The adapter cancels pending requests on disconnection.
+                            if !err.is_elapsed() {
+                                let err = broker_unavailable("connection to the broker was lost");
+                                return future::Either::A(future::err(err));
+                            }
+
+                            let future = pending_tx.lock().then(move |adapter| {
+                                if let Ok(mut adapter) = adapter {
+                                    adapter.abandon(&pending_id);
+                                }
+
+                                let detail = "timeout on an outgoing HTTP response";
+                                Err(error().status(StatusCode::GATEWAY_TIMEOUT).detail(detail).build())
+                            });
+
+                            future::Either::B(future)
                         })
                 })
-                .then(|result| match result {
-                    Ok(resp) => Ok(HttpResponse::builder()
-                        .status(resp.properties().status())
-                        .body(resp.payload().to_string())
-                        .map_err(|err| {
-                            tower_web::Error::builder()
-                                .status(StatusCode::UNPROCESSABLE_ENTITY)
-                                .kind("http_response_build_error", "Failed to build HTTP response")
-                                .detail(&err.to_string())
-                                .build()
-                        })
-                        .and_then(|mut http_response| Headers::try_from(&resp)
-                            .map(|headers| {
-                                headers.add_to_header_map(http_response.headers_mut());
-                                http_response
-                            })
+                .then(move |result| {
+                    let response = match result {
+                        Ok(resp) => HttpResponse::builder()
+                            .status(resp.properties().status())
+                            .body(resp.payload().to_string())
                             .map_err(|err| {
                                 tower_web::Error::builder()
                                     .status(StatusCode::UNPROCESSABLE_ENTITY)
-                                    .kind("http_response_headers_error", "Failed to set HTTP response headers")
+                                    .kind("http_response_build_error", "Failed to build HTTP response")
                                     .detail(&err.to_string())
                                     .build()
                             })
-                        )),
-                    Err(err) => {
-                        notify_error(err.clone());
+                            .and_then(|mut http_response| Headers::try_from(&resp)
+                                .map(|headers| {
+                                    headers.add_to_header_map(http_response.headers_mut());
+                                    http_response
+                                })
+                                .map_err(|err| {
+                                    tower_web::Error::builder()
+                                        .status(StatusCode::UNPROCESSABLE_ENTITY)
+                                        .kind("http_response_headers_error", "Failed to set HTTP response headers")
+                                        .detail(&err.to_string())
+                                        .build()
+                                })
+                            ),
+                        Err(err) => {
+                            notify_error(err.clone());
+
+                            let builder = tower_web::Error::builder()
+                                .status(err.status_code())
+                                .kind(err.kind(), err.title());
+
+                            let builder = match err.detail() {
+                                Some(detail) => builder.detail(detail),
+                                None => builder,
+                            };
+
+                            Err(builder.build())
+                        }
+                    };
 
-                        let builder = tower_web::Error::builder()
-                            .status(err.status_code())
-                            .kind(err.kind(), err.title());
+                    let status = match response {
+                        Ok(ref http_response) => http_response.status(),
+                        Err(ref err) => err.status_code(),
+                    };
 
-                        let builder = match err.detail() {
-                            Some(detail) => builder.detail(detail),
-                            None => builder,
-                        };
+                    let latency = started_at.elapsed();
+                    metrics.observe_http_request(&destination, &method, status.as_u16(), latency);
 
-                        Ok(Err(builder.build()))
-                    }
-                })
+                    let log_fields = log_fields.status(status.as_u16()).latency(latency);
+                    logging::with_fields(log_fields, || info!("Request to '{}' processed", destination));
+
+                    Ok(response)
+                });
+
+            future::Either::B(response)
         }
     }
 }
 
 ////////////////////////////////////////////////////////////////////////////////
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize)]
 pub(crate) struct IdTokenConfig {
     #[serde(deserialize_with = "svc_authn::serde::algorithm")]
     algorithm: Algorithm,
     #[serde(deserialize_with = "svc_authn::serde::file")]
     key: Vec<u8>,
+    /// Lifetime of the agent's MQTT password token, unlimited if not set.
+    #[serde(default, deserialize_with = "crate::serde::optional_duration")]
+    mqtt_expires_in: Option<Duration>,
 }
 
 ////////////////////////////////////////////////////////////////////////////////
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize)]
 pub(crate) struct HttpConfig {
-    listener_address: SocketAddr,
+    /// The listener serving all route groups unless `listeners` are configured.
+    listener_address: Option<ListenerAddress>,
+    #[serde(default)]
+    unix_socket: unix_socket::Config,
+    cors: Option<Cors>,
+    tls: Option<tls::Config>,
+    #[serde(default)]
+    listeners: Vec<ListenerConfig>,
+}
+
+impl HttpConfig {
+    pub(crate) fn listeners(&self) -> Vec<ListenerConfig> {
+        if !self.listeners.is_empty() {
+            return self.listeners.clone();
+        }
+
+        self.listener_address
+            .as_ref()
+            .map(|address| ListenerConfig {
+                address: address.to_owned(),
+                unix_socket: self.unix_socket.clone(),
+                routes: RouteGroup::all(),
+                cors: self.cors.clone().unwrap_or_default(),
+                tls: self.tls.clone(),
+                authn: None,
+            })
+            .into_iter()
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct ListenerConfig {
+    address: ListenerAddress,
+    /// Permissions and ownership of the socket file for `unix:` addresses.
+    #[serde(default)]
+    unix_socket: unix_socket::Config,
+    #[serde(default = "RouteGroup::all")]
+    routes: Vec<RouteGroup>,
+    #[serde(default)]
     cors: Cors,
+    /// Terminate TLS on the listener.
+    tls: Option<tls::Config>,
+    /// Overrides the top level `authn` for this listener.
+    authn: Option<authn::ConfigMap>,
+}
+
+impl ListenerConfig {
+    fn serves(&self, group: RouteGroup) -> bool {
+        self.routes.contains(&group)
+    }
+
+    fn authn<'a>(&'a self, config: &'a Config) -> &'a authn::ConfigMap {
+        self.authn.as_ref().unwrap_or(&config.authn)
+    }
+}
+
+/// A TCP socket address or a `unix:` prefixed path of a Unix socket.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum ListenerAddress {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl FromStr for ListenerAddress {
+    type Err = String;
+
+    fn from_str(value: &str) -> std::result::Result<Self, Self::Err> {
+        match value.strip_prefix("unix:") {
+            Some("") => Err(String::from("empty Unix socket path")),
+            Some(path) => Ok(ListenerAddress::Unix(PathBuf::from(path))),
+            None => value
+                .parse()
+                .map(ListenerAddress::Tcp)
+                .map_err(|err| format!("invalid listener address = '{}', {}", value, err)),
+        }
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for ListenerAddress {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let value = <String as serde::Deserialize>::deserialize(deserializer)?;
+        value.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+impl fmt::Display for ListenerAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ListenerAddress::Tcp(address) => write!(f, "{}", address),
+            ListenerAddress::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// Routes which may be enabled on a listener.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum RouteGroup {
+    /// `/api/v1/request`
+    Api,
+    /// `/api/v1/admin/*`
+    Admin,
+    /// `/metrics`
+    Metrics,
+    /// `/healthz` and `/readyz`
+    Health,
+}
+
+impl RouteGroup {
+    fn all() -> Vec<Self> {
+        vec![
+            RouteGroup::Api,
+            RouteGroup::Admin,
+            RouteGroup::Metrics,
+            RouteGroup::Health,
+        ]
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// What happens to new requests while the broker is disconnected.
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct DisconnectionConfig {
+    #[serde(default)]
+    requests: DisconnectionMode,
+    #[serde(deserialize_with = "crate::serde::duration")]
+    #[serde(default = "DisconnectionConfig::default_queue_timeout")]
+    queue_timeout: Duration,
+}
+
+impl DisconnectionConfig {
+    fn default_queue_timeout() -> Duration {
+        Duration::from_secs(5)
+    }
+}
+
+impl Default for DisconnectionConfig {
+    fn default() -> Self {
+        Self {
+            requests: DisconnectionMode::default(),
+            queue_timeout: Self::default_queue_timeout(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum DisconnectionMode {
+    /// Respond with 503 right away.
+    #[default]
+    Reject,
+    /// Wait for reconnection up to `queue_timeout`.
+    Queue,
 }
 
 ////////////////////////////////////////////////////////////////////////////////
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Default, Deserialize)]
 pub(crate) struct Cors {
     #[serde(deserialize_with = "crate::serde::allowed_origins")]
     #[serde(default)]
@@ -199,8 +566,9 @@ pub(crate) struct Cors {
 
 ////////////////////////////////////////////////////////////////////////////////
 
-struct State {
-    event: endpoint::event::State,
+pub(crate) struct State {
+    event: RwLock<endpoint::event::State>,
+    health: Health,
 }
 
 ////////////////////////////////////////////////////////////////////////////////
@@ -208,6 +576,7 @@ struct State {
 pub(crate) fn run() {
     // Config
     let config = config::load().expect("Failed to load config");
+    logging::init(&config.logging);
     info!("Config: {:?}", config);
 
     // Sentry
@@ -219,197 +588,338 @@ pub(crate) fn run() {
     let agent_id = AgentId::new(&config.agent_label, config.id.clone());
     info!("Agent Id: {}", agent_id);
 
-    let token = jws_compact::TokenBuilder::new()
-        .issuer(agent_id.as_account_id().audience())
-        .subject(&agent_id)
-        .key(config.id_token.algorithm, config.id_token.key.as_slice())
-        .build()
-        .expect("Error creating an id token");
-
-    let mut agent_config = config.mqtt.clone();
-    agent_config.set_password(&token);
+    let connector = MqttConnector {
+        agent_id: agent_id.clone(),
+        config: config.mqtt.clone(),
+    };
 
-    let (mut tx, rx) = AgentBuilder::new(agent_id.clone(), API_VERSION)
-        .connection_mode(ConnectionMode::Bridge)
-        .start(&agent_config)
-        .expect("Failed to create an agent");
+    let id_token = config.id_token.clone();
+    let token_agent_id = agent_id.clone();
+    let mint = move || agent_token(&id_token, &token_agent_id);
 
-    // Event loop for incoming messages of MQTT Agent
+    // Notifications of MQTT Agent. The agent is replaced with one connected with a re-minted
+    // password token before the token expires and after the connection is lost.
     let (mq_tx, mq_rx) = mpsc::unbounded::<AgentNotification>();
-    thread::spawn(move || {
-        for message in rx {
-            if mq_tx.unbounded_send(message).is_err() {
-                error!("Error sending message to the internal channel");
-            }
-        }
-    });
+    let tx = Supervisor::start(
+        connector,
+        mint,
+        config.id_token.mqtt_expires_in,
+        agent_id.clone(),
+        mq_tx,
+    )
+    .expect("Failed to create an agent");
+
+    // Prometheus metrics
+    let metrics = Arc::new(Metrics::new(&config.metrics).expect("Failed to create metrics"));
+
+    // Distributed tracing
+    let (tracer, span_rx) = Tracer::new(config.tracing.as_ref()).expect("Failed to create tracer");
 
     // Create Subscriptions
-    subscribe(&mut tx, &agent_id, &config).expect("Failed to subscribe");
+    let health = Health::default();
+    subscription::subscribe(&mut tx.get(), &agent_id, &config.events, &health)
+        .expect("Failed to subscribe");
+    metrics.set_broker_connected(true);
     let agent = tx.clone();
+    let admin_agent = tx.clone();
+    let reload_agent = tx.clone();
+    let reply_agent = tx.clone();
+    let shutdown_agent = tx.clone();
 
     // Create MQTT Request Adapter
-    let req_tx = Mutex::new(Adapter::new(tx));
+    let req_tx = Mutex::new(Adapter::new(tx, metrics.clone()));
     let resp_tx = req_tx.clone();
+    let shutdown_tx = req_tx.clone();
 
     // Generate bearer tokens for callback requests
     let mut tokens = HashMap::new();
     for audience in config.events.keys() {
-        // Unique subject audience for each tenant to generate unique tokens
-        let subject_audience = format!("{}:{}", config.id.audience(), audience);
-        let subject = AccountId::new(config.id.label(), &subject_audience);
-
-        let token = jws_compact::TokenBuilder::new()
-            .issuer(config.id.audience())
-            .subject(&subject)
-            .key(config.id_token.algorithm, config.id_token.key.as_slice())
-            .build()
-            .unwrap_or_else(|_| panic!("Error creating an id token for audience = '{}'", audience));
-
+        let token = tenant_token(&config, audience).unwrap_or_else(|err| panic!("{}", err));
         tokens.insert(audience.to_owned(), token);
     }
 
     // Application resources
     let state = Arc::new(State {
-        event: endpoint::event::State::new(config.events.clone(), tokens),
+        event: RwLock::new(endpoint::event::State::new(config.events.clone(), tokens)),
+        health,
     });
 
+    state.health.set_connected(true);
+
     let config = Arc::new(config);
-    let config_ = config.clone();
-    let (hq_tx, hq_rx) = OutgoingStream::new(&config.http_client);
+    let state_ = state.clone();
+    let (reply_tx, reply_rx) = mpsc::unbounded::<CallbackReply>();
+    let (hq_tx, hq_rx) = OutgoingStream::new(
+        &config.http_client,
+        reply_tx,
+        metrics.clone(),
+        tracer.clone(),
+    );
+
+    // Redeliver events persisted on the previous shutdown
+    if let Some(queue_path) = config.shutdown.queue_path() {
+        let restored = hq_tx
+            .restore(queue_path, |audience| {
+                let event_state = state.event.read().ok()?;
+                event_state.token(audience).map(|token| token.to_owned())
+            })
+            .expect("Failed to restore durable queue");
+
+        info!("Restored {} undelivered events", restored);
+    }
+
+    // Publish tenants' callback replies to MQTT
+    let reply_state = state.clone();
+    let reply_config = config.clone();
+    let reply_rx = reply_rx.for_each(move |reply| {
+        let audience = reply.audience().to_owned();
+
+        let result = endpoint::reply::handle(
+            &mut reply_agent.get(),
+            &reply_state,
+            &reply_config.id,
+            &reply_config.agent_label,
+            reply,
+        );
+
+        if let Err(err) = result {
+            error!(
+                "Error publishing the callback reply of the audience = '{}', {:#}",
+                audience, err
+            );
+
+            let err = SvcError::builder()
+                .kind("callback_reply_error", "Callback reply error")
+                .detail(&err.to_string())
+                .build();
+
+            notify_error(err);
+        }
+
+        Ok(())
+    });
+
+    let hq_tx_ = hq_tx.clone();
+    let metrics_ = metrics.clone();
+    let mq_config = config.clone();
     let mq_rx = mq_rx.for_each(move |message| {
-        let mut hq_tx = hq_tx.clone();
-        let state = state.clone();
-        let mut agent = agent.clone();
+        let mut hq_tx = hq_tx_.clone();
+        let state = state_.clone();
+        let mq_metrics = metrics_.clone();
+        let agent = agent.clone();
         let agent_id = agent_id.clone();
-        let config = config_.clone();
+        let resubscription_config = mq_config.resubscription.clone();
 
-        resp_tx
-            .lock()
-            .and_then(move |mut resp_tx| {
-                match message {
-                    AgentNotification::Message(message, metadata) => {
-                        let topic: &str = &metadata.topic;
+        resp_tx.lock().and_then(move |mut resp_tx| {
+            match message {
+                AgentNotification::Message(message, metadata) => {
+                    let topic: &str = &metadata.topic;
 
-                        // Log incoming messages
+                    // Log incoming messages
+                    logging::with_fields(message_fields(topic, &message, &state), || {
                         info!(
-                            "Incoming message = '{:?}' sent to the topic = '{}', dup = '{}', pkid = '{:?}'",
-                            message,
+                            "Incoming message sent to the topic = '{}', dup = '{}', pkid = '{:?}'",
+                            topic, metadata.dup, metadata.pkid,
+                        )
+                    });
+
+                    if let Ok(message) = message {
+                        let result = MessageHandler {
+                            resp_tx: &mut resp_tx,
+                            hq_tx: &mut hq_tx,
                             topic,
-                            metadata.dup,
-                            metadata.pkid,
-                        );
-
-                        if let Ok(message) = message {
-                            let result = MessageHandler {
-                                resp_tx: &mut resp_tx,
-                                hq_tx: &mut hq_tx,
-                                topic,
-                                message: message.clone(),
-                                state,
-                            }.handle();
-
-                            if let Err(err) = result {
-                                error!(
-                                    "Error processing a message = '{text:?}' sent to the topic = '{topic}', {detail}",
-                                    text = message,
-                                    topic = topic,
-                                    detail = err,
-                                );
-
-                                let err = SvcError::builder()
-                                    .kind("message_processing_error", "Message processing error")
-                                    .detail(&err.to_string())
-                                    .build();
+                            message: message.clone(),
+                            state: state.clone(),
+                        }
+                        .handle();
+
+                        if let Err(err) = result {
+                            logging::with_fields(
+                                message_fields(topic, &Ok(message), &state),
+                                || {
+                                    error!(
+                                        "Error processing a message sent to the topic = '{}', {}",
+                                        topic, err,
+                                    )
+                                },
+                            );
+
+                            let err = SvcError::builder()
+                                .kind("message_processing_error", "Message processing error")
+                                .detail(&err.to_string())
+                                .build();
 
-                                notify_error(err);
-                            }
+                            notify_error(err);
                         }
                     }
-                    AgentNotification::Disconnection => {
-                        error!("Disconnected from broker");
+                }
+                AgentNotification::Disconnection => {
+                    error!("Disconnected from broker");
+                    mq_metrics.set_broker_connected(false);
+                    state.health.set_connected(false);
+
+                    // Responses to requests published before can't arrive anymore.
+                    let failed = resp_tx.fail_all("broker_unavailable");
+
+                    if failed > 0 {
+                        warn!("Failed {} requests waiting for responses", failed);
                     }
-                    AgentNotification::Reconnection => {
-                        error!("Reconnected to broker");
-                        resubscribe(&mut agent, &agent_id, &config.clone());
+                }
+                AgentNotification::Reconnection => {
+                    error!("Reconnected to broker");
+                    mq_metrics.set_broker_connected(true);
+                    state.health.set_connected(true);
+
+                    tokio::spawn(subscription::resubscribe(
+                        resubscription_config,
+                        agent,
+                        agent_id,
+                        state,
+                        mq_metrics,
+                    ));
+                }
+                AgentNotification::Suback(suback) => {
+                    let granted = suback
+                        .return_codes
+                        .iter()
+                        .all(|code| *code != SubscribeReturnCodes::Failure);
+
+                    if !granted {
+                        error!(
+                            "Subscription rejected by the broker, pkid = '{}'",
+                            suback.pkid.0
+                        );
                     }
-                    _ => error!("Unsupported notification type = '{:?}'", message),
+
+                    state.health.subscription_acknowledged(granted);
                 }
+                _ => error!("Unsupported notification type = '{:?}'", message),
+            }
 
-                Ok(())
-            })
+            Ok(())
+        })
     });
 
-    // Resources
-    let request = Request::new(req_tx, Duration::from_secs((config.http_client).timeout()));
-
-    // Middleware
-    let cors = CorsBuilder::new()
-        .allow_origins(config.http.cors.allow_origins.clone())
-        .allow_methods(vec![Method::POST])
-        .allow_headers(vec![
-            header::AUTHORIZATION,
-            header::CONTENT_LENGTH,
-            header::CONTENT_TYPE,
-        ])
-        .allow_credentials(true)
-        .max_age(config.http.cors.max_age)
-        .build();
-
-    let tcp_stream =
-        TcpListener::bind(&config.http.listener_address).expect("Invalid HTTP listener address");
-
-    let server = ServiceBuilder::new()
-        .config(config.authn.clone())
-        .middleware(LogMiddleware::new("http_gateway::http"))
-        .middleware(cors)
-        .resource(request)
-        .serve(tcp_stream.incoming());
-
-    tokio::run(server.join(mq_rx).join(hq_rx).map(|_| ()));
-}
-
-fn subscribe(agent: &mut Agent, agent_id: &AgentId, config: &Config) -> anyhow::Result<()> {
-    let group = SharedGroup::new("loadbalancer", agent_id.as_account_id().clone());
-
-    // Responses
-    agent
-        .subscribe(&Subscription::unicast_responses(), QoS::AtLeastOnce, None)
-        .context("Error subscribing to app's responses topic")?;
-
-    // Audience level events for each tenant
-    for (tenant_audience, tenant_events_config) in &config.events {
-        for source in tenant_events_config.sources() {
-            agent
-                .subscribe(
-                    &Subscription::broadcast_events(
-                        source.account_id(),
-                        source.version(),
-                        &format!("audiences/{}/events", tenant_audience),
-                    ),
-                    QoS::AtLeastOnce,
-                    Some(&group),
-                )
-                .context("Error subscribing to app's events topic")?;
-        }
+    // HTTP listener
+    let server = HttpServer::bind(
+        &config,
+        req_tx,
+        hq_tx.clone(),
+        state.clone(),
+        admin_agent,
+        metrics,
+        tracer,
+    )
+    .expect("Failed to bind HTTP listener");
+
+    let shutdown = Shutdown::new(
+        config.shutdown.clone(),
+        state.clone(),
+        server.handle(),
+        shutdown_tx,
+        hq_tx.clone(),
+    );
+
+    let reloader = Reloader::new(config, server, state, reload_agent, hq_tx);
+
+    let mut runtime = Runtime::new().expect("Failed to create Tokio runtime");
+
+    let _ = runtime.block_on(future::lazy(move || {
+        let (stop_tx, stop_rx) = oneshot::channel::<()>();
+        let stop = stop_rx.shared();
+
+        // Background tasks wait for messages forever so they're stopped after the drain.
+        tokio::spawn(until(reloader.run(), stop.clone()));
+        tokio::spawn(until(mq_rx, stop.clone()));
+        tokio::spawn(until(hq_rx, stop.clone()));
+        tokio::spawn(until(reply_rx, stop.clone()));
+        tokio::spawn(until(span_rx, stop));
+
+        shutdown.run().then(move |_| stop_tx.send(()))
+    }));
+
+    shutdown::finish(runtime, &shutdown_agent);
+}
+
+/// Starts the gateway's agents connected with the given password.
+struct MqttConnector {
+    agent_id: AgentId,
+    config: AgentConfig,
+}
+
+impl Connector for MqttConnector {
+    type Agent = Agent;
+
+    fn connect(&mut self, password: &str) -> anyhow::Result<(Agent, Notifications)> {
+        let mut config = self.config.clone();
+        config.set_password(password);
+
+        let (agent, rx) = AgentBuilder::new(self.agent_id.clone(), API_VERSION)
+            .connection_mode(ConnectionMode::Bridge)
+            .start(&config)
+            .map_err(|err| format_err!("Failed to create an agent: {}", err))?;
+
+        Ok((agent, Box::new(rx.into_iter())))
     }
 
-    Ok(())
+    fn disconnect(&mut self, mut agent: Agent) {
+        if let Err(err) = agent.disconnect() {
+            error!("Failed to disconnect the replaced agent, {}", err);
+        }
+    }
 }
 
-fn resubscribe(agent: &mut Agent, agent_id: &AgentId, config: &Config) {
-    if let Err(err) = subscribe(agent, agent_id, config) {
-        let err = format!("Failed to resubscribe after reconnection: {}", err);
-        error!("{}", err);
+/// Mints the agent's MQTT password token.
+fn agent_token(config: &IdTokenConfig, agent_id: &AgentId) -> anyhow::Result<String> {
+    let builder = jws_compact::TokenBuilder::new()
+        .issuer(agent_id.as_account_id().audience())
+        .subject(agent_id)
+        .key(config.algorithm, config.key.as_slice());
+
+    let builder = match config.mqtt_expires_in {
+        Some(expires_in) => builder.expires_in(expires_in.as_secs() as i64),
+        None => builder,
+    };
 
-        let svc_error = SvcError::builder()
-            .kind("resubscription_error", "Resubscription error")
-            .detail(&err)
-            .build();
+    let token = builder
+        .build()
+        .map_err(|err| format_err!("Error creating an id token: {}", err))?;
 
-        sentry::send(svc_error)
-            .unwrap_or_else(|err| warn!("Error sending error to Sentry: {}", err));
+    if let Some(expires_in) = config.mqtt_expires_in {
+        let expires_at = Utc::now() + chrono::Duration::seconds(expires_in.as_secs() as i64);
+        info!("MQTT password token expires at = '{}'", expires_at);
     }
+
+    Ok(token)
+}
+
+pub(crate) fn shared_group(agent_id: &AgentId) -> SharedGroup {
+    SharedGroup::new("loadbalancer", agent_id.as_account_id().clone())
+}
+
+/// Account representing the tenant in callback tokens and replies.
+pub(crate) fn tenant_account_id(id: &AccountId, audience: &str) -> AccountId {
+    // Unique subject audience for each tenant to generate unique tokens
+    let subject_audience = format!("{}:{}", id.audience(), audience);
+    AccountId::new(id.label(), &subject_audience)
+}
+
+/// Mints a bearer token for callback requests to the tenant.
+pub(crate) fn tenant_token(config: &Config, audience: &str) -> anyhow::Result<String> {
+    let subject = tenant_account_id(&config.id, audience);
+
+    jws_compact::TokenBuilder::new()
+        .issuer(config.id.audience())
+        .subject(&subject)
+        .key(config.id_token.algorithm, config.id_token.key.as_slice())
+        .build()
+        .map_err(|err| {
+            format_err!(
+                "Error creating an id token for audience = '{}': {}",
+                audience,
+                err
+            )
+        })
 }
 
 //////////////////////////////////////////////////////////////////////////////////
@@ -439,8 +949,16 @@ impl MessageHandler<'_> {
             }
             IncomingMessage::Event(event) => {
                 let event = IncomingEvent::convert::<JsonValue>(event)?;
-                let outev = state.event.handle(topic, &event)?;
-                hq_tx.send(outev)
+                let event_state = state
+                    .event
+                    .read()
+                    .map_err(|_| format_err!("error acquiring a lock for events state"))?;
+
+                for outev in event_state.handle(topic, &event)? {
+                    hq_tx.send(outev)?;
+                }
+
+                Ok(())
             }
             _ => Err(format_err!(
                 "unsupported message type, message = '{:?}'",
@@ -452,6 +970,58 @@ impl MessageHandler<'_> {
 
 //////////////////////////////////////////////////////////////////////////////////
 
+/// Log fields of an incoming message, its payload is redacted by the logger.
+///
+/// Events get audiences they're routed to so that redaction configured for them applies.
+fn message_fields(
+    topic: &str,
+    message: &Result<IncomingMessage<String>, String>,
+    state: &State,
+) -> Fields {
+    let fields = Fields::default().topic(topic);
+
+    match message {
+        Ok(IncomingMessage::Event(event)) => {
+            let props = event.properties();
+            let mut fields = fields.agent(props.as_agent_id()).payload(event.payload());
+
+            if let Ok(event_state) = state.event.read() {
+                let audiences = event_state.audiences(topic, props.as_account_id());
+
+                if !audiences.is_empty() {
+                    fields = fields.audience(&audiences.join(","));
+                }
+            }
+
+            match props.label() {
+                Some(label) => fields.method(label),
+                None => fields,
+            }
+        }
+        Ok(IncomingMessage::Request(req)) => {
+            let props = req.properties();
+
+            fields
+                .agent(props.as_agent_id())
+                .method(props.method())
+                .correlation_id(props.correlation_data())
+                .payload(req.payload())
+        }
+        Ok(IncomingMessage::Response(resp)) => {
+            let props = resp.properties();
+
+            fields
+                .agent(props.as_agent_id())
+                .correlation_id(props.correlation_data())
+                .status(props.status().as_u16())
+                .payload(resp.payload())
+        }
+        Err(payload) => fields.payload(payload),
+    }
+}
+
+//////////////////////////////////////////////////////////////////////////////////
+
 fn notify_error(error: SvcError) {
     if let Err(err) = sentry::send(error) {
         error!("Error sending error to Sentry: {}", err);
@@ -460,16 +1030,36 @@ fn notify_error(error: SvcError) {
 
 //////////////////////////////////////////////////////////////////////////////////
 
+mod api_key;
+mod authn;
+mod authz;
 pub(crate) mod config;
+mod cookie_auth;
 mod endpoint;
+mod reload;
+mod revocation;
+mod server;
+mod shutdown;
+mod subscription;
 
 //////////////////////////////////////////////////////////////////////////////////
 
 #[cfg(test)]
 mod test {
+    use std::collections::HashMap;
+    use std::sync::{Arc, RwLock};
+    use std::thread;
+    use std::time::Duration;
 
-    use super::RequestPayload;
+    use http::StatusCode;
     use serde_json::{self, json};
+    use tokio::runtime::Runtime;
+
+    use super::{
+        endpoint, wait_connected, DisconnectionConfig, DisconnectionMode, ListenerAddress,
+        RequestPayload, State,
+    };
+    use crate::util::health::Health;
 
     #[test]
     fn ser() {
@@ -484,6 +1074,82 @@ mod test {
 
         dbg!(d);
     }
+
+    #[test]
+    fn parses_listener_address() {
+        assert_eq!(
+            "127.0.0.1:8080".parse::<ListenerAddress>(),
+            Ok(ListenerAddress::Tcp(([127, 0, 0, 1], 8080).into()))
+        );
+
+        assert_eq!(
+            "unix:/run/http-gateway.sock".parse::<ListenerAddress>(),
+            Ok(ListenerAddress::Unix("/run/http-gateway.sock".into()))
+        );
+
+        assert!("unix:".parse::<ListenerAddress>().is_err());
+        assert!("localhost".parse::<ListenerAddress>().is_err());
+    }
+
+    fn state() -> Arc<State> {
+        Arc::new(State {
+            event: RwLock::new(endpoint::event::State::new(HashMap::new(), HashMap::new())),
+            health: Health::default(),
+        })
+    }
+
+    fn disconnection(requests: DisconnectionMode, queue_timeout: Duration) -> DisconnectionConfig {
+        DisconnectionConfig {
+            requests,
+            queue_timeout,
+        }
+    }
+
+    #[test]
+    fn rejects_requests_while_disconnected() {
+        let mut runtime = Runtime::new().unwrap();
+        let state = state();
+        let config = disconnection(DisconnectionMode::Reject, Duration::from_secs(5));
+
+        let err = runtime
+            .block_on(wait_connected(state.clone(), &config))
+            .expect_err("Request is accepted while disconnected");
+
+        assert_eq!(err.status_code(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(err.kind(), "broker_unavailable");
+
+        state.health.set_connected(true);
+        assert!(runtime.block_on(wait_connected(state, &config)).is_ok());
+    }
+
+    #[test]
+    fn queues_requests_until_reconnection() {
+        let mut runtime = Runtime::new().unwrap();
+        let state = state();
+        let config = disconnection(DisconnectionMode::Queue, Duration::from_secs(5));
+
+        let reconnecting_state = state.clone();
+        let reconnection = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(200));
+            reconnecting_state.health.set_connected(true);
+        });
+
+        assert!(runtime.block_on(wait_connected(state, &config)).is_ok());
+        reconnection.join().unwrap();
+    }
+
+    #[test]
+    fn fails_queued_requests_on_timeout() {
+        let mut runtime = Runtime::new().unwrap();
+        let config = disconnection(DisconnectionMode::Queue, Duration::from_millis(200));
+
+        let err = runtime
+            .block_on(wait_connected(state(), &config))
+            .expect_err("Request is accepted while disconnected");
+
+        assert_eq!(err.status_code(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(err.kind(), "broker_unavailable");
+    }
 }
 
 //////////////////////////////////////////////////////////////////////////////////
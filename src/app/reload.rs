@@ -0,0 +1,327 @@
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::sync::Arc;
+
+use anyhow::{format_err, Result};
+use futures::{Future, Stream};
+use log::{error, info, warn};
+use tokio_signal::unix::{Signal, SIGHUP};
+
+use super::config::{self, Config};
+use super::endpoint;
+use super::server::HttpServer;
+use super::{shared_group, tenant_token, HttpConfig, State};
+use crate::util::agent::AgentHandle;
+use crate::util::http_stream::OutgoingStream;
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Applies configuration changes to the running gateway.
+pub(crate) struct Reloader {
+    config: Arc<Config>,
+    server: HttpServer,
+    state: Arc<State>,
+    agent: AgentHandle,
+    hq_tx: OutgoingStream,
+}
+
+impl Reloader {
+    pub(crate) fn new(
+        config: Arc<Config>,
+        server: HttpServer,
+        state: Arc<State>,
+        agent: AgentHandle,
+        hq_tx: OutgoingStream,
+    ) -> Self {
+        Self {
+            config,
+            server,
+            state,
+            agent,
+            hq_tx,
+        }
+    }
+
+    /// Starts the HTTP listener and reloads the config on each SIGHUP.
+    ///
+    /// Must be called within the Tokio runtime.
+    pub(crate) fn run(mut self) -> impl Future<Item = (), Error = ()> {
+        self.server
+            .start(self.config.clone())
+            .expect("Failed to start HTTP listener");
+
+        Signal::new(SIGHUP)
+            .flatten_stream()
+            .for_each(move |_| {
+                info!("SIGHUP received, reloading config");
+
+                if let Err(err) = self.reload() {
+                    error!("Failed to reload config, keeping the old one: {}", err);
+                }
+
+                Ok(())
+            })
+            .map_err(|err| error!("Error handling SIGHUP: {}", err))
+    }
+
+    fn reload(&mut self) -> Result<()> {
+        let loaded = config::load().map_err(|err| format_err!("{}", err));
+        let (config, tokens) = next_config(&self.config, loaded)?;
+
+        // Authn keys, CORS and request timeout are applied by restarting the HTTP listener.
+        // Listeners, TLS, API keys and revocations are prepared before changing anything.
+        let config = Arc::new(config);
+        let prepared = self.server.prepare(config.clone())?;
+        let next_event_state = endpoint::event::State::new(config.events.clone(), tokens);
+
+        {
+            let mut event_state = self
+                .state
+                .event
+                .write()
+                .map_err(|_| format_err!("error acquiring a lock for events state"))?;
+
+            let mut agent = self.agent.get();
+            let group = shared_group(self.agent.id());
+            let health = &self.state.health;
+
+            let result = endpoint::event::update_subscriptions(
+                &mut agent,
+                &group,
+                health,
+                event_state.config(),
+                &config.events,
+            );
+
+            if let Err(err) = result {
+                // Subscriptions made before the failure aren't needed with the old config.
+                let old = event_state.config();
+
+                if let Err(err) = endpoint::event::update_subscriptions(
+                    &mut agent,
+                    &group,
+                    health,
+                    &config.events,
+                    old,
+                ) {
+                    error!("Failed to restore subscriptions of the old config: {}", err);
+                }
+
+                return Err(err);
+            }
+
+            *event_state = next_event_state;
+        }
+
+        self.hq_tx.set_config(&config.http_client);
+        self.server.apply(prepared);
+        self.config = config;
+
+        info!("Config reloaded: {:?}", self.config);
+        Ok(())
+    }
+}
+
+/// Prepares the loaded config to be applied, failures leave the current config intact.
+fn next_config(
+    current: &Config,
+    loaded: Result<Config>,
+) -> Result<(Config, HashMap<String, String>)> {
+    let mut config = loaded?;
+    keep_restart_only_settings(current, &mut config);
+
+    // Mint tokens before applying anything so that failure leaves the old config intact.
+    let mut tokens = HashMap::new();
+
+    for audience in config.events.keys() {
+        tokens.insert(audience.to_owned(), tenant_token(&config, audience)?);
+    }
+
+    Ok((config, tokens))
+}
+
+/// Warns about changed settings that can't be applied without a restart and reverts them.
+fn keep_restart_only_settings(current: &Config, config: &mut Config) {
+    keep("id", &current.id, &mut config.id);
+    keep("agent_label", &current.agent_label, &mut config.agent_label);
+    keep("id_token", &current.id_token, &mut config.id_token);
+    keep("mqtt", &current.mqtt, &mut config.mqtt);
+    keep("tracing", &current.tracing, &mut config.tracing);
+    keep("logging", &current.logging, &mut config.logging);
+    keep("shutdown", &current.shutdown, &mut config.shutdown);
+    keep("metrics", &current.metrics, &mut config.metrics);
+    keep(
+        "resubscription",
+        &current.resubscription,
+        &mut config.resubscription,
+    );
+
+    keep_listener_sockets(&current.http, &mut config.http);
+
+    if changed(&current.sentry, &config.sentry) {
+        warn!("Changing 'sentry' requires restart, the new value is ignored");
+    }
+}
+
+/// Listeners are bound on start, the rest of their settings is applied on reload.
+fn keep_listener_sockets(current: &HttpConfig, config: &mut HttpConfig) {
+    keep(
+        "http.listener_address",
+        &current.listener_address,
+        &mut config.listener_address,
+    );
+
+    keep(
+        "http.unix_socket",
+        &current.unix_socket,
+        &mut config.unix_socket,
+    );
+
+    if current.listeners.len() != config.listeners.len() {
+        warn!("Adding or removing 'http.listeners' requires restart, the new value is ignored");
+        config.listeners = current.listeners.clone();
+        return;
+    }
+
+    for (idx, (current, listener)) in current
+        .listeners
+        .iter()
+        .zip(config.listeners.iter_mut())
+        .enumerate()
+    {
+        keep(
+            &format!("http.listeners[{}].address", idx),
+            &current.address,
+            &mut listener.address,
+        );
+
+        keep(
+            &format!("http.listeners[{}].unix_socket", idx),
+            &current.unix_socket,
+            &mut listener.unix_socket,
+        );
+    }
+}
+
+fn keep<T: Clone + Debug>(name: &str, current: &T, new: &mut T) {
+    if changed(current, new) {
+        warn!(
+            "Changing '{}' requires restart, the new value is ignored",
+            name
+        );
+
+        *new = current.clone();
+    }
+}
+
+// Not every config type implements `PartialEq` so compare their debug representations.
+fn changed<T: Debug>(current: &T, new: &T) -> bool {
+    format!("{:?}", current) != format!("{:?}", new)
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use super::*;
+    use crate::app::RouteGroup;
+
+    fn config(agent_label: &str, callback: &str) -> Config {
+        serde_json::from_value(json!({
+            "id": "http-gateway.svc.example.org",
+            "agent_label": agent_label,
+            "id_token": {"algorithm": "ES256", "key": "data/keys/svc.private_key.p8.der.sample"},
+            "authn": {},
+            "mqtt": {"uri": "mqtt://0.0.0.0:1883"},
+            "http": {"listener_address": "0.0.0.0:8080"},
+            "http_client": {"timeout": 5},
+            "events": {
+                "example.org": {
+                    "callback": callback,
+                    "sources": [{"account_id": "conference.svc.example.org", "version": "v1"}],
+                },
+            },
+        }))
+        .expect("Failed to parse config")
+    }
+
+    #[test]
+    fn keeps_restart_only_settings() {
+        let current = config("alpha", "https://example.org/callback");
+        let mut new = config("beta", "https://example.org/v2/callback");
+        new.http = serde_json::from_value(json!({
+            "listener_address": "0.0.0.0:9090",
+            "cors": {"max_age": 60},
+        }))
+        .expect("Failed to parse HTTP config");
+
+        keep_restart_only_settings(&current, &mut new);
+
+        assert_eq!(new.agent_label, "alpha");
+        assert_eq!(
+            new.http.listeners()[0].address,
+            current.http.listeners()[0].address
+        );
+
+        // The rest of the HTTP settings is applied.
+        assert_eq!(
+            new.http.listeners()[0].cors.max_age,
+            std::time::Duration::from_secs(60)
+        );
+
+        let callback = new.events["example.org"].callback();
+        assert_eq!(callback, Some("https://example.org/v2/callback"));
+    }
+
+    #[test]
+    fn keeps_listener_sockets() {
+        let listeners = |second: &str, max_age: u64| -> HttpConfig {
+            serde_json::from_value(json!({
+                "listeners": [
+                    {"address": "0.0.0.0:8080", "routes": ["api"]},
+                    {"address": second, "routes": ["admin"], "cors": {"max_age": max_age}},
+                ],
+            }))
+            .expect("Failed to parse HTTP config")
+        };
+
+        let current = listeners("unix:/run/http-gateway/admin.sock", 0);
+        let mut new = listeners("0.0.0.0:9090", 60);
+        keep_listener_sockets(&current, &mut new);
+
+        assert_eq!(new.listeners[1].address, current.listeners[1].address);
+        assert_eq!(new.listeners[1].routes, vec![RouteGroup::Admin]);
+        assert_eq!(
+            new.listeners[1].cors.max_age,
+            std::time::Duration::from_secs(60)
+        );
+
+        // Listeners can't be matched to the current ones so they are all kept.
+        let mut new = listeners("0.0.0.0:9090", 60);
+        new.listeners.pop();
+        keep_listener_sockets(&current, &mut new);
+
+        assert_eq!(new.listeners.len(), 2);
+        assert_eq!(
+            new.listeners[1].cors.max_age,
+            current.listeners[1].cors.max_age
+        );
+    }
+
+    #[test]
+    fn failed_reload_keeps_current_config() {
+        let current = config("alpha", "https://example.org/callback");
+        let loaded = Err(format_err!("Failed to parse App.toml"));
+
+        assert!(next_config(&current, loaded).is_err());
+        assert_eq!(current.agent_label, "alpha");
+
+        let loaded = Ok(config("beta", "https://example.org/v2/callback"));
+        let (next, tokens) = next_config(&current, loaded).expect("Failed to prepare config");
+
+        assert_eq!(next.agent_label, "alpha");
+        assert!(tokens.contains_key("example.org"));
+    }
+}
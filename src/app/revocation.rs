@@ -0,0 +1,204 @@
+use std::collections::HashSet;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::{Context, Result};
+use log::{error, info};
+use serde_derive::Deserialize;
+use serde_json::Value as JsonValue;
+use svc_agent::AccountId;
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct Config {
+    /// JSON file with revoked token ids and subjects.
+    path: PathBuf,
+    /// How often the file is checked for changes.
+    #[serde(deserialize_with = "crate::serde::duration")]
+    #[serde(default = "Config::default_reload_interval")]
+    reload_interval: Duration,
+}
+
+impl Config {
+    fn default_reload_interval() -> Duration {
+        Duration::from_secs(10)
+    }
+
+    fn modified(&self) -> Option<SystemTime> {
+        fs::metadata(&self.path)
+            .and_then(|meta| meta.modified())
+            .ok()
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct List {
+    /// Revoked `jti` claims.
+    #[serde(default)]
+    jti: HashSet<String>,
+    /// Accounts all tokens of which are revoked.
+    #[serde(default)]
+    subjects: Vec<AccountId>,
+}
+
+impl List {
+    fn load(config: &Config) -> Result<Self> {
+        let data = fs::read(&config.path).with_context(|| {
+            format!(
+                "Failed to read revocation list from {}",
+                config.path.display()
+            )
+        })?;
+
+        serde_json::from_slice(&data).with_context(|| {
+            format!(
+                "Failed to parse revocation list from {}",
+                config.path.display()
+            )
+        })
+    }
+
+    fn check(&self, claims: &JsonValue) -> Result<(), String> {
+        if let Some(jti) = claims.get("jti").and_then(JsonValue::as_str) {
+            if self.jti.contains(jti) {
+                return Err(format!("token with jti = '{}' is revoked", jti));
+            }
+        }
+
+        if let Some(label) = claims.get("sub").and_then(JsonValue::as_str) {
+            let audience = match claims.get("aud") {
+                Some(JsonValue::String(aud)) => vec![aud.as_str()],
+                Some(JsonValue::Array(auds)) => auds.iter().filter_map(JsonValue::as_str).collect(),
+                _ => vec![],
+            };
+
+            for aud in audience {
+                let account_id = AccountId::new(label, aud);
+
+                if self.subjects.contains(&account_id) {
+                    return Err(format!("tokens of subject = '{}' are revoked", account_id));
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Revoked tokens checked after the token is verified.
+///
+/// The file is reloaded on checking a token if it has changed.
+#[derive(Debug, Default)]
+pub(crate) struct Revocations {
+    config: Option<Config>,
+    state: Mutex<State>,
+}
+
+#[derive(Debug)]
+struct State {
+    list: Arc<List>,
+    modified: Option<SystemTime>,
+    checked_at: Instant,
+}
+
+impl Default for State {
+    fn default() -> Self {
+        Self {
+            list: Arc::new(List::default()),
+            modified: None,
+            checked_at: Instant::now(),
+        }
+    }
+}
+
+impl Revocations {
+    pub(crate) fn load(config: Option<&Config>) -> Result<Self> {
+        let config = match config {
+            Some(config) => config,
+            None => return Ok(Self::default()),
+        };
+
+        let modified = config.modified();
+        let list = List::load(config)?;
+
+        Ok(Self {
+            config: Some(config.to_owned()),
+            state: Mutex::new(State {
+                list: Arc::new(list),
+                modified,
+                checked_at: Instant::now(),
+            }),
+        })
+    }
+
+    /// Fails if the token's `jti` or subject is revoked.
+    pub(crate) fn check(&self, claims: &JsonValue) -> Result<(), String> {
+        self.list().check(claims)
+    }
+
+    fn list(&self) -> Arc<List> {
+        let mut state = match self.state.lock() {
+            Ok(state) => state,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+
+        if let Some(ref config) = self.config {
+            if state.checked_at.elapsed() >= config.reload_interval {
+                state.checked_at = Instant::now();
+                let modified = config.modified();
+
+                if modified != state.modified {
+                    // The old list stays active until the file is fixed.
+                    match List::load(config) {
+                        Ok(list) => {
+                            info!(
+                                "Revocation list reloaded from '{}', jti = '{}', subjects = '{}'",
+                                config.path.display(),
+                                list.jti.len(),
+                                list.subjects.len()
+                            );
+
+                            state.list = Arc::new(list);
+                            state.modified = modified;
+                        }
+                        Err(err) => error!("Failed to reload revocation list, {:#}", err),
+                    }
+                }
+            }
+        }
+
+        state.list.clone()
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn revokes_by_jti_and_subject() {
+        let list = serde_json::from_value::<List>(json!({
+            "jti": ["d9a3e8f2"],
+            "subjects": ["john.usr.example.org"],
+        }))
+        .expect("Failed to parse revocation list");
+
+        let revoked = json!({ "jti": "d9a3e8f2", "sub": "jane", "aud": "usr.example.org" });
+        assert!(list.check(&revoked).is_err());
+
+        let revoked = json!({ "jti": "6c1b0f7e", "sub": "john", "aud": ["usr.example.org"] });
+        assert!(list.check(&revoked).is_err());
+
+        let valid = json!({ "jti": "6c1b0f7e", "sub": "john", "aud": "other.example.org" });
+        assert!(list.check(&valid).is_ok());
+    }
+}
@@ -0,0 +1,428 @@
+use std::sync::Arc;
+
+use anyhow::{format_err, Context, Result};
+use futures::{future, sync::oneshot, Future, Stream};
+use futures_locks::Mutex;
+use http::{header, header::HeaderName, Method};
+use log::info;
+use tokio::net::{TcpListener, UnixListener};
+use tokio::reactor::Handle;
+use tower_web::middleware::cors::{AllowedOrigins, CorsBuilder};
+use tower_web::net::ConnectionStream;
+use tower_web::{middleware::log::LogMiddleware, ServiceBuilder};
+
+use super::api_key::ApiKeys;
+use super::authn::Authenticator;
+use super::config::Config;
+use super::cookie_auth::CookieAuthMiddleware;
+use super::endpoint;
+use super::revocation::Revocations;
+use super::{ListenerAddress, ListenerConfig, Request, RouteGroup, State};
+use crate::util::agent::AgentHandle;
+use crate::util::http_stream::OutgoingStream;
+use crate::util::jwks::JwksStore;
+use crate::util::metrics::Metrics;
+use crate::util::mqtt_request::Adapter;
+use crate::util::tls::TlsAcceptor;
+use crate::util::trace::{Tracer, TRACEPARENT_HEADER};
+use crate::util::unix_socket;
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// HTTP listeners which may be restarted with a new config without closing their sockets.
+///
+/// Connections accepted before the restart are served till the end with the old config.
+pub(crate) struct HttpServer {
+    listeners: ListenerHandle,
+    req_tx: Mutex<Adapter>,
+    hq_tx: OutgoingStream,
+    state: Arc<State>,
+    agent: AgentHandle,
+    metrics: Arc<Metrics>,
+    tracer: Tracer,
+    jwks: JwksStore,
+}
+
+/// Allows to stop the listeners from outside of the server.
+#[derive(Clone)]
+pub(crate) struct ListenerHandle {
+    inner: Arc<std::sync::Mutex<Vec<Listener>>>,
+}
+
+struct Listener {
+    address: ListenerAddress,
+    // `None` once stopped.
+    socket: Option<Socket>,
+    shutdown_tx: Option<oneshot::Sender<()>>,
+}
+
+enum Socket {
+    Tcp(std::net::TcpListener),
+    Unix(std::os::unix::net::UnixListener),
+}
+
+/// Everything the listeners need to serve with a new config, prepared without touching them.
+pub(crate) struct Prepared {
+    config: Arc<Config>,
+    sockets: Vec<(
+        future::Either<TcpListener, UnixListener>,
+        Option<Arc<TlsAcceptor>>,
+    )>,
+    api_keys: Arc<ApiKeys>,
+    revocations: Arc<Revocations>,
+}
+
+impl ListenerHandle {
+    /// Stops accepting connections and closes the sockets. Accepted connections are served till the end.
+    pub(crate) fn stop(&self) {
+        if let Ok(mut listeners) = self.inner.lock() {
+            for listener in listeners.iter_mut() {
+                if listener.socket.take().is_some() {
+                    if let ListenerAddress::Unix(ref path) = listener.address {
+                        unix_socket::remove(path);
+                    }
+                }
+
+                if let Some(shutdown_tx) = listener.shutdown_tx.take() {
+                    let _ = shutdown_tx.send(());
+                }
+            }
+        }
+    }
+}
+
+impl HttpServer {
+    pub(crate) fn bind(
+        config: &Config,
+        req_tx: Mutex<Adapter>,
+        hq_tx: OutgoingStream,
+        state: Arc<State>,
+        agent: AgentHandle,
+        metrics: Arc<Metrics>,
+        tracer: Tracer,
+    ) -> Result<Self> {
+        let listener_configs = config.http.listeners();
+
+        if listener_configs.is_empty() {
+            return Err(format_err!(
+                "Neither 'http.listener_address' nor 'http.listeners' are configured"
+            ));
+        }
+
+        let mut listeners = Vec::with_capacity(listener_configs.len());
+
+        for listener_config in listener_configs {
+            let socket = match listener_config.address {
+                ListenerAddress::Tcp(ref address) => std::net::TcpListener::bind(address)
+                    .map(Socket::Tcp)
+                    .with_context(|| format!("Failed to bind HTTP listener to {}", address))?,
+                ListenerAddress::Unix(ref path) => {
+                    if listener_config.tls.is_some() {
+                        return Err(format_err!(
+                            "TLS is not supported on Unix socket {}",
+                            path.display()
+                        ));
+                    }
+
+                    unix_socket::bind(path, &listener_config.unix_socket).map(Socket::Unix)?
+                }
+            };
+
+            listeners.push(Listener {
+                address: listener_config.address,
+                socket: Some(socket),
+                shutdown_tx: None,
+            });
+        }
+
+        Ok(Self {
+            listeners: ListenerHandle {
+                inner: Arc::new(std::sync::Mutex::new(listeners)),
+            },
+            req_tx,
+            hq_tx,
+            state,
+            agent,
+            metrics,
+            tracer,
+            jwks: JwksStore::new(),
+        })
+    }
+
+    pub(crate) fn handle(&self) -> ListenerHandle {
+        self.listeners.clone()
+    }
+
+    /// Starts accepting connections with the config stopping the previous accept loops if any.
+    ///
+    /// Must be called within the Tokio runtime.
+    pub(crate) fn start(&mut self, config: Arc<Config>) -> Result<()> {
+        let prepared = self.prepare(config)?;
+        self.apply(prepared);
+        Ok(())
+    }
+
+    /// Validates the config and prepares the listeners to be restarted with it.
+    ///
+    /// Failures leave the running listeners intact. Must be called within the Tokio runtime.
+    pub(crate) fn prepare(&self, config: Arc<Config>) -> Result<Prepared> {
+        let listeners = self
+            .listeners
+            .inner
+            .lock()
+            .map_err(|_| format_err!("error acquiring a lock for HTTP listeners"))?;
+
+        let listener_configs = config.http.listeners();
+
+        // Listeners are bound once so their addresses are kept on reload.
+        if listener_configs.len() != listeners.len() {
+            return Err(format_err!(
+                "HTTP listeners can't be changed without restart"
+            ));
+        }
+
+        // Prepare all listeners first so that an invalid TLS config leaves the old ones running.
+        let mut sockets = Vec::with_capacity(listeners.len());
+
+        for (listener, listener_config) in listeners.iter().zip(listener_configs.iter()) {
+            // Browsers don't send cookies to any origin with credentials allowed.
+            if config.cookie_auth.is_some()
+                && listener_config.serves(RouteGroup::Api)
+                && matches!(
+                    listener_config.cors.allow_origins,
+                    AllowedOrigins::Any { .. }
+                )
+            {
+                return Err(format_err!(
+                    "Cookie authentication requires a list of CORS origins on {}",
+                    listener.address
+                ));
+            }
+
+            let socket = match listener.socket {
+                Some(Socket::Tcp(ref socket)) => socket
+                    .try_clone()
+                    .and_then(|socket| TcpListener::from_std(socket, &Handle::default()))
+                    .map(future::Either::A),
+                Some(Socket::Unix(ref socket)) => socket
+                    .try_clone()
+                    .and_then(|socket| UnixListener::from_std(socket, &Handle::default()))
+                    .map(future::Either::B),
+                None => {
+                    return Err(format_err!(
+                        "HTTP listener on {} is stopped",
+                        listener.address
+                    ))
+                }
+            }
+            .context("Failed to clone HTTP listener")?;
+
+            let acceptor = match listener_config.tls {
+                Some(ref tls_config) => {
+                    let acceptor = TlsAcceptor::new(tls_config).with_context(|| {
+                        format!("Failed to configure TLS on {}", listener.address)
+                    })?;
+
+                    Some(Arc::new(acceptor))
+                }
+                None => None,
+            };
+
+            sockets.push((socket, acceptor));
+        }
+
+        let api_keys = ApiKeys::load(&config.api_keys)
+            .map(Arc::new)
+            .context("Failed to load API keys")?;
+
+        let revocations = Revocations::load(config.revocation.as_ref())
+            .map(Arc::new)
+            .context("Failed to load revocation list")?;
+
+        Ok(Prepared {
+            config,
+            sockets,
+            api_keys,
+            revocations,
+        })
+    }
+
+    /// Restarts the listeners with the prepared config.
+    pub(crate) fn apply(&mut self, prepared: Prepared) {
+        let Prepared {
+            config,
+            sockets,
+            api_keys,
+            revocations,
+        } = prepared;
+
+        // The number of listeners never changes and has been checked on preparation.
+        let mut listeners = match self.listeners.inner.lock() {
+            Ok(listeners) => listeners,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+
+        let listener_configs = config.http.listeners();
+
+        // Keys of JWKS issuers are shared by the listeners and refreshed in background.
+        let mut jwks_urls = Vec::new();
+
+        for listener_config in &listener_configs {
+            for jwks_config in listener_config.authn(&config).jwks().values() {
+                self.jwks
+                    .watch(jwks_config.jwks_url(), jwks_config.refresh_interval());
+
+                jwks_urls.push(jwks_config.jwks_url());
+            }
+        }
+
+        self.jwks.retain(&jwks_urls);
+
+        for ((listener, listener_config), (socket, acceptor)) in listeners
+            .iter_mut()
+            .zip(listener_configs.iter())
+            .zip(sockets)
+        {
+            // Stopped on shutdown since the preparation.
+            if listener.socket.is_none() {
+                continue;
+            }
+
+            let server = match (socket, acceptor) {
+                (future::Either::A(socket), Some(acceptor)) => {
+                    let incoming = socket.incoming().map(move |io| acceptor.accept(io));
+                    let server =
+                        self.serve(&config, &api_keys, &revocations, listener_config, incoming);
+                    future::Either::A(future::Either::A(server))
+                }
+                (future::Either::A(socket), None) => {
+                    let server = self.serve(
+                        &config,
+                        &api_keys,
+                        &revocations,
+                        listener_config,
+                        socket.incoming(),
+                    );
+                    future::Either::A(future::Either::B(server))
+                }
+                (future::Either::B(socket), _) => {
+                    let server = self.serve(
+                        &config,
+                        &api_keys,
+                        &revocations,
+                        listener_config,
+                        socket.incoming(),
+                    );
+                    future::Either::B(server)
+                }
+            };
+
+            let (shutdown_tx, shutdown_rx) = oneshot::channel();
+
+            if let Some(prev_shutdown_tx) = listener.shutdown_tx.replace(shutdown_tx) {
+                let _ = prev_shutdown_tx.send(());
+            }
+
+            tokio::spawn(server.select2(shutdown_rx).then(|_| Ok(())));
+
+            info!(
+                "HTTP listener started on {}, routes = '{:?}', tls = '{}'",
+                listener.address,
+                listener_config.routes,
+                listener_config.tls.is_some()
+            );
+        }
+    }
+
+    fn serve<I>(
+        &self,
+        config: &Arc<Config>,
+        api_keys: &Arc<ApiKeys>,
+        revocations: &Arc<Revocations>,
+        listener_config: &ListenerConfig,
+        incoming: I,
+    ) -> impl Future<Item = (), Error = ()>
+    where
+        I: ConnectionStream + Send + 'static,
+        I::Item: Send + 'static,
+    {
+        // Resources of the route groups the listener doesn't serve respond with 404.
+        let request = Request::new(
+            self.req_tx.clone(),
+            config,
+            self.state.clone(),
+            self.metrics.clone(),
+            self.tracer.clone(),
+            listener_config.serves(RouteGroup::Api),
+        );
+
+        let tenants = endpoint::tenant::Tenants::new(
+            config.clone(),
+            self.state.clone(),
+            self.agent.clone(),
+            listener_config.serves(RouteGroup::Admin),
+        );
+
+        let introspection = endpoint::introspection::Introspection::new(
+            config.clone(),
+            self.state.clone(),
+            self.req_tx.clone(),
+            self.hq_tx.clone(),
+            listener_config.serves(RouteGroup::Admin),
+        );
+
+        let metrics = endpoint::metrics::MetricsResource::new(
+            self.metrics.clone(),
+            self.state.clone(),
+            listener_config.serves(RouteGroup::Metrics),
+        );
+
+        let health = endpoint::health::Health::new(
+            config.health.clone(),
+            self.state.clone(),
+            self.metrics.clone(),
+            listener_config.serves(RouteGroup::Health),
+        );
+
+        // Middleware
+        let mut allow_headers = vec![
+            header::AUTHORIZATION,
+            header::CONTENT_LENGTH,
+            header::CONTENT_TYPE,
+            HeaderName::from_static(TRACEPARENT_HEADER),
+        ];
+
+        if let Some(ref cookie_auth) = config.cookie_auth {
+            allow_headers.push(cookie_auth.csrf_header().to_owned());
+        }
+
+        let cors = CorsBuilder::new()
+            .allow_origins(listener_config.cors.allow_origins.clone())
+            .allow_methods(vec![Method::POST])
+            .allow_headers(allow_headers)
+            .allow_credentials(true)
+            .max_age(listener_config.cors.max_age)
+            .build();
+
+        let authn = listener_config.authn(config);
+        let authenticator = Authenticator::new(
+            authn,
+            self.jwks.clone(),
+            api_keys.clone(),
+            revocations.clone(),
+        );
+
+        ServiceBuilder::new()
+            .config(authn.keys().to_owned())
+            .config(authenticator)
+            .middleware(LogMiddleware::new("http_gateway::http"))
+            .middleware(cors)
+            .middleware(CookieAuthMiddleware::new(config.cookie_auth.as_ref()))
+            .resource(request)
+            .resource(tenants)
+            .resource(introspection)
+            .resource(metrics)
+            .resource(health)
+            .serve(incoming)
+    }
+}
@@ -0,0 +1,170 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use futures::{Future, Stream};
+use futures_locks::Mutex;
+use log::{error, info, warn};
+use serde_derive::Deserialize;
+use tokio::prelude::FutureExt;
+use tokio::runtime::Runtime;
+use tokio::timer::Interval;
+use tokio_signal::unix::{Signal, SIGTERM};
+
+use super::server::ListenerHandle;
+use super::State;
+use crate::util::agent::AgentHandle;
+use crate::util::http_stream::OutgoingStream;
+use crate::util::mqtt_request::Adapter;
+use crate::util::poll::wait_until;
+
+////////////////////////////////////////////////////////////////////////////////
+
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// Persisting the rest of the queue doesn't involve network so it's expected to be quick.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct Config {
+    #[serde(deserialize_with = "crate::serde::duration")]
+    #[serde(default = "Config::default_grace_period")]
+    grace_period: Duration,
+    /// Events left undelivered on shutdown are written there and redelivered on start.
+    queue_path: Option<PathBuf>,
+}
+
+impl Config {
+    fn default_grace_period() -> Duration {
+        Duration::from_secs(30)
+    }
+
+    pub(crate) fn queue_path(&self) -> Option<&Path> {
+        self.queue_path.as_deref()
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            grace_period: Self::default_grace_period(),
+            queue_path: None,
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Shuts the gateway down gracefully on SIGTERM.
+pub(crate) struct Shutdown {
+    config: Config,
+    state: Arc<State>,
+    listener: ListenerHandle,
+    req_tx: Mutex<Adapter>,
+    hq_tx: OutgoingStream,
+}
+
+impl Shutdown {
+    pub(crate) fn new(
+        config: Config,
+        state: Arc<State>,
+        listener: ListenerHandle,
+        req_tx: Mutex<Adapter>,
+        hq_tx: OutgoingStream,
+    ) -> Self {
+        Self {
+            config,
+            state,
+            listener,
+            req_tx,
+            hq_tx,
+        }
+    }
+
+    /// Waits for SIGTERM and drains the gateway.
+    ///
+    /// Must be called within the Tokio runtime.
+    pub(crate) fn run(self) -> impl Future<Item = (), Error = ()> {
+        Signal::new(SIGTERM)
+            .flatten_stream()
+            .into_future()
+            .map_err(|(err, _)| error!("Error handling SIGTERM: {}", err))
+            .and_then(move |_| self.shutdown())
+            .then(|_| Ok(()))
+    }
+
+    fn shutdown(self) -> impl Future<Item = (), Error = ()> {
+        let Self {
+            config,
+            state,
+            listener,
+            req_tx,
+            hq_tx,
+        } = self;
+
+        info!(
+            "SIGTERM received, shutting down within {:?}",
+            config.grace_period
+        );
+
+        // Fail readiness and stop accepting connections. Accepted ones are served till the end.
+        state.health.set_shutting_down();
+        listener.stop();
+
+        let started_at = Instant::now();
+
+        wait_idle(req_tx, hq_tx.clone())
+            .timeout(config.grace_period)
+            .then(move |result| {
+                match result {
+                    Ok(()) => info!("Drained in {:?}", started_at.elapsed()),
+                    Err(_) => warn!(
+                        "Grace period expired with {} undelivered events",
+                        hq_tx.pending()
+                    ),
+                }
+
+                if let Err(err) = hq_tx.close(config.queue_path()) {
+                    error!("Error closing the outgoing stream, {:#}", err);
+                }
+
+                wait_until(POLL_INTERVAL, move || hq_tx.pending() == 0).timeout(CLOSE_TIMEOUT)
+            })
+            .map_err(|_| error!("Timed out persisting undelivered events"))
+    }
+}
+
+/// Disconnects the agent from the broker and waits for the runtime to complete the tasks left
+/// after the drain, e.g. responses on open connections and span exports, for `CLOSE_TIMEOUT`
+/// at most.
+///
+/// Undelivered events are persisted by then so the broker gets a clean DISCONNECT.
+pub(crate) fn finish(runtime: Runtime, agent: &AgentHandle) {
+    if let Err(err) = agent.disconnect(CLOSE_TIMEOUT) {
+        error!("Error disconnecting from broker, {:#}", err);
+    }
+
+    let (tx, rx) = std::sync::mpsc::channel();
+
+    thread::spawn(move || {
+        let _ = runtime.shutdown_on_idle().wait();
+        let _ = tx.send(());
+    });
+
+    match rx.recv_timeout(CLOSE_TIMEOUT) {
+        Ok(()) => info!("Shutdown complete"),
+        Err(_) => warn!("Shutdown complete, closing the remaining connections"),
+    }
+}
+
+/// Resolves when there are neither MQTT requests waiting for responses nor undelivered events.
+fn wait_idle(req_tx: Mutex<Adapter>, hq_tx: OutgoingStream) -> impl Future<Item = (), Error = ()> {
+    Interval::new(Instant::now(), POLL_INTERVAL)
+        .map_err(|err| error!("Timer error: {}", err))
+        .and_then(move |_| req_tx.lock().map(|mut adapter| adapter.in_flight()))
+        .skip_while(move |in_flight| Ok(*in_flight > 0 || hq_tx.pending() > 0))
+        .into_future()
+        .map(|_| ())
+        .map_err(|_| ())
+}
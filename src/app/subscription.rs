@@ -0,0 +1,219 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::{format_err, Context, Result};
+use futures::future::{self, Loop};
+use futures::Future;
+use log::{error, info, warn};
+use serde_derive::Deserialize;
+use svc_agent::mqtt::{Agent, QoS};
+use svc_agent::{AgentId, Subscription};
+use svc_error::{extension::sentry, Error as SvcError};
+use tokio::prelude::FutureExt;
+use tokio::timer::Delay;
+
+use super::endpoint;
+use super::{shared_group, State};
+use crate::util::agent::AgentHandle;
+use crate::util::health::Health;
+use crate::util::metrics::Metrics;
+use crate::util::poll::wait_until;
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Key of the app's responses subscription in the subscriptions state.
+pub(crate) const RESPONSES: &str = "responses";
+
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct Config {
+    /// Delay before the first retry, doubled on each next one up to `max_backoff`.
+    #[serde(deserialize_with = "crate::serde::duration")]
+    #[serde(default = "Config::default_initial_backoff")]
+    initial_backoff: Duration,
+    #[serde(deserialize_with = "crate::serde::duration")]
+    #[serde(default = "Config::default_max_backoff")]
+    max_backoff: Duration,
+    /// Subscriptions not acknowledged by the broker within this time are sent again.
+    #[serde(deserialize_with = "crate::serde::duration")]
+    #[serde(default = "Config::default_confirmation_timeout")]
+    confirmation_timeout: Duration,
+}
+
+impl Config {
+    fn default_initial_backoff() -> Duration {
+        Duration::from_secs(1)
+    }
+
+    fn default_max_backoff() -> Duration {
+        Duration::from_secs(30)
+    }
+
+    fn default_confirmation_timeout() -> Duration {
+        Duration::from_secs(10)
+    }
+
+    fn backoff(&self, attempt: u32) -> Duration {
+        self.initial_backoff
+            .checked_mul(1 << attempt.min(16))
+            .map_or(self.max_backoff, |backoff| backoff.min(self.max_backoff))
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            initial_backoff: Self::default_initial_backoff(),
+            max_backoff: Self::default_max_backoff(),
+            confirmation_timeout: Self::default_confirmation_timeout(),
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Subscribes to app's responses and events of the tenants skipping the ones already confirmed.
+pub(crate) fn subscribe(
+    agent: &mut Agent,
+    agent_id: &AgentId,
+    events: &endpoint::event::ConfigMap,
+    health: &Health,
+) -> Result<()> {
+    let group = shared_group(agent_id);
+
+    // Responses
+    if !health.is_subscription_confirmed(RESPONSES) {
+        let result = agent
+            .subscribe(&Subscription::unicast_responses(), QoS::AtLeastOnce, None)
+            .context("Error subscribing to app's responses topic");
+
+        track(health, RESPONSES, result)?;
+    }
+
+    // Events matching the topic pattern of each tenant
+    for (key, subscription) in endpoint::event::subscriptions(events) {
+        if !health.is_subscription_confirmed(&key) {
+            track(health, &key, subscription.subscribe(agent, &group))?;
+        }
+    }
+
+    Ok(())
+}
+
+fn track(health: &Health, topic: &str, result: Result<()>) -> Result<()> {
+    match result {
+        Ok(()) => health.subscription_requested(topic),
+        Err(_) => health.subscription_failed(topic),
+    }
+
+    result
+}
+
+/// Subscribes again after reconnection since the broker drops subscriptions with the connection.
+///
+/// Retries with backoff until the broker confirms all subscriptions.
+/// Stops if the connection is lost again, the next reconnection starts over.
+pub(crate) fn resubscribe(
+    config: Config,
+    agent: AgentHandle,
+    agent_id: AgentId,
+    state: Arc<State>,
+    metrics: Arc<Metrics>,
+) -> impl Future<Item = (), Error = ()> {
+    let epoch = state.health.reset_subscriptions();
+
+    future::loop_fn(0, move |attempt| {
+        metrics.inc_resubscription_attempts();
+
+        let mut agent = agent.get();
+
+        let result = state
+            .event
+            .read()
+            .map_err(|_| format_err!("error acquiring a lock for events state"))
+            .and_then(|event_state| {
+                subscribe(&mut agent, &agent_id, event_state.config(), &state.health)
+            });
+
+        let wait = match result {
+            Ok(()) => config.confirmation_timeout,
+            Err(err) => {
+                report(attempt, &err);
+                Duration::from_secs(0)
+            }
+        };
+
+        let config = config.clone();
+        let state = state.clone();
+        let state_ = state.clone();
+
+        wait_until(POLL_INTERVAL, move || {
+            state_.health.is_subscribed() || state_.health.subscriptions_epoch() != epoch
+        })
+        .timeout(wait)
+        .then(move |_| {
+            let health = &state.health;
+
+            if health.subscriptions_epoch() != epoch || !health.is_connected() {
+                return future::Either::A(future::ok(Loop::Break(())));
+            }
+
+            if health.is_subscribed() {
+                info!(
+                    "Resubscribed after reconnection, attempts = '{}'",
+                    attempt + 1
+                );
+                return future::Either::A(future::ok(Loop::Break(())));
+            }
+
+            let backoff = config.backoff(attempt);
+
+            warn!(
+                "Subscriptions = '{}' are missing after reconnection, retrying in {:?}",
+                health.missing_subscriptions().join(", "),
+                backoff
+            );
+
+            let retry = Delay::new(Instant::now() + backoff)
+                .map_err(|err| error!("Timer error: {}", err))
+                .map(move |()| Loop::Continue(attempt + 1));
+
+            future::Either::B(retry)
+        })
+    })
+}
+
+fn report(attempt: u32, err: &anyhow::Error) {
+    let err = format!(
+        "Failed to resubscribe after reconnection, attempt = '{}': {:#}",
+        attempt + 1,
+        err
+    );
+
+    error!("{}", err);
+
+    let svc_error = SvcError::builder()
+        .kind("resubscription_error", "Resubscription error")
+        .detail(&err)
+        .build();
+
+    sentry::send(svc_error).unwrap_or_else(|err| warn!("Error sending error to Sentry: {}", err));
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn doubles_backoff_up_to_max() {
+        let config = Config::default();
+        assert_eq!(config.backoff(0), Duration::from_secs(1));
+        assert_eq!(config.backoff(1), Duration::from_secs(2));
+        assert_eq!(config.backoff(4), Duration::from_secs(16));
+        assert_eq!(config.backoff(5), Duration::from_secs(30));
+        assert_eq!(config.backoff(100), Duration::from_secs(30));
+    }
+}
@@ -1,4 +1,3 @@
 fn main() {
-    env_logger::init();
     http_gateway::run();
 }
@@ -3,21 +3,31 @@
 // thus clippy fails when HeaderValue is used as key in BTreeSet
 #![allow(clippy::mutable_key_type)]
 
+use http::header::HeaderName;
+use http::Uri;
 use serde::de;
 use serde::de::{Deserializer, Error, SeqAccess, Unexpected, Visitor};
+use serde::ser::Serializer;
+use std::convert::TryFrom;
 use std::fmt;
 use std::time::Duration;
 use tower_web::middleware::cors::AllowedOrigins;
 
+use crate::util::topic::TopicPattern;
+
 ////////////////////////////////////////////////////////////////////////////////
 
 struct DurationVisitor;
 
+// Bare numbers are seconds.
 impl<'de> Visitor<'de> for DurationVisitor {
     type Value = Duration;
 
     fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-        write!(formatter, "an u64")
+        write!(
+            formatter,
+            "seconds or a duration like '500ms', '5s', '2m' or '1h'"
+        )
     }
 
     fn visit_u64<E>(self, v: u64) -> Result<Self::Value, E>
@@ -26,13 +36,53 @@ impl<'de> Visitor<'de> for DurationVisitor {
     {
         Ok(Duration::new(v, 0))
     }
+
+    fn visit_i64<E>(self, v: i64) -> Result<Self::Value, E>
+    where
+        E: de::Error,
+    {
+        match u64::try_from(v) {
+            Ok(v) => self.visit_u64(v),
+            Err(_) => Err(Error::invalid_value(Unexpected::Signed(v), &self)),
+        }
+    }
+
+    fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+    where
+        E: de::Error,
+    {
+        let v = v.trim();
+        let split = v.find(|c: char| !c.is_ascii_digit()).unwrap_or(v.len());
+        let (value, unit) = v.split_at(split);
+
+        let value = value
+            .parse::<u64>()
+            .map_err(|_| Error::invalid_value(Unexpected::Str(v), &self))?;
+
+        let duration = match unit.trim() {
+            "ms" => Some(Duration::from_millis(value)),
+            "" | "s" => Some(Duration::from_secs(value)),
+            "m" => value.checked_mul(60).map(Duration::from_secs),
+            "h" => value.checked_mul(3600).map(Duration::from_secs),
+            _ => None,
+        };
+
+        duration.ok_or_else(|| Error::invalid_value(Unexpected::Str(v), &self))
+    }
 }
 
 pub fn duration<'de, D>(deserializer: D) -> Result<Duration, D::Error>
 where
     D: Deserializer<'de>,
 {
-    deserializer.deserialize_u64(DurationVisitor)
+    deserializer.deserialize_any(DurationVisitor)
+}
+
+pub(crate) fn optional_duration<'de, D>(deserializer: D) -> Result<Option<Duration>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    duration(deserializer).map(Some)
 }
 
 ////////////////////////////////////////////////////////////////////////////////
@@ -66,15 +116,153 @@ impl<'de> Visitor<'de> for AllowedOriginsVisitor {
         let mut origins = BTreeSet::new();
         while let Some(value) = seq.next_element()? {
             let value: String = value;
-            let _ = HeaderValue::from_str(&value).map(|v| origins.insert(v));
+
+            let origin = Some(&value)
+                .filter(|value| is_origin(value))
+                .and_then(|value| HeaderValue::from_str(value).ok())
+                .ok_or_else(|| {
+                    A::Error::custom(format!(
+                        "invalid origin = '{}', expected 'scheme://host[:port]'",
+                        value
+                    ))
+                })?;
+
+            origins.insert(origin);
         }
         Ok(AllowedOrigins::Origins(origins))
     }
 }
 
+// Browsers send origins serialized as `scheme://host[:port]` without a path or a trailing slash.
+fn is_origin(value: &str) -> bool {
+    match value.parse::<Uri>() {
+        Ok(uri) => match (uri.scheme_part(), uri.authority_part()) {
+            (Some(scheme), Some(authority)) => {
+                value == format!("{}://{}", scheme, authority) && !authority.as_str().contains('@')
+            }
+            _ => false,
+        },
+        Err(_) => false,
+    }
+}
+
 pub fn allowed_origins<'de, D>(deserializer: D) -> Result<AllowedOrigins, D::Error>
 where
     D: Deserializer<'de>,
 {
     deserializer.deserialize_seq(AllowedOriginsVisitor)
 }
+
+////////////////////////////////////////////////////////////////////////////////
+
+struct TopicPatternVisitor;
+
+impl<'de> Visitor<'de> for TopicPatternVisitor {
+    type Value = TopicPattern;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(formatter, "a topic pattern like 'rooms/{{room_id}}/events'")
+    }
+
+    fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+    where
+        E: Error,
+    {
+        v.parse::<TopicPattern>().map_err(|err| E::custom(err))
+    }
+}
+
+pub(crate) fn topic_pattern<'de, D>(deserializer: D) -> Result<TopicPattern, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    deserializer.deserialize_str(TopicPatternVisitor)
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+pub(crate) fn header_name<'de, D>(deserializer: D) -> Result<HeaderName, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value = <String as de::Deserialize>::deserialize(deserializer)?;
+
+    HeaderName::from_bytes(value.as_bytes())
+        .map_err(|_| Error::invalid_value(Unexpected::Str(&value), &"an HTTP header name"))
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+pub(crate) fn display<T, S>(value: &T, serializer: S) -> Result<S::Ok, S::Error>
+where
+    T: fmt::Display,
+    S: Serializer,
+{
+    serializer.collect_str(value)
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test {
+    use serde_derive::Deserialize;
+    use serde_json::json;
+
+    use super::*;
+
+    #[derive(Debug, Deserialize)]
+    struct Durations {
+        #[serde(deserialize_with = "duration")]
+        value: Duration,
+    }
+
+    fn parse_duration(value: serde_json::Value) -> Result<Duration, serde_json::Error> {
+        serde_json::from_value::<Durations>(json!({ "value": value })).map(|d| d.value)
+    }
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_duration(json!(5)).unwrap(), Duration::from_secs(5));
+        assert_eq!(parse_duration(json!("5")).unwrap(), Duration::from_secs(5));
+        assert_eq!(
+            parse_duration(json!("500ms")).unwrap(),
+            Duration::from_millis(500)
+        );
+        assert_eq!(parse_duration(json!("5s")).unwrap(), Duration::from_secs(5));
+        assert_eq!(
+            parse_duration(json!("2m")).unwrap(),
+            Duration::from_secs(120)
+        );
+        assert_eq!(
+            parse_duration(json!("1h")).unwrap(),
+            Duration::from_secs(3600)
+        );
+        assert!(parse_duration(json!("5 days")).is_err());
+        assert!(parse_duration(json!("ms")).is_err());
+        assert!(parse_duration(json!(-1)).is_err());
+    }
+
+    #[derive(Debug, Deserialize)]
+    struct Origins {
+        #[serde(deserialize_with = "allowed_origins")]
+        value: AllowedOrigins,
+    }
+
+    #[test]
+    fn rejects_invalid_origins() {
+        let origins = json!({ "value": ["https://example.org", "http://localhost:8080"] });
+        assert!(serde_json::from_value::<Origins>(origins).is_ok());
+
+        for origin in &[
+            "https://example.org/",
+            "example.org",
+            "https://example.org/app",
+        ] {
+            let err = serde_json::from_value::<Origins>(json!({ "value": [origin] }))
+                .expect_err("Invalid origin accepted")
+                .to_string();
+
+            assert!(err.contains(origin), "{}", err);
+        }
+    }
+}
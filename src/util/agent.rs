@@ -0,0 +1,487 @@
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use anyhow::{format_err, Result};
+use futures::sync::mpsc::UnboundedSender;
+use log::{error, info};
+use svc_agent::mqtt::{Agent, AgentNotification};
+use svc_agent::AgentId;
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Delay before connecting again after the connection is lost or connecting has failed.
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+pub(crate) type Notifications = Box<dyn Iterator<Item = AgentNotification> + Send>;
+
+enum Event {
+    /// A notification of the agent of the generation.
+    Notification(u64, AgentNotification),
+    /// Disconnect the current agent and stop, the sender is notified once done.
+    Stop(Sender<()>),
+}
+
+/// The current MQTT agent.
+///
+/// The agent is replaced when its password token is re-minted so components get it
+/// for each use instead of keeping a clone.
+#[derive(Clone)]
+pub(crate) struct AgentHandle<A = Agent> {
+    id: AgentId,
+    agent: Arc<RwLock<A>>,
+    events: Arc<Mutex<Sender<Event>>>,
+}
+
+impl<A: Clone> AgentHandle<A> {
+    fn new(id: AgentId, agent: A, events: Sender<Event>) -> Self {
+        Self {
+            id,
+            agent: Arc::new(RwLock::new(agent)),
+            events: Arc::new(Mutex::new(events)),
+        }
+    }
+
+    /// The id stays the same when the agent is replaced.
+    pub(crate) fn id(&self) -> &AgentId {
+        &self.id
+    }
+
+    pub(crate) fn get(&self) -> A {
+        match self.agent.read() {
+            Ok(agent) => agent.clone(),
+            Err(poisoned) => poisoned.into_inner().clone(),
+        }
+    }
+
+    /// Disconnects the current agent from the broker and stops replacing it, waits for
+    /// `timeout` at most.
+    pub(crate) fn disconnect(&self, timeout: Duration) -> Result<()> {
+        let (done_tx, done_rx) = mpsc::channel();
+
+        self.events
+            .lock()
+            .map_err(|_| format_err!("error acquiring a lock for agent events"))?
+            .send(Event::Stop(done_tx))
+            .map_err(|_| format_err!("agent supervisor has stopped"))?;
+
+        done_rx
+            .recv_timeout(timeout)
+            .map_err(|_| format_err!("timed out disconnecting the agent"))
+    }
+
+    fn replace(&self, agent: A) -> A {
+        let mut current = match self.agent.write() {
+            Ok(current) => current,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+
+        std::mem::replace(&mut *current, agent)
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Starts agents connected to the broker with the given password.
+pub(crate) trait Connector: Send + 'static {
+    type Agent: Clone + Send + Sync + 'static;
+
+    fn connect(&mut self, password: &str) -> Result<(Self::Agent, Notifications)>;
+
+    /// Disconnects the agent from the broker so that its notifications end.
+    fn disconnect(&mut self, agent: Self::Agent);
+}
+
+/// Keeps the agent connected with an unexpired password token.
+///
+/// The token is re-minted and the agent is replaced by a new one at 3/4 of the token's
+/// lifetime and after the connection is lost. Notifications of the current agent are passed
+/// to the gateway. A replacement is passed as a `Disconnection`, unless the connection is
+/// already lost, followed by a `Reconnection` so that requests in flight on the replaced agent
+/// are failed and subscriptions are restored. Replaced agents are disconnected; only their
+/// messages which have arrived before that are passed, e.g. responses.
+pub(crate) struct Supervisor<C: Connector, M> {
+    connector: C,
+    mint: M,
+    lifetime: Option<Duration>,
+    handle: AgentHandle<C::Agent>,
+    tx: UnboundedSender<AgentNotification>,
+    connected: bool,
+    forwarder: Option<JoinHandle<()>>,
+}
+
+impl<C, M> Supervisor<C, M>
+where
+    C: Connector,
+    M: FnMut() -> Result<String> + Send + 'static,
+{
+    /// Connects the first agent. Without `lifetime` the token never expires so the agent
+    /// is never replaced.
+    pub(crate) fn start(
+        mut connector: C,
+        mut mint: M,
+        lifetime: Option<Duration>,
+        agent_id: AgentId,
+        tx: UnboundedSender<AgentNotification>,
+    ) -> Result<AgentHandle<C::Agent>> {
+        let (agent, notifications) = connector.connect(&mint()?)?;
+        let (events_tx, events_rx) = mpsc::channel();
+        let handle = AgentHandle::new(agent_id, agent, events_tx.clone());
+
+        let supervisor = Self {
+            connector,
+            mint,
+            lifetime,
+            handle: handle.clone(),
+            tx,
+            connected: true,
+            forwarder: None,
+        };
+
+        thread::spawn(move || supervisor.run(notifications, events_tx, events_rx));
+        Ok(handle)
+    }
+
+    fn run(
+        mut self,
+        notifications: Notifications,
+        events_tx: Sender<Event>,
+        events_rx: mpsc::Receiver<Event>,
+    ) {
+        let mut generation = 0;
+        self.forwarder = Some(forward(generation, notifications, events_tx.clone()));
+
+        let mut reconnect_at = self.rotation_time();
+
+        loop {
+            let event = match reconnect_at {
+                Some(at) => events_rx.recv_timeout(at.saturating_duration_since(Instant::now())),
+                None => events_rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+            };
+
+            let delivered = match event {
+                Ok(Event::Stop(done_tx)) => {
+                    let agent = self.handle.get();
+                    self.retire(agent);
+                    info!("Disconnected from broker");
+
+                    let _ = done_tx.send(());
+                    return;
+                }
+                Ok(Event::Notification(gen, AgentNotification::Disconnection))
+                    if gen == generation =>
+                {
+                    self.connected = false;
+
+                    if self.lifetime.is_some() {
+                        reconnect_at = Some(Instant::now() + RECONNECT_DELAY);
+                    }
+
+                    self.send(AgentNotification::Disconnection)
+                }
+                Ok(Event::Notification(gen, AgentNotification::Disconnection))
+                | Ok(Event::Notification(gen, AgentNotification::Reconnection))
+                    if gen != generation =>
+                {
+                    true
+                }
+                Ok(Event::Notification(_, notification)) => self.send(notification),
+                Err(RecvTimeoutError::Timeout) => match self.connect() {
+                    Ok((agent, notifications)) => {
+                        // Responses to requests published by the replaced agent won't arrive.
+                        if self.connected && !self.send(AgentNotification::Disconnection) {
+                            return;
+                        }
+
+                        let replaced = self.handle.replace(agent);
+                        self.retire(replaced);
+
+                        generation += 1;
+                        self.forwarder =
+                            Some(forward(generation, notifications, events_tx.clone()));
+                        self.connected = true;
+                        reconnect_at = self.rotation_time();
+
+                        info!("Connected to broker with a new password token");
+                        self.send(AgentNotification::Reconnection)
+                    }
+                    Err(err) => {
+                        error!(
+                            "Failed to connect to broker with a new password token, {:#}",
+                            err
+                        );
+
+                        reconnect_at = Some(Instant::now() + RECONNECT_DELAY);
+                        true
+                    }
+                },
+                Err(RecvTimeoutError::Disconnected) => false,
+            };
+
+            // The gateway has stopped.
+            if !delivered {
+                return;
+            }
+        }
+    }
+
+    fn connect(&mut self) -> Result<(C::Agent, Notifications)> {
+        let password = (self.mint)()?;
+        self.connector.connect(&password)
+    }
+
+    /// Disconnects the agent and waits for its notifications to end.
+    fn retire(&mut self, agent: C::Agent) {
+        self.connector.disconnect(agent);
+
+        if let Some(forwarder) = self.forwarder.take() {
+            if forwarder.join().is_err() {
+                error!("Notifications forwarder of the agent has panicked");
+            }
+        }
+    }
+
+    fn rotation_time(&self) -> Option<Instant> {
+        self.lifetime
+            .map(|lifetime| Instant::now() + lifetime * 3 / 4)
+    }
+
+    fn send(&self, notification: AgentNotification) -> bool {
+        self.tx.unbounded_send(notification).is_ok()
+    }
+}
+
+fn forward(generation: u64, notifications: Notifications, tx: Sender<Event>) -> JoinHandle<()> {
+    thread::spawn(move || {
+        for notification in notifications {
+            if tx
+                .send(Event::Notification(generation, notification))
+                .is_err()
+            {
+                return;
+            }
+        }
+    })
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test {
+    use std::sync::Mutex;
+
+    use futures::sync::mpsc::{unbounded, UnboundedReceiver};
+    use futures::Stream;
+    use svc_agent::AccountId;
+
+    use super::*;
+
+    #[derive(Clone, Debug, PartialEq)]
+    struct FakeAgent {
+        password: String,
+    }
+
+    /// Password and the sender of notifications of each agent started, `None` once disconnected.
+    type Connections = Vec<(String, Option<Sender<AgentNotification>>)>;
+
+    #[derive(Clone, Default)]
+    struct FakeConnector {
+        connections: Arc<Mutex<Connections>>,
+        // Notification iterators dropped by their forwarders.
+        finished: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl FakeConnector {
+        fn passwords(&self) -> Vec<String> {
+            let connections = self.connections.lock().unwrap();
+            connections.iter().map(|(p, _)| p.to_owned()).collect()
+        }
+
+        fn disconnected(&self) -> Vec<String> {
+            let connections = self.connections.lock().unwrap();
+
+            connections
+                .iter()
+                .filter(|(_, tx)| tx.is_none())
+                .map(|(p, _)| p.to_owned())
+                .collect()
+        }
+
+        fn finished(&self) -> Vec<String> {
+            self.finished.lock().unwrap().clone()
+        }
+
+        fn notify(&self, generation: usize, notification: AgentNotification) {
+            let connections = self.connections.lock().unwrap();
+
+            connections[generation]
+                .1
+                .as_ref()
+                .expect("Agent is disconnected")
+                .send(notification)
+                .unwrap();
+        }
+    }
+
+    impl Connector for FakeConnector {
+        type Agent = FakeAgent;
+
+        fn connect(&mut self, password: &str) -> Result<(FakeAgent, Notifications)> {
+            let (tx, rx) = mpsc::channel();
+            let mut connections = self.connections.lock().unwrap();
+            connections.push((password.to_owned(), Some(tx)));
+
+            let agent = FakeAgent {
+                password: password.to_owned(),
+            };
+
+            let notifications = FakeNotifications {
+                password: password.to_owned(),
+                rx: rx.into_iter(),
+                finished: self.finished.clone(),
+            };
+
+            Ok((agent, Box::new(notifications)))
+        }
+
+        fn disconnect(&mut self, agent: FakeAgent) {
+            let mut connections = self.connections.lock().unwrap();
+
+            for (password, tx) in connections.iter_mut() {
+                if *password == agent.password {
+                    tx.take();
+                }
+            }
+        }
+    }
+
+    struct FakeNotifications {
+        password: String,
+        rx: mpsc::IntoIter<AgentNotification>,
+        finished: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl Iterator for FakeNotifications {
+        type Item = AgentNotification;
+
+        fn next(&mut self) -> Option<AgentNotification> {
+            self.rx.next()
+        }
+    }
+
+    impl Drop for FakeNotifications {
+        fn drop(&mut self) {
+            let mut finished = self.finished.lock().unwrap();
+            finished.push(self.password.to_owned());
+        }
+    }
+
+    fn start(
+        lifetime: Duration,
+    ) -> (
+        FakeConnector,
+        AgentHandle<FakeAgent>,
+        UnboundedReceiver<AgentNotification>,
+    ) {
+        let connector = FakeConnector::default();
+        let (tx, rx) = unbounded();
+        let mut minted = 0;
+
+        let mint = move || {
+            minted += 1;
+            Ok(format!("token-{}", minted))
+        };
+
+        let agent_id = AgentId::new("alpha", AccountId::new("http-gateway", "svc.example.org"));
+
+        let handle = Supervisor::start(connector.clone(), mint, Some(lifetime), agent_id, tx)
+            .expect("Failed to start agent");
+
+        (connector, handle, rx)
+    }
+
+    #[test]
+    fn reconnects_with_new_token() {
+        let (connector, handle, rx) = start(Duration::from_secs(3600));
+        let mut rx = rx.wait();
+        assert_eq!(handle.get().password, "token-1");
+
+        connector.notify(0, AgentNotification::Disconnection);
+
+        let notification = rx.next().unwrap().unwrap();
+        assert!(matches!(notification, AgentNotification::Disconnection));
+
+        // The connection is already lost so there is no other disconnection.
+        let notification = rx.next().unwrap().unwrap();
+        assert!(matches!(notification, AgentNotification::Reconnection));
+        assert_eq!(handle.get().password, "token-2");
+        assert_eq!(connector.passwords(), vec!["token-1", "token-2"]);
+
+        connector.notify(1, AgentNotification::Disconnection);
+
+        let notification = rx.next().unwrap().unwrap();
+        assert!(matches!(notification, AgentNotification::Disconnection));
+
+        let notification = rx.next().unwrap().unwrap();
+        assert!(matches!(notification, AgentNotification::Reconnection));
+        assert_eq!(handle.get().password, "token-3");
+    }
+
+    #[test]
+    fn rotates_token_before_expiration() {
+        let (connector, handle, rx) = start(Duration::from_millis(400));
+        let mut rx = rx.wait();
+        let started_at = Instant::now();
+
+        // Requests in flight on the replaced agent are failed as on a disconnection.
+        let notification = rx.next().unwrap().unwrap();
+        assert!(matches!(notification, AgentNotification::Disconnection));
+        assert!(started_at.elapsed() >= Duration::from_millis(300));
+
+        let notification = rx.next().unwrap().unwrap();
+        assert!(matches!(notification, AgentNotification::Reconnection));
+        assert_eq!(handle.get().password, "token-2");
+        assert_eq!(connector.passwords(), vec!["token-1", "token-2"]);
+    }
+
+    #[test]
+    fn shuts_down_replaced_agent() {
+        let (connector, _handle, rx) = start(Duration::from_secs(3600));
+        let mut rx = rx.wait();
+
+        connector.notify(0, AgentNotification::Disconnection);
+
+        let notification = rx.next().unwrap().unwrap();
+        assert!(matches!(notification, AgentNotification::Disconnection));
+
+        // The replaced agent is disconnected and its forwarder has ended by the reconnection.
+        let notification = rx.next().unwrap().unwrap();
+        assert!(matches!(notification, AgentNotification::Reconnection));
+        assert_eq!(connector.disconnected(), vec!["token-1"]);
+        assert_eq!(connector.finished(), vec!["token-1"]);
+
+        // The new agent's notifications are still passed.
+        connector.notify(1, AgentNotification::Disconnection);
+
+        let notification = rx.next().unwrap().unwrap();
+        assert!(matches!(notification, AgentNotification::Disconnection));
+    }
+
+    #[test]
+    fn disconnects_on_stop() {
+        let (connector, handle, rx) = start(Duration::from_secs(3600));
+        let mut rx = rx.wait();
+
+        handle
+            .disconnect(Duration::from_secs(1))
+            .expect("Failed to disconnect");
+
+        assert_eq!(connector.disconnected(), vec!["token-1"]);
+        assert_eq!(connector.finished(), vec!["token-1"]);
+
+        // The supervisor has stopped so there are neither notifications nor replacements.
+        assert!(rx.next().is_none());
+        assert!(handle.disconnect(Duration::from_secs(1)).is_err());
+    }
+}
@@ -0,0 +1,122 @@
+use anyhow::{format_err, Context, Result};
+use serde_json::{Map as JsonMap, Value as JsonValue};
+use svc_agent::mqtt::{
+    Address, IntoPublishableMessage, OutgoingMessage, OutgoingRequest, PublishableDump,
+    PublishableMessage,
+};
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Outgoing MQTT request with properties svc-agent has no fields for, e.g. the trace context.
+///
+/// The properties are added to the envelope svc-agent builds so the topic and the rest of
+/// the envelope are the same as of a plain request.
+pub(crate) struct ExtendedRequest<T> {
+    request: OutgoingRequest<T>,
+    properties: JsonMap<String, JsonValue>,
+}
+
+impl<T> ExtendedRequest<T> {
+    pub(crate) fn new(request: OutgoingRequest<T>) -> Self {
+        Self {
+            request,
+            properties: JsonMap::new(),
+        }
+    }
+
+    pub(crate) fn correlation_data(&self) -> &str {
+        self.request.properties().correlation_data()
+    }
+
+    pub(crate) fn set_property(&mut self, name: &str, value: JsonValue) {
+        self.properties.insert(name.to_owned(), value);
+    }
+}
+
+impl<T: serde::Serialize> IntoPublishableMessage for ExtendedRequest<T> {
+    fn into_dump(
+        self: Box<Self>,
+        address: &Address,
+    ) -> Result<PublishableMessage, svc_agent::Error> {
+        let Self {
+            request,
+            properties,
+        } = *self;
+
+        match Box::new(OutgoingMessage::Request(request)).into_dump(address)? {
+            PublishableMessage::Request(dump) => {
+                let payload = extend(dump.payload(), properties)
+                    .map_err(|err| svc_agent::Error::new(&format!("{:#}", err)))?;
+
+                let dump = PublishableDump::new(dump.topic().to_owned(), dump.qos(), payload);
+                Ok(PublishableMessage::Request(dump))
+            }
+            _ => Err(svc_agent::Error::new(
+                "request is dumped as another message type",
+            )),
+        }
+    }
+}
+
+/// Adds the properties to the serialized envelope. Ones set by svc-agent aren't overridden.
+fn extend(envelope: &str, properties: JsonMap<String, JsonValue>) -> Result<String> {
+    if properties.is_empty() {
+        return Ok(envelope.to_owned());
+    }
+
+    let mut envelope =
+        serde_json::from_str::<JsonValue>(envelope).context("Failed to parse envelope")?;
+
+    let envelope_properties = envelope
+        .get_mut("properties")
+        .and_then(JsonValue::as_object_mut)
+        .ok_or_else(|| format_err!("missing envelope properties"))?;
+
+    for (name, value) in properties {
+        envelope_properties.entry(name).or_insert(value);
+    }
+
+    serde_json::to_string(&envelope).context("Failed to serialize envelope")
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn extends_envelope_properties() {
+        let envelope = json!({
+            "payload": "{}",
+            "properties": { "type": "request", "method": "room.read" },
+        });
+
+        let mut properties = JsonMap::new();
+        properties.insert(
+            "traceparent".to_owned(),
+            json!("00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01"),
+        );
+        properties.insert("method".to_owned(), json!("room.delete"));
+
+        let extended =
+            extend(&envelope.to_string(), properties).expect("Failed to extend envelope");
+        let extended = serde_json::from_str::<JsonValue>(&extended).unwrap();
+
+        assert_eq!(extended["payload"], "{}");
+        assert_eq!(extended["properties"]["method"], "room.read");
+        assert_eq!(
+            extended["properties"]["traceparent"],
+            "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01"
+        );
+
+        assert!(extend("{}", JsonMap::new()).is_ok());
+        assert!(extend(
+            r#"{"payload": "{}"}"#,
+            extended["properties"].as_object().cloned().unwrap()
+        )
+        .is_err());
+    }
+}
@@ -3,9 +3,10 @@ use std::convert::TryFrom;
 use anyhow::{format_err, Context, Error};
 use http::header::{HeaderMap, HeaderName, HeaderValue};
 use serde::ser::Serialize;
+use serde_json::{Map as JsonMap, Value as JsonValue};
 use svc_agent::{mqtt::IncomingMessageContent, Addressable};
 
-#[derive(Debug)]
+#[derive(Debug, serde_derive::Serialize, serde_derive::Deserialize)]
 pub(crate) struct Headers(Vec<(String, String)>);
 
 impl Headers {
@@ -23,11 +24,29 @@ impl Headers {
         }
     }
 
+    pub(crate) fn get(&self, name: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
     pub(crate) fn to_header_map(&self) -> HeaderMap {
         let mut header_map = HeaderMap::new();
         self.add_to_header_map(&mut header_map);
         header_map
     }
+
+    /// Unprefixed properties as a JSON object for non-HTTP sinks.
+    pub(crate) fn to_json(&self) -> JsonValue {
+        let mut object = JsonMap::with_capacity(self.0.len());
+
+        for (name, value) in self.0.iter() {
+            object.insert(name.to_owned(), JsonValue::String(value.to_owned()));
+        }
+
+        JsonValue::Object(object)
+    }
 }
 
 impl<T, P: Addressable + Serialize> TryFrom<&IncomingMessageContent<T, P>> for Headers {
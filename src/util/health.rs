@@ -0,0 +1,242 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use serde_derive::Serialize;
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum SubscriptionStatus {
+    /// Sent to the broker, waiting for SUBACK.
+    Requested,
+    Confirmed,
+    /// Couldn't be sent, will be retried.
+    Failed,
+}
+
+#[derive(Debug, Default)]
+struct Subscriptions {
+    topics: BTreeMap<String, SubscriptionStatus>,
+    // svc-agent doesn't tell the packet id of a SUBSCRIBE but the broker acknowledges them
+    // in the order they've been sent so SUBACKs are matched against this queue. Topics retried
+    // or removed before the acknowledgement leave an empty slot to consume the stale SUBACK.
+    awaiting: VecDeque<Option<String>>,
+    // Incremented on each reset to let resubscription retries of a previous connection stop.
+    epoch: u64,
+}
+
+/// Broker connection state shared between the MQTT event loop and readiness checks.
+#[derive(Debug, Default)]
+pub(crate) struct Health {
+    connected: AtomicBool,
+    subscriptions: Mutex<Subscriptions>,
+    shutting_down: AtomicBool,
+}
+
+impl Health {
+    pub(crate) fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn set_connected(&self, connected: bool) {
+        self.connected.store(connected, Ordering::SeqCst);
+    }
+
+    /// Whether there are subscriptions and all of them have been confirmed by the broker.
+    pub(crate) fn is_subscribed(&self) -> bool {
+        self.with_subscriptions(|subs| {
+            !subs.topics.is_empty()
+                && subs
+                    .topics
+                    .values()
+                    .all(|status| *status == SubscriptionStatus::Confirmed)
+        })
+    }
+
+    /// Topics not confirmed by the broker yet.
+    pub(crate) fn missing_subscriptions(&self) -> Vec<String> {
+        self.with_subscriptions(|subs| {
+            subs.topics
+                .iter()
+                .filter(|(_, status)| **status != SubscriptionStatus::Confirmed)
+                .map(|(topic, _)| topic.to_owned())
+                .collect()
+        })
+    }
+
+    pub(crate) fn subscriptions(&self) -> Vec<(String, SubscriptionStatus)> {
+        self.with_subscriptions(|subs| {
+            subs.topics
+                .iter()
+                .map(|(topic, status)| (topic.to_owned(), *status))
+                .collect()
+        })
+    }
+
+    pub(crate) fn is_subscription_confirmed(&self, topic: &str) -> bool {
+        self.with_subscriptions(|subs| {
+            subs.topics.get(topic) == Some(&SubscriptionStatus::Confirmed)
+        })
+    }
+
+    /// Forgets all subscriptions since the broker drops them with the connection.
+    /// Returns the new epoch.
+    pub(crate) fn reset_subscriptions(&self) -> u64 {
+        self.with_subscriptions(|subs| {
+            subs.topics.clear();
+            subs.awaiting.clear();
+            subs.epoch += 1;
+            subs.epoch
+        })
+    }
+
+    pub(crate) fn subscriptions_epoch(&self) -> u64 {
+        self.with_subscriptions(|subs| subs.epoch)
+    }
+
+    /// Marks the subscription sent to the broker. Only the latest request of a topic counts.
+    pub(crate) fn subscription_requested(&self, topic: &str) {
+        self.with_subscriptions(|subs| {
+            subs.forget_awaiting(topic);
+
+            subs.topics
+                .insert(topic.to_owned(), SubscriptionStatus::Requested);
+
+            subs.awaiting.push_back(Some(topic.to_owned()));
+        })
+    }
+
+    /// Marks the subscription which couldn't be sent to the broker.
+    pub(crate) fn subscription_failed(&self, topic: &str) {
+        self.with_subscriptions(|subs| {
+            subs.forget_awaiting(topic);
+
+            subs.topics
+                .insert(topic.to_owned(), SubscriptionStatus::Failed);
+        })
+    }
+
+    /// Applies the SUBACK to the earliest subscription waiting for it.
+    /// The subscription fails if the broker has rejected it with the 0x80 return code.
+    pub(crate) fn subscription_acknowledged(&self, granted: bool) {
+        self.with_subscriptions(|subs| {
+            if let Some(Some(topic)) = subs.awaiting.pop_front() {
+                if let Some(status) = subs.topics.get_mut(&topic) {
+                    *status = if granted {
+                        SubscriptionStatus::Confirmed
+                    } else {
+                        SubscriptionStatus::Failed
+                    };
+                }
+            }
+        })
+    }
+
+    pub(crate) fn subscription_removed(&self, topic: &str) {
+        self.with_subscriptions(|subs| {
+            subs.forget_awaiting(topic);
+            subs.topics.remove(topic);
+        })
+    }
+
+    pub(crate) fn is_shutting_down(&self) -> bool {
+        self.shutting_down.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn set_shutting_down(&self) {
+        self.shutting_down.store(true, Ordering::SeqCst);
+    }
+
+    fn with_subscriptions<R>(&self, f: impl FnOnce(&mut Subscriptions) -> R) -> R {
+        // The state stays consistent even if a holder of the lock has panicked.
+        let mut subs = match self.subscriptions.lock() {
+            Ok(subs) => subs,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+
+        f(&mut subs)
+    }
+}
+
+impl Subscriptions {
+    fn forget_awaiting(&mut self, topic: &str) {
+        for slot in self.awaiting.iter_mut() {
+            if slot.as_deref() == Some(topic) {
+                *slot = None;
+            }
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test {
+    use super::{Health, SubscriptionStatus};
+
+    #[test]
+    fn waits_for_all_subscriptions() {
+        let health = Health::default();
+        assert!(!health.is_subscribed());
+
+        health.subscription_requested("responses");
+        health.subscription_requested("events");
+        health.subscription_acknowledged(true);
+        assert!(!health.is_subscribed());
+        assert_eq!(health.missing_subscriptions(), vec!["events"]);
+        health.subscription_acknowledged(true);
+        assert!(health.is_subscribed());
+
+        // A late acknowledgement is ignored.
+        health.subscription_acknowledged(true);
+        assert!(health.is_subscribed());
+
+        health.subscription_failed("tenant");
+        assert!(!health.is_subscribed());
+        assert_eq!(
+            health.subscriptions()[2],
+            ("tenant".to_owned(), SubscriptionStatus::Failed)
+        );
+
+        health.subscription_removed("tenant");
+        assert!(health.is_subscribed());
+
+        let epoch = health.subscriptions_epoch();
+        assert_eq!(health.reset_subscriptions(), epoch + 1);
+        health.subscription_acknowledged(true);
+        assert!(!health.is_subscribed());
+    }
+
+    #[test]
+    fn matches_acknowledgements_to_latest_requests() {
+        let health = Health::default();
+
+        // The SUBACK of the first request is stale once the topic is requested again.
+        health.subscription_requested("responses");
+        health.subscription_requested("responses");
+        health.subscription_requested("events");
+        health.subscription_acknowledged(true);
+        assert_eq!(health.missing_subscriptions(), vec!["events", "responses"]);
+        health.subscription_acknowledged(true);
+        assert_eq!(health.missing_subscriptions(), vec!["events"]);
+
+        // Rejected by the broker.
+        health.subscription_acknowledged(false);
+        assert_eq!(
+            health.subscriptions()[0],
+            ("events".to_owned(), SubscriptionStatus::Failed)
+        );
+        assert!(!health.is_subscribed());
+
+        // A SUBACK of a removed topic doesn't confirm the next one.
+        health.subscription_requested("tenant");
+        health.subscription_removed("tenant");
+        health.subscription_requested("events");
+        health.subscription_acknowledged(true);
+        assert_eq!(health.missing_subscriptions(), vec!["events"]);
+        health.subscription_acknowledged(true);
+        assert!(health.is_subscribed());
+    }
+}
@@ -1,52 +1,209 @@
-use std::time::Duration;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::sync::{
+    atomic::{AtomicU64, AtomicUsize, Ordering},
+    Arc, Mutex,
+};
+use std::time::{Duration, Instant};
 
-use anyhow::{Context, Result};
-use futures::{sync::mpsc, Future, Stream};
-use log::{error, info};
+use anyhow::{format_err, Context, Result};
+use chrono::{SecondsFormat, Utc};
+use futures::{future, sync::mpsc, Future, Stream};
+use log::{error, info, warn};
+use serde_derive::{Deserialize, Serialize};
 use serde_json::Value as JsonValue;
 
 use crate::util::headers::Headers;
+use crate::util::logging::{self, Fields};
+use crate::util::metrics::Metrics;
+use crate::util::sink::{self, HttpSink, Sink};
+use crate::util::topic::Variables;
+use crate::util::trace::{SpanKind, TraceContext, Tracer, TRACEPARENT_PROPERTY};
 
 ////////////////////////////////////////////////////////////////////////////////
 
-const DEFAULT_TIMEOUT: u64 = 5;
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
 
 ////////////////////////////////////////////////////////////////////////////////
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize)]
 pub(crate) struct Config {
-    timeout: Option<u64>,
+    #[serde(default, deserialize_with = "crate::serde::optional_duration")]
+    timeout: Option<Duration>,
 }
 
 impl Config {
-    pub(crate) fn timeout(&self) -> u64 {
+    pub(crate) fn timeout(&self) -> Duration {
         self.timeout.unwrap_or(DEFAULT_TIMEOUT)
     }
 }
 
 ////////////////////////////////////////////////////////////////////////////////
 
-type HttpClient = reqwest::r#async::Client;
-
-////////////////////////////////////////////////////////////////////////////////
-
-#[derive(Debug)]
+#[derive(Debug, Serialize, Deserialize)]
 pub(crate) struct OutgoingMessage {
     payload: JsonValue,
     headers: Headers,
-    uri: String,
+    audience: String,
+    sink: sink::Config,
+    callback: Option<String>,
+    // Tokens aren't persisted, the current one of the audience is used on redelivery.
+    #[serde(skip)]
     token: String,
+    expects_reply: bool,
+    /// Topic placeholders captured from the event to publish replies to the tenant's topic.
+    #[serde(default)]
+    vars: Variables,
+    #[serde(skip)]
+    trace: Option<TraceContext>,
 }
 
 impl OutgoingMessage {
-    pub(crate) fn new(payload: JsonValue, headers: Headers, uri: &str, token: &str) -> Self {
+    pub(crate) fn new(
+        payload: JsonValue,
+        headers: Headers,
+        audience: &str,
+        sink: &sink::Config,
+        callback: Option<String>,
+        token: &str,
+    ) -> Self {
         Self {
             payload,
             headers,
-            uri: uri.to_owned(),
+            audience: audience.to_owned(),
+            sink: sink.to_owned(),
+            callback,
             token: token.to_owned(),
+            expects_reply: false,
+            vars: Variables::new(),
+            trace: None,
+        }
+    }
+
+    /// Makes the sink read the reply to the message to publish it to MQTT.
+    ///
+    /// Variables captured from the event's topic are kept to build the topic of reply events.
+    pub(crate) fn expect_reply(mut self, vars: Variables) -> Self {
+        self.expects_reply = true;
+        self.vars = vars;
+        self
+    }
+
+    pub(crate) fn payload(&self) -> &JsonValue {
+        &self.payload
+    }
+
+    pub(crate) fn headers(&self) -> &Headers {
+        &self.headers
+    }
+
+    pub(crate) fn audience(&self) -> &str {
+        &self.audience
+    }
+
+    pub(crate) fn callback(&self) -> Option<&str> {
+        self.callback.as_deref()
+    }
+
+    pub(crate) fn token(&self) -> &str {
+        &self.token
+    }
+
+    pub(crate) fn expects_reply(&self) -> bool {
+        self.expects_reply
+    }
+
+    /// Trace context of the delivery to be propagated to the sink.
+    pub(crate) fn trace(&self) -> Option<&TraceContext> {
+        self.trace.as_ref()
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Tenant's reply to a callback request.
+#[derive(Debug)]
+pub(crate) struct CallbackReply {
+    audience: String,
+    vars: Variables,
+    payload: JsonValue,
+}
+
+impl CallbackReply {
+    pub(crate) fn audience(&self) -> &str {
+        &self.audience
+    }
+
+    pub(crate) fn vars(&self) -> &Variables {
+        &self.vars
+    }
+
+    pub(crate) fn payload(&self) -> &JsonValue {
+        &self.payload
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Delivery state of an audience as shown by the admin API.
+#[derive(Debug, Clone, Default, Serialize)]
+pub(crate) struct AudienceStats {
+    queue_depth: usize,
+    last_delivery: Option<Delivery>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct Delivery {
+    success: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    at: String,
+    latency_ms: u64,
+}
+
+#[derive(Clone, Default)]
+struct Audiences {
+    inner: Arc<Mutex<BTreeMap<String, AudienceStats>>>,
+}
+
+impl Audiences {
+    fn queued(&self, audience: &str) {
+        if let Ok(mut inner) = self.inner.lock() {
+            inner.entry(audience.to_owned()).or_default().queue_depth += 1;
+        }
+    }
+
+    /// Removes the message from the audience's queue recording the delivery result if any.
+    fn dequeued(&self, audience: &str, delivery: Option<Delivery>) {
+        if let Ok(mut inner) = self.inner.lock() {
+            let stats = inner.entry(audience.to_owned()).or_default();
+            stats.queue_depth = stats.queue_depth.saturating_sub(1);
+
+            if delivery.is_some() {
+                stats.last_delivery = delivery;
+            }
         }
     }
+
+    fn delivered(&self, audience: &str, result: Result<(), String>, latency: Duration) {
+        let delivery = Delivery {
+            success: result.is_ok(),
+            error: result.err(),
+            at: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+            latency_ms: latency.as_millis() as u64,
+        };
+
+        self.dequeued(audience, Some(delivery));
+    }
+
+    fn snapshot(&self) -> BTreeMap<String, AudienceStats> {
+        self.inner
+            .lock()
+            .map(|inner| inner.clone())
+            .unwrap_or_default()
+    }
 }
 
 ////////////////////////////////////////////////////////////////////////////////
@@ -54,61 +211,309 @@ impl OutgoingMessage {
 #[derive(Clone)]
 pub(crate) struct OutgoingStream {
     tx: mpsc::UnboundedSender<OutgoingMessage>,
+    timeout: Arc<AtomicU64>,
+    metrics: Arc<Metrics>,
+    // Messages queued or being delivered.
+    pending: Arc<AtomicUsize>,
+    // Set on shutdown, messages are persisted there instead of being delivered.
+    drain: Arc<Mutex<Option<Drain>>>,
+    audiences: Audiences,
+}
+
+struct Drain {
+    file: Option<File>,
+}
+
+impl Drain {
+    fn persist(&mut self, message: &OutgoingMessage) -> Result<()> {
+        let file = match self.file {
+            Some(ref mut file) => file,
+            None => return Err(format_err!("durable queue is not configured")),
+        };
+
+        let mut line = serde_json::to_vec(message).context("Failed to serialize message")?;
+        line.push(b'\n');
+        file.write_all(&line)
+            .context("Failed to write to durable queue")
+    }
 }
 
 impl OutgoingStream {
-    pub(crate) fn new(config: &Config) -> (Self, impl Future<Item = (), Error = ()>) {
+    pub(crate) fn new(
+        config: &Config,
+        reply_tx: mpsc::UnboundedSender<CallbackReply>,
+        metrics: Arc<Metrics>,
+        tracer: Tracer,
+    ) -> (Self, impl Future<Item = (), Error = ()>) {
         let (tx, rx) = mpsc::unbounded::<OutgoingMessage>();
-        let object = Self { tx };
+        let timeout = Arc::new(AtomicU64::new(config.timeout().as_millis() as u64));
 
-        let client = HttpClient::builder()
-            .timeout(Duration::from_secs(config.timeout()))
-            .build()
-            .expect("Error creating HTTP client");
+        let pending = Arc::new(AtomicUsize::new(0));
+        let drain = Arc::new(Mutex::new(None::<Drain>));
+        let audiences = Audiences::default();
+
+        let object = Self {
+            tx,
+            timeout: timeout.clone(),
+            metrics: metrics.clone(),
+            pending: pending.clone(),
+            drain: drain.clone(),
+            audiences: audiences.clone(),
+        };
+
+        let http = Arc::new(HttpSink::new(timeout));
+        let mut sinks: HashMap<sink::Config, Arc<dyn Sink>> = HashMap::new();
+
+        let ostream = rx.for_each(move |outev| {
+            metrics.dec_outgoing_queue_depth();
+
+            if let Ok(mut maybe_drain) = drain.lock() {
+                if let Some(ref mut drain) = *maybe_drain {
+                    if let Err(err) = drain.persist(&outev) {
+                        error!(
+                            "Dropping the message to the sink = '{}' of the audience = '{}' on shutdown, {:#}",
+                            &outev.sink, &outev.audience, err,
+                        );
+                    }
+
+                    audiences.dequeued(&outev.audience, None);
+                    pending.fetch_sub(1, Ordering::SeqCst);
+                    return future::Either::A(future::ok(()));
+                }
+            }
+
+            let sink = match sinks.get(&outev.sink) {
+                Some(sink) => sink.clone(),
+                None => match sink::build(&outev.sink, &http) {
+                    Ok(sink) => {
+                        sinks.insert(outev.sink.clone(), sink.clone());
+                        sink
+                    }
+                    Err(err) => {
+                        error!(
+                            "Error creating the sink = '{}' for the audience = '{}', {:#}",
+                            &outev.sink, &outev.audience, err,
+                        );
+
+                        let detail = format!("{:#}", err);
+                        audiences.delivered(&outev.audience, Err(detail), Duration::from_secs(0));
+                        pending.fetch_sub(1, Ordering::SeqCst);
+                        return future::Either::A(future::ok(()));
+                    }
+                },
+            };
+
+            let pending = pending.clone();
+
+            let future = Self::send_handler(
+                sink.as_ref(),
+                outev,
+                reply_tx.clone(),
+                metrics.clone(),
+                audiences.clone(),
+                &tracer,
+            )
+            .then(move |result| {
+                pending.fetch_sub(1, Ordering::SeqCst);
+                result
+            });
+
+            future::Either::B(future)
+        });
 
-        let ostream = rx.for_each(move |outev| Self::send_handler(&client, outev));
         (object, ostream)
     }
 
+    /// Applies the new timeout to callback requests sent after the call.
+    pub(crate) fn set_config(&self, config: &Config) {
+        self.timeout
+            .store(config.timeout().as_millis() as u64, Ordering::Relaxed);
+    }
+
     pub(crate) fn send(&self, message: OutgoingMessage) -> Result<()> {
+        let audience = message.audience.clone();
+        self.metrics.inc_outgoing_queue_depth();
+        self.pending.fetch_add(1, Ordering::SeqCst);
+        self.audiences.queued(&audience);
+
         self.tx
             .unbounded_send(message)
+            .map_err(|err| {
+                self.metrics.dec_outgoing_queue_depth();
+                self.pending.fetch_sub(1, Ordering::SeqCst);
+                self.audiences.dequeued(&audience, None);
+                err
+            })
             .context("error sending message to the outgoing HTTP stream")
     }
 
+    /// Number of messages queued or being delivered.
+    pub(crate) fn pending(&self) -> usize {
+        self.pending.load(Ordering::SeqCst)
+    }
+
+    /// Queue depth and the last delivery result by audience.
+    pub(crate) fn audiences(&self) -> BTreeMap<String, AudienceStats> {
+        self.audiences.snapshot()
+    }
+
+    /// Stops delivering messages. The rest of the queue is appended to the durable queue file
+    /// if it's given or dropped otherwise.
+    pub(crate) fn close(&self, queue_path: Option<&Path>) -> Result<()> {
+        let file = match queue_path {
+            Some(path) => {
+                let file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .with_context(|| format!("Failed to open durable queue {}", path.display()))?;
+
+                Some(file)
+            }
+            None => None,
+        };
+
+        let mut drain = self
+            .drain
+            .lock()
+            .map_err(|_| format_err!("error acquiring a lock for outgoing stream"))?;
+
+        *drain = Some(Drain { file });
+        Ok(())
+    }
+
+    /// Enqueues messages persisted on the previous shutdown and removes the durable queue file.
+    ///
+    /// Tokens are resolved by audience; messages of unknown audiences are dropped.
+    pub(crate) fn restore<F>(&self, queue_path: &Path, token: F) -> Result<usize>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        if !queue_path.exists() {
+            return Ok(0);
+        }
+
+        let file = File::open(queue_path)
+            .with_context(|| format!("Failed to open durable queue {}", queue_path.display()))?;
+
+        let mut count = 0;
+
+        for line in BufReader::new(file).lines() {
+            let line = line.context("Failed to read durable queue")?;
+
+            let mut message = match serde_json::from_str::<OutgoingMessage>(&line) {
+                Ok(message) => message,
+                Err(err) => {
+                    warn!("Skipping invalid message in durable queue, {}", err);
+                    continue;
+                }
+            };
+
+            match token(&message.audience) {
+                Some(token) => message.token = token,
+                None => {
+                    warn!(
+                        "Skipping message of unknown audience = '{}' in durable queue",
+                        message.audience
+                    );
+
+                    continue;
+                }
+            }
+
+            self.send(message)?;
+            count += 1;
+        }
+
+        fs::remove_file(queue_path)
+            .with_context(|| format!("Failed to remove durable queue {}", queue_path.display()))?;
+
+        Ok(count)
+    }
+
     fn send_handler(
-        client: &HttpClient,
-        outev: OutgoingMessage,
+        sink: &dyn Sink,
+        mut outev: OutgoingMessage,
+        reply_tx: mpsc::UnboundedSender<CallbackReply>,
+        metrics: Arc<Metrics>,
+        audiences: Audiences,
+        tracer: &Tracer,
     ) -> impl Future<Item = (), Error = ()> {
-        client
-            .post(&outev.uri)
-            .bearer_auth(outev.token.to_owned())
-            .headers(outev.headers.to_header_map())
-            .json(&outev.payload)
-            .send()
-            .then(move |resp| {
-                match resp {
-                    Ok(ref res) if res.status().is_success() => {
-                        info!("Message payload = '{}' sent successfully to the HTTP callback = '{}'",
-                              &outev.payload, &outev.uri,
-                        );
-                    }
-                    Ok(res) => {
-                        error!(
-                            "Error with status code = '{}' on sending the message payload = '{}' to the HTTP callback = '{}'",
-                            res.status(), &outev.payload, &outev.uri,
-                        );
+        let started_at = Instant::now();
+
+        // Continue the trace of the request which caused the event if the service propagated it.
+        let parent = outev
+            .headers
+            .get(TRACEPARENT_PROPERTY)
+            .and_then(TraceContext::parse);
+
+        let mut span = tracer.start(
+            &format!("callback {}", outev.audience),
+            SpanKind::Client,
+            parent.as_ref(),
+        );
+
+        span.set_attribute("audience", &outev.audience);
+        span.set_attribute("sink", &outev.sink.to_string());
+
+        if tracer.is_enabled() {
+            outev.trace = Some(span.context().to_owned());
+        }
+
+        sink.send(&outev).then(move |result| {
+            let latency = started_at.elapsed();
+            metrics.observe_callback_delivery(&outev.audience, result.is_ok(), latency);
+            span.end(result.is_ok());
+
+            let delivery = match result {
+                Ok(_) => Ok(()),
+                Err(ref err) => Err(format!("{:#}", err)),
+            };
+
+            audiences.delivered(&outev.audience, delivery, latency);
+
+            let mut log_fields = Fields::default()
+                .audience(&outev.audience)
+                .latency(latency)
+                .payload(&outev.payload);
+
+            if let Some(label) = outev.headers.get("label") {
+                log_fields = log_fields.method(label);
+            }
+
+            match result {
+                Ok(maybe_reply) => {
+                    logging::with_fields(log_fields, || {
+                        info!(
+                            "Message sent successfully to the sink = '{}' of the audience = '{}'",
+                            &outev.sink, &outev.audience,
+                        )
+                    });
+
+                    if let Some(payload) = maybe_reply {
+                        let reply = CallbackReply {
+                            audience: outev.audience,
+                            vars: outev.vars,
+                            payload,
+                        };
+
+                        if reply_tx.unbounded_send(reply).is_err() {
+                            error!("Error sending callback reply to the internal channel");
+                        }
                     }
-                    Err(e) => {
+                }
+                Err(err) => {
+                    logging::with_fields(log_fields, || {
                         error!(
-                            "Network error on sending the message payload = '{}' to the HTTP callback = '{}', {}",
-                            &outev.payload, &outev.uri, e,
-                        );
-                    }
+                            "Error on sending the message to the sink = '{}' of the audience = '{}', {}",
+                            &outev.sink, &outev.audience, err,
+                        )
+                    });
                 }
+            }
 
-                Ok(())
-            })
+            Ok(())
+        })
     }
 }
 
@@ -0,0 +1,606 @@
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::{format_err, Context, Result};
+use futures::future::{self, Loop};
+use futures::{Future, Stream};
+use log::{error, info, warn};
+use ring::signature::{self, RsaPublicKeyComponents, UnparsedPublicKey};
+use serde_derive::Deserialize;
+use serde_json::Value as JsonValue;
+use tokio::prelude::FutureExt;
+use tokio::timer::Delay;
+
+////////////////////////////////////////////////////////////////////////////////
+
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Failed fetches are retried sooner than the regular refresh.
+const RETRY_INTERVAL: Duration = Duration::from_secs(10);
+
+const MAX_JWKS_SIZE: usize = 64 * 1024;
+
+#[derive(Debug, Deserialize)]
+struct JwkSet {
+    keys: Vec<Jwk>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Jwk {
+    kty: String,
+    kid: Option<String>,
+    alg: Option<String>,
+    #[serde(rename = "use")]
+    use_: Option<String>,
+    crv: Option<String>,
+    x: Option<String>,
+    y: Option<String>,
+    n: Option<String>,
+    e: Option<String>,
+}
+
+#[derive(Debug)]
+enum Material {
+    // Uncompressed point as expected by ring.
+    Ec { crv: String, point: Vec<u8> },
+    Rsa { n: Vec<u8>, e: Vec<u8> },
+}
+
+#[derive(Debug)]
+struct Key {
+    alg: Option<String>,
+    material: Material,
+}
+
+impl Key {
+    fn from_jwk(jwk: &Jwk) -> Result<Self> {
+        let material = match jwk.kty.as_str() {
+            "EC" => {
+                let mut point = vec![0x04];
+                point.extend(decode_field(&jwk.x, "x")?);
+                point.extend(decode_field(&jwk.y, "y")?);
+
+                let crv = jwk
+                    .crv
+                    .to_owned()
+                    .ok_or_else(|| format_err!("missing 'crv'"))?;
+
+                Material::Ec { crv, point }
+            }
+            "RSA" => Material::Rsa {
+                n: decode_field(&jwk.n, "n")?,
+                e: decode_field(&jwk.e, "e")?,
+            },
+            kty => return Err(format_err!("unsupported key type = '{}'", kty)),
+        };
+
+        Ok(Self {
+            alg: jwk.alg.to_owned(),
+            material,
+        })
+    }
+
+    fn verify(&self, alg: &str, message: &[u8], sig: &[u8]) -> Result<()> {
+        if let Some(ref key_alg) = self.alg {
+            if key_alg != alg {
+                return Err(format_err!(
+                    "token algorithm = '{}' doesn't match the key's one = '{}'",
+                    alg,
+                    key_alg
+                ));
+            }
+        }
+
+        let result = match (alg, &self.material) {
+            ("ES256", Material::Ec { crv, point }) if crv == "P-256" => {
+                UnparsedPublicKey::new(&signature::ECDSA_P256_SHA256_FIXED, point)
+                    .verify(message, sig)
+            }
+            ("ES384", Material::Ec { crv, point }) if crv == "P-384" => {
+                UnparsedPublicKey::new(&signature::ECDSA_P384_SHA384_FIXED, point)
+                    .verify(message, sig)
+            }
+            ("RS256", Material::Rsa { n, e }) => RsaPublicKeyComponents { n, e }.verify(
+                &signature::RSA_PKCS1_2048_8192_SHA256,
+                message,
+                sig,
+            ),
+            ("RS384", Material::Rsa { n, e }) => RsaPublicKeyComponents { n, e }.verify(
+                &signature::RSA_PKCS1_2048_8192_SHA384,
+                message,
+                sig,
+            ),
+            ("RS512", Material::Rsa { n, e }) => RsaPublicKeyComponents { n, e }.verify(
+                &signature::RSA_PKCS1_2048_8192_SHA512,
+                message,
+                sig,
+            ),
+            _ => {
+                return Err(format_err!(
+                    "token algorithm = '{}' is not supported by the key",
+                    alg
+                ))
+            }
+        };
+
+        result.map_err(|_| format_err!("invalid signature"))
+    }
+}
+
+fn decode_field(value: &Option<String>, name: &str) -> Result<Vec<u8>> {
+    let value = value
+        .as_ref()
+        .ok_or_else(|| format_err!("missing '{}'", name))?;
+
+    decode(value).with_context(|| format!("invalid '{}'", name))
+}
+
+fn decode(value: &str) -> Result<Vec<u8>> {
+    base64::decode_config(value, base64::URL_SAFE_NO_PAD).map_err(|err| format_err!("{}", err))
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Signing keys of an issuer selected by `kid`.
+#[derive(Debug, Default)]
+pub(crate) struct KeySet {
+    keys: HashMap<String, Key>,
+}
+
+impl KeySet {
+    /// Parses a JWK set skipping keys which can't be used to verify signatures.
+    pub(crate) fn parse(data: &[u8]) -> Result<Self> {
+        let set = serde_json::from_slice::<JwkSet>(data).context("Invalid JWK set")?;
+        let mut keys = HashMap::new();
+
+        for jwk in set.keys {
+            if matches!(jwk.use_.as_deref(), Some(use_) if use_ != "sig") {
+                continue;
+            }
+
+            let kid = jwk.kid.to_owned().unwrap_or_default();
+
+            match Key::from_jwk(&jwk) {
+                Ok(key) => {
+                    keys.insert(kid, key);
+                }
+                Err(err) => warn!("Skipping JWK with kid = '{}', {:#}", kid, err),
+            }
+        }
+
+        if keys.is_empty() {
+            return Err(format_err!("No usable keys in JWK set"));
+        }
+
+        Ok(Self { keys })
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    // A token without `kid` may only be verified when there's a single key.
+    fn select(&self, kid: Option<&str>) -> Result<&Key> {
+        match kid {
+            Some(kid) => self
+                .keys
+                .get(kid)
+                .ok_or_else(|| format_err!("unknown key id = '{}'", kid)),
+            None if self.keys.len() == 1 => Ok(self.keys.values().next().expect("no keys")),
+            None => Err(format_err!("missing key id")),
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Debug, Deserialize)]
+struct Header {
+    alg: String,
+    kid: Option<String>,
+}
+
+/// Claims of a JWS compact token decoded without verifying the signature.
+pub(crate) fn decode_claims(token: &str) -> Result<JsonValue> {
+    let payload = token
+        .split('.')
+        .nth(1)
+        .ok_or_else(|| format_err!("malformed token"))?;
+
+    serde_json::from_slice(&decode(payload)?).context("Invalid token claims")
+}
+
+/// Verifies the signature and validity period of a JWS compact token and returns its claims.
+/// Tokens without `exp` are rejected.
+pub(crate) fn verify(token: &str, keys: &KeySet) -> Result<JsonValue> {
+    let mut parts = token.rsplitn(2, '.');
+    let sig = parts.next().map(decode).transpose()?;
+    let message = parts.next();
+
+    let (message, sig) = match (message, sig) {
+        (Some(message), Some(sig)) => (message, sig),
+        _ => return Err(format_err!("malformed token")),
+    };
+
+    let header = message
+        .split('.')
+        .next()
+        .map(decode)
+        .transpose()?
+        .ok_or_else(|| format_err!("malformed token"))?;
+
+    let header = serde_json::from_slice::<Header>(&header).context("Invalid token header")?;
+
+    keys.select(header.kid.as_deref())?
+        .verify(&header.alg, message.as_bytes(), &sig)?;
+
+    let claims = decode_claims(token)?;
+    let now = unix_now();
+
+    let exp = claims
+        .get("exp")
+        .and_then(JsonValue::as_u64)
+        .ok_or_else(|| format_err!("missing 'exp'"))?;
+
+    if exp <= now {
+        return Err(format_err!("token expired"));
+    }
+
+    if let Some(nbf) = claims.get("nbf").and_then(JsonValue::as_u64) {
+        if nbf > now {
+            return Err(format_err!("token is not valid yet"));
+        }
+    }
+
+    Ok(claims)
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default()
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+type HttpClient = reqwest::r#async::Client;
+
+/// Keys fetched from JWKS URLs and refreshed in background.
+///
+/// Keys of the last successful fetch are kept when a refresh fails.
+#[derive(Clone)]
+pub(crate) struct JwksStore {
+    client: HttpClient,
+    entries: Arc<RwLock<HashMap<String, Entry>>>,
+}
+
+struct Entry {
+    keys: Option<Arc<KeySet>>,
+    refresh_interval: Duration,
+    // Distinguishes the refresh task of the entry from one of a removed entry with the same URL.
+    generation: Instant,
+}
+
+impl JwksStore {
+    pub(crate) fn new() -> Self {
+        Self {
+            client: HttpClient::new(),
+            entries: Arc::new(RwLock::new(HashMap::new())),
+        }
+    }
+
+    /// The last successfully fetched keys of the URL.
+    pub(crate) fn keys(&self, url: &str) -> Option<Arc<KeySet>> {
+        self.entries
+            .read()
+            .ok()?
+            .get(url)
+            .and_then(|entry| entry.keys.clone())
+    }
+
+    /// Starts fetching keys from the URL periodically unless it's already done.
+    ///
+    /// Must be called within the Tokio runtime.
+    pub(crate) fn watch(&self, url: &str, refresh_interval: Duration) {
+        let generation = {
+            let mut entries = match self.entries.write() {
+                Ok(entries) => entries,
+                Err(_) => return error!("Error acquiring a lock for JWKS store"),
+            };
+
+            if let Some(entry) = entries.get_mut(url) {
+                entry.refresh_interval = refresh_interval;
+                return;
+            }
+
+            let generation = Instant::now();
+
+            let entry = Entry {
+                keys: None,
+                refresh_interval,
+                generation,
+            };
+
+            entries.insert(url.to_owned(), entry);
+            generation
+        };
+
+        tokio::spawn(self.clone().refresh_loop(url.to_owned(), generation));
+    }
+
+    /// Stops refreshing URLs other than the given ones and forgets their keys.
+    pub(crate) fn retain(&self, urls: &[&str]) {
+        if let Ok(mut entries) = self.entries.write() {
+            entries.retain(|url, _| urls.contains(&url.as_str()));
+        }
+    }
+
+    fn refresh_loop(self, url: String, generation: Instant) -> impl Future<Item = (), Error = ()> {
+        future::loop_fn((), move |()| {
+            let store = self.clone();
+            let url = url.clone();
+
+            self.refresh(&url).then(move |result| {
+                let refresh_interval = match store.refresh_interval(&url, generation) {
+                    Some(refresh_interval) => refresh_interval,
+                    None => return future::Either::A(future::ok(Loop::Break(()))),
+                };
+
+                let delay = match result {
+                    Ok(()) => refresh_interval,
+                    Err(()) => refresh_interval.min(RETRY_INTERVAL),
+                };
+
+                let next = Delay::new(Instant::now() + delay)
+                    .map(|()| Loop::Continue(()))
+                    .map_err(|err| error!("Timer error: {}", err));
+
+                future::Either::B(next)
+            })
+        })
+    }
+
+    // `None` once the entry has been removed.
+    fn refresh_interval(&self, url: &str, generation: Instant) -> Option<Duration> {
+        self.entries
+            .read()
+            .ok()?
+            .get(url)
+            .filter(|entry| entry.generation == generation)
+            .map(|entry| entry.refresh_interval)
+    }
+
+    /// Fetches keys from the URL replacing the previous ones on success.
+    pub(crate) fn refresh(&self, url: &str) -> impl Future<Item = (), Error = ()> {
+        let entries = self.entries.clone();
+        let url = url.to_owned();
+
+        fetch(&self.client, &url).then(move |result| {
+            let keys = match result {
+                Ok(keys) => keys,
+                Err(err) => {
+                    error!("Failed to fetch JWKS from '{}', {:#}", url, err);
+                    return Err(());
+                }
+            };
+
+            info!("Fetched {} keys from JWKS = '{}'", keys.len(), url);
+
+            let mut entries = entries
+                .write()
+                .map_err(|_| error!("Error acquiring a lock for JWKS store"))?;
+
+            if let Some(entry) = entries.get_mut(&url) {
+                entry.keys = Some(Arc::new(keys));
+            }
+
+            Ok(())
+        })
+    }
+}
+
+fn fetch(client: &HttpClient, url: &str) -> impl Future<Item = KeySet, Error = anyhow::Error> {
+    client
+        .get(url)
+        .send()
+        .and_then(|res| res.error_for_status())
+        .map_err(anyhow::Error::from)
+        .and_then(read_body)
+        .timeout(FETCH_TIMEOUT)
+        .map_err(|err| format_err!("{}", err))
+        .and_then(|body| KeySet::parse(&body))
+}
+
+/// Reads the JWKS failing if it's larger than `MAX_JWKS_SIZE`.
+fn read_body(
+    res: reqwest::r#async::Response,
+) -> impl Future<Item = Vec<u8>, Error = anyhow::Error> {
+    let content_length = res
+        .headers()
+        .get(reqwest::header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<usize>().ok());
+
+    if let Some(len) = content_length {
+        if len > MAX_JWKS_SIZE {
+            return future::Either::A(future::err(jwks_too_large()));
+        }
+    }
+
+    // Content-Length may be missing or wrong so the actual size is checked too.
+    let body = res
+        .into_body()
+        .map_err(anyhow::Error::from)
+        .fold(Vec::new(), |mut body, chunk| {
+            if body.len() + chunk.len() > MAX_JWKS_SIZE {
+                return Err(jwks_too_large());
+            }
+
+            body.extend_from_slice(&chunk);
+            Ok(body)
+        });
+
+    future::Either::B(body)
+}
+
+fn jwks_too_large() -> anyhow::Error {
+    format_err!("JWKS is larger than {} bytes", MAX_JWKS_SIZE)
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    use ring::rand::SystemRandom;
+    use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING};
+    use serde_json::json;
+
+    use super::*;
+
+    fn encode(data: &[u8]) -> String {
+        base64::encode_config(data, base64::URL_SAFE_NO_PAD)
+    }
+
+    fn generate_key() -> EcdsaKeyPair {
+        let rng = SystemRandom::new();
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng)
+            .expect("Failed to generate key");
+
+        EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8.as_ref())
+            .expect("Failed to parse key")
+    }
+
+    fn jwk(kid: &str, key: &EcdsaKeyPair) -> JsonValue {
+        let point = key.public_key().as_ref();
+
+        json!({
+            "kty": "EC",
+            "kid": kid,
+            "use": "sig",
+            "alg": "ES256",
+            "crv": "P-256",
+            "x": encode(&point[1..33]),
+            "y": encode(&point[33..65]),
+        })
+    }
+
+    fn sign(kid: &str, key: &EcdsaKeyPair, claims: &JsonValue) -> String {
+        let header = json!({ "alg": "ES256", "typ": "JWT", "kid": kid });
+
+        let message = format!(
+            "{}.{}",
+            encode(header.to_string().as_bytes()),
+            encode(claims.to_string().as_bytes())
+        );
+
+        let sig = key
+            .sign(&SystemRandom::new(), message.as_bytes())
+            .expect("Failed to sign");
+
+        format!("{}.{}", message, encode(sig.as_ref()))
+    }
+
+    // Responds once with the body and closes the listener.
+    fn serve_once(body: String) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("Failed to bind");
+        let url = format!("http://{}/jwks", listener.local_addr().expect("No address"));
+
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().expect("Failed to accept");
+            let mut buf = [0; 4096];
+            let _ = stream.read(&mut buf);
+
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            );
+
+            // The client hangs up early on an oversized response.
+            let _ = stream.write_all(response.as_bytes());
+        });
+
+        url
+    }
+
+    #[test]
+    fn verifies_by_kid() {
+        let (old_key, new_key, other_key) = (generate_key(), generate_key(), generate_key());
+        let jwks = json!({ "keys": [jwk("old", &old_key), jwk("new", &new_key)] });
+        let keys = KeySet::parse(jwks.to_string().as_bytes()).expect("Failed to parse JWKS");
+        let claims = json!({ "iss": "iam.example.org", "sub": "john", "exp": unix_now() + 60 });
+
+        for (kid, key) in &[("old", &old_key), ("new", &new_key)] {
+            let token = sign(kid, key, &claims);
+            assert_eq!(verify(&token, &keys).expect("Failed to verify"), claims);
+        }
+
+        assert!(verify(&sign("old", &new_key, &claims), &keys).is_err());
+        assert!(verify(&sign("other", &other_key, &claims), &keys).is_err());
+
+        let expired = json!({ "sub": "john", "exp": unix_now() - 1 });
+        assert!(verify(&sign("new", &new_key, &expired), &keys).is_err());
+
+        let err = verify(&sign("new", &new_key, &json!({ "sub": "john" })), &keys)
+            .expect_err("Token without 'exp' verified");
+        assert_eq!(err.to_string(), "missing 'exp'");
+    }
+
+    #[test]
+    fn keeps_last_known_good_keys() {
+        let key = generate_key();
+        let jwks = json!({ "keys": [jwk("current", &key)] });
+        let url = serve_once(jwks.to_string());
+
+        let store = JwksStore::new();
+        let entry = Entry {
+            keys: None,
+            refresh_interval: Duration::from_secs(60),
+            generation: Instant::now(),
+        };
+
+        store
+            .entries
+            .write()
+            .expect("Failed to lock")
+            .insert(url.clone(), entry);
+
+        let mut runtime = tokio::runtime::Runtime::new().expect("Failed to create runtime");
+        runtime
+            .block_on(store.refresh(&url))
+            .expect("Failed to fetch");
+        assert_eq!(store.keys(&url).map(|keys| keys.len()), Some(1));
+
+        // The stand-in server is gone so the refresh fails.
+        assert!(runtime.block_on(store.refresh(&url)).is_err());
+
+        let keys = store.keys(&url).expect("Keys have been dropped");
+        let claims = json!({ "sub": "john", "exp": unix_now() + 60 });
+        let token = sign("current", &key, &claims);
+        assert!(verify(&token, &keys).is_ok());
+
+        store.retain(&[]);
+        assert!(store.keys(&url).is_none());
+    }
+
+    #[test]
+    fn limits_jwks_size() {
+        let key = generate_key();
+        let padding = "x".repeat(MAX_JWKS_SIZE);
+        let jwks = json!({ "keys": [jwk("current", &key)], "padding": padding });
+        let url = serve_once(jwks.to_string());
+
+        let mut runtime = tokio::runtime::Runtime::new().expect("Failed to create runtime");
+        let err = runtime
+            .block_on(fetch(&HttpClient::new(), &url))
+            .err()
+            .expect("Oversized JWKS fetched");
+        assert!(err.to_string().contains("larger than"));
+    }
+}
@@ -0,0 +1,395 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::io::Write;
+use std::time::Duration;
+
+use chrono::{SecondsFormat, Utc};
+use log::{warn, Record};
+use ring::digest;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::{Map as JsonMap, Value as JsonValue};
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub(crate) struct Config {
+    #[serde(default)]
+    format: Format,
+    #[serde(default)]
+    payload: PayloadConfig,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Format {
+    #[default]
+    Text,
+    Json,
+}
+
+/// How message payloads get into logs, from the least to the most strict.
+///
+/// Payloads are hashed unless the config opts in to revealing them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum PayloadMode {
+    Full,
+    /// First `max_length` bytes of the payload.
+    Truncated,
+    /// SHA-256 of the payload to correlate identical payloads without revealing them.
+    #[default]
+    Hashed,
+    Omitted,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct PayloadConfig {
+    #[serde(default)]
+    mode: PayloadMode,
+    #[serde(default = "PayloadConfig::default_max_length")]
+    max_length: usize,
+    /// Overrides by audience.
+    #[serde(default)]
+    audiences: HashMap<String, PayloadMode>,
+    /// Overrides by method or event label, take precedence over audiences.
+    #[serde(default)]
+    methods: HashMap<String, PayloadMode>,
+}
+
+impl PayloadConfig {
+    fn default_max_length() -> usize {
+        256
+    }
+
+    /// The strictest override applies to messages of several audiences.
+    fn mode(&self, audience: Option<&str>, method: Option<&str>) -> PayloadMode {
+        method
+            .and_then(|method| self.methods.get(method))
+            .or_else(|| {
+                audience.and_then(|audience| {
+                    audience
+                        .split(',')
+                        .filter_map(|audience| self.audiences.get(audience))
+                        .max()
+                })
+            })
+            .copied()
+            .unwrap_or(self.mode)
+    }
+
+    /// Redacts the payload according to the mode for the audience and method of the fields.
+    fn render(&self, fields: &Fields) -> Option<String> {
+        let payload = fields.payload.as_ref()?;
+
+        match self.mode(fields.audience.as_deref(), fields.method.as_deref()) {
+            PayloadMode::Full => Some(payload.to_owned()),
+            PayloadMode::Truncated if payload.len() <= self.max_length => Some(payload.to_owned()),
+            PayloadMode::Truncated => {
+                let mut end = self.max_length;
+
+                while !payload.is_char_boundary(end) {
+                    end -= 1;
+                }
+
+                Some(format!("{}...", &payload[..end]))
+            }
+            PayloadMode::Hashed => {
+                let hash = digest::digest(&digest::SHA256, payload.as_bytes());
+                let hex = hash
+                    .as_ref()
+                    .iter()
+                    .map(|byte| format!("{:02x}", byte))
+                    .collect::<String>();
+
+                Some(format!("sha256:{}", hex))
+            }
+            PayloadMode::Omitted => None,
+        }
+    }
+}
+
+impl Default for PayloadConfig {
+    fn default() -> Self {
+        Self {
+            mode: PayloadMode::default(),
+            max_length: Self::default_max_length(),
+            audiences: HashMap::new(),
+            methods: HashMap::new(),
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Target of records about actions taken on behalf of other accounts.
+pub(crate) const AUDIT_TARGET: &str = "http_gateway::audit";
+
+/// Structured fields attached to log records made within `with_fields`.
+///
+/// The payload is never written as is but according to the `logging.payload` config.
+#[derive(Debug, Default, Serialize)]
+pub(crate) struct Fields {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    account: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    agent: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    impersonated_by: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    topic: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    correlation_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    audience: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    method: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    latency_ms: Option<u64>,
+    #[serde(skip)]
+    payload: Option<String>,
+}
+
+impl Fields {
+    pub(crate) fn account(mut self, account: &impl Display) -> Self {
+        self.account = Some(account.to_string());
+        self
+    }
+
+    pub(crate) fn agent(mut self, agent: &impl Display) -> Self {
+        self.agent = Some(agent.to_string());
+        self
+    }
+
+    /// The caller acting on behalf of `account`.
+    pub(crate) fn impersonated_by(mut self, account: &impl Display) -> Self {
+        self.impersonated_by = Some(account.to_string());
+        self
+    }
+
+    pub(crate) fn topic(mut self, topic: &str) -> Self {
+        self.topic = Some(topic.to_owned());
+        self
+    }
+
+    pub(crate) fn correlation_id(mut self, correlation_id: &str) -> Self {
+        self.correlation_id = Some(correlation_id.to_owned());
+        self
+    }
+
+    /// Audiences of a message routed to several tenants are comma-separated.
+    pub(crate) fn audience(mut self, audience: &str) -> Self {
+        self.audience = Some(audience.to_owned());
+        self
+    }
+
+    pub(crate) fn method(mut self, method: &str) -> Self {
+        self.method = Some(method.to_owned());
+        self
+    }
+
+    pub(crate) fn status(mut self, status: u16) -> Self {
+        self.status = Some(status);
+        self
+    }
+
+    pub(crate) fn latency(mut self, latency: Duration) -> Self {
+        self.latency_ms = Some(latency.as_millis() as u64);
+        self
+    }
+
+    pub(crate) fn payload(mut self, payload: &impl Display) -> Self {
+        self.payload = Some(payload.to_string());
+        self
+    }
+}
+
+thread_local! {
+    static FIELDS: RefCell<Option<Fields>> = const { RefCell::new(None) };
+}
+
+/// Attaches the fields to log records made by `f` on the current thread.
+pub(crate) fn with_fields<R>(fields: Fields, f: impl FnOnce() -> R) -> R {
+    let prev = FIELDS.with(|cell| cell.replace(Some(fields)));
+    let result = f();
+    FIELDS.with(|cell| cell.replace(prev));
+    result
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Installs the global logger filtered by `RUST_LOG`.
+///
+/// A logger installed before is kept, e.g. when the gateway is started several times in tests.
+pub(crate) fn init(config: &Config) {
+    let config = config.to_owned();
+
+    env_logger::Builder::from_default_env()
+        .format(move |buf, record| {
+            FIELDS.with(|cell| {
+                let fields = cell.borrow();
+                let fields = fields.as_ref();
+
+                match config.format {
+                    Format::Text => {
+                        let line = to_text(record, fields, &config.payload);
+                        writeln!(buf, "[{} {}] {}", buf.timestamp(), record.level(), line)
+                    }
+                    Format::Json => {
+                        let line = to_json(record, fields, &config.payload);
+                        writeln!(buf, "{}", line)
+                    }
+                }
+            })
+        })
+        .try_init()
+        .unwrap_or_else(|err| warn!("Keeping the logger installed before, {}", err));
+}
+
+fn to_text(record: &Record, fields: Option<&Fields>, payload: &PayloadConfig) -> String {
+    let mut line = format!("{}: {}", record.target(), record.args());
+
+    if let Some(fields) = fields {
+        if let Ok(JsonValue::Object(object)) = serde_json::to_value(fields) {
+            for (key, value) in object {
+                match value {
+                    JsonValue::String(value) => line.push_str(&format!(" {}='{}'", key, value)),
+                    value => line.push_str(&format!(" {}={}", key, value)),
+                }
+            }
+        }
+
+        if let Some(payload) = payload.render(fields) {
+            line.push_str(&format!(" payload='{}'", payload));
+        }
+    }
+
+    line
+}
+
+fn to_json(record: &Record, fields: Option<&Fields>, payload: &PayloadConfig) -> JsonValue {
+    let mut object = JsonMap::new();
+
+    object.insert(
+        "timestamp".to_owned(),
+        Utc::now()
+            .to_rfc3339_opts(SecondsFormat::Millis, true)
+            .into(),
+    );
+
+    object.insert("level".to_owned(), record.level().to_string().into());
+    object.insert("target".to_owned(), record.target().into());
+    object.insert("message".to_owned(), record.args().to_string().into());
+
+    if let Some(fields) = fields {
+        if let Ok(JsonValue::Object(fields_object)) = serde_json::to_value(fields) {
+            object.extend(fields_object);
+        }
+
+        if let Some(payload) = payload.render(fields) {
+            object.insert("payload".to_owned(), payload.into());
+        }
+    }
+
+    JsonValue::Object(object)
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use log::Level;
+    use serde_json::json;
+
+    fn payload_config() -> PayloadConfig {
+        serde_json::from_value::<PayloadConfig>(json!({
+            "mode": "truncated",
+            "max_length": 8,
+            "audiences": {"example.org": "hashed"},
+            "methods": {"room.create": "omitted", "room.update": "full"},
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn redacts_payload() {
+        let config = payload_config();
+        let payload = r#"{"name":"secret"}"#;
+
+        let fields = Fields::default().payload(&payload);
+        assert_eq!(config.render(&fields).unwrap(), r#"{"name":..."#);
+
+        let fields = Fields::default().audience("example.org").payload(&payload);
+        assert!(config.render(&fields).unwrap().starts_with("sha256:"));
+
+        let fields = Fields::default()
+            .audience("example.org")
+            .method("room.update")
+            .payload(&payload);
+
+        assert_eq!(config.render(&fields).unwrap(), payload);
+
+        let fields = Fields::default().method("room.create").payload(&payload);
+        assert_eq!(config.render(&fields), None);
+
+        let fields = Fields::default()
+            .audience("example.net,example.org")
+            .payload(&payload);
+
+        assert!(config.render(&fields).unwrap().starts_with("sha256:"));
+    }
+
+    #[test]
+    fn hashes_payload_by_default() {
+        let config = serde_json::from_value::<PayloadConfig>(json!({
+            "methods": {"room.update": "full"},
+        }))
+        .unwrap();
+
+        let payload = r#"{"name":"secret"}"#;
+
+        let fields = Fields::default().audience("example.org").payload(&payload);
+        assert!(config.render(&fields).unwrap().starts_with("sha256:"));
+
+        let fields = Fields::default().method("room.update").payload(&payload);
+        assert_eq!(config.render(&fields).unwrap(), payload);
+
+        let fields = Fields::default().payload(&payload);
+        let default_config = PayloadConfig::default();
+        assert!(default_config
+            .render(&fields)
+            .unwrap()
+            .starts_with("sha256:"));
+    }
+
+    #[test]
+    fn formats_json() {
+        let fields = Fields::default()
+            .topic("apps/conference.svc.example.org/api/v1/rooms")
+            .latency(Duration::from_millis(15))
+            .method("room.create")
+            .payload(&"{}");
+
+        let line = to_json(
+            &Record::builder()
+                .args(format_args!("Incoming message"))
+                .level(Level::Info)
+                .target("http_gateway")
+                .build(),
+            Some(&fields),
+            &payload_config(),
+        );
+        assert_eq!(line["message"], json!("Incoming message"));
+        assert_eq!(line["level"], json!("INFO"));
+        assert_eq!(line["latency_ms"], json!(15));
+        assert_eq!(
+            line["topic"],
+            json!("apps/conference.svc.example.org/api/v1/rooms")
+        );
+        assert!(line.get("payload").is_none());
+        assert!(line.get("account").is_none());
+    }
+}
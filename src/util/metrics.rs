@@ -0,0 +1,290 @@
+use std::collections::HashSet;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge,
+    IntGaugeVec, Opts, Registry, TextEncoder,
+};
+
+use serde_derive::Deserialize;
+
+use crate::util::health::SubscriptionStatus;
+
+////////////////////////////////////////////////////////////////////////////////
+
+const NAMESPACE: &str = "http_gateway";
+/// Label value of destinations and methods not listed in the config.
+const OTHER_LABEL: &str = "other";
+
+/// Request metrics are labeled with the listed destinations and methods only
+/// since they come from callers and would otherwise make the number of series unbounded.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub(crate) struct Config {
+    #[serde(default)]
+    destinations: HashSet<String>,
+    #[serde(default)]
+    methods: HashSet<String>,
+}
+
+impl Config {
+    fn destination_label<'a>(&self, destination: &'a str) -> &'a str {
+        if self.destinations.contains(destination) {
+            destination
+        } else {
+            OTHER_LABEL
+        }
+    }
+
+    fn method_label<'a>(&self, method: &'a str) -> &'a str {
+        if self.methods.contains(method) {
+            method
+        } else {
+            OTHER_LABEL
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Prometheus metrics of the gateway.
+pub(crate) struct Metrics {
+    config: Config,
+    registry: Registry,
+    http_request_duration: HistogramVec,
+    mqtt_round_trip_duration: Histogram,
+    adapter_in_flight: IntGauge,
+    callback_deliveries: IntCounterVec,
+    callback_delivery_duration: HistogramVec,
+    outgoing_queue_depth: IntGauge,
+    broker_connected: IntGauge,
+    subscription_confirmed: IntGaugeVec,
+    resubscription_attempts: IntCounter,
+}
+
+impl Metrics {
+    pub(crate) fn new(config: &Config) -> Result<Self> {
+        let registry = Registry::new();
+
+        let http_request_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "http_request_duration_seconds",
+                "Duration of requests to /api/v1/request",
+            )
+            .namespace(NAMESPACE),
+            &["destination", "method", "status"],
+        )?;
+
+        let mqtt_round_trip_duration = Histogram::with_opts(
+            HistogramOpts::new(
+                "mqtt_round_trip_duration_seconds",
+                "Time between publishing an MQTT request and receiving its response",
+            )
+            .namespace(NAMESPACE),
+        )?;
+
+        let adapter_in_flight = IntGauge::with_opts(
+            Opts::new(
+                "adapter_in_flight",
+                "MQTT requests waiting for their responses",
+            )
+            .namespace(NAMESPACE),
+        )?;
+
+        let callback_deliveries = IntCounterVec::new(
+            Opts::new("callback_deliveries_total", "Delivered events by outcome")
+                .namespace(NAMESPACE),
+            &["audience", "outcome"],
+        )?;
+
+        let callback_delivery_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "callback_delivery_duration_seconds",
+                "Duration of event deliveries to sinks",
+            )
+            .namespace(NAMESPACE),
+            &["audience"],
+        )?;
+
+        let outgoing_queue_depth = IntGauge::with_opts(
+            Opts::new(
+                "outgoing_queue_depth",
+                "Events waiting to be delivered to sinks",
+            )
+            .namespace(NAMESPACE),
+        )?;
+
+        let broker_connected = IntGauge::with_opts(
+            Opts::new("broker_connected", "1 if connected to the MQTT broker").namespace(NAMESPACE),
+        )?;
+
+        let subscription_confirmed = IntGaugeVec::new(
+            Opts::new(
+                "subscription_confirmed",
+                "1 if the broker has confirmed the subscription",
+            )
+            .namespace(NAMESPACE),
+            &["topic"],
+        )?;
+
+        let resubscription_attempts = IntCounter::with_opts(
+            Opts::new(
+                "resubscription_attempts_total",
+                "Attempts to resubscribe after reconnection",
+            )
+            .namespace(NAMESPACE),
+        )?;
+
+        registry.register(Box::new(http_request_duration.clone()))?;
+        registry.register(Box::new(mqtt_round_trip_duration.clone()))?;
+        registry.register(Box::new(adapter_in_flight.clone()))?;
+        registry.register(Box::new(callback_deliveries.clone()))?;
+        registry.register(Box::new(callback_delivery_duration.clone()))?;
+        registry.register(Box::new(outgoing_queue_depth.clone()))?;
+        registry.register(Box::new(broker_connected.clone()))?;
+        registry.register(Box::new(subscription_confirmed.clone()))?;
+        registry.register(Box::new(resubscription_attempts.clone()))?;
+
+        Ok(Self {
+            config: config.to_owned(),
+            registry,
+            http_request_duration,
+            mqtt_round_trip_duration,
+            adapter_in_flight,
+            callback_deliveries,
+            callback_delivery_duration,
+            outgoing_queue_depth,
+            broker_connected,
+            subscription_confirmed,
+            resubscription_attempts,
+        })
+    }
+
+    /// Renders all metrics in Prometheus text format.
+    pub(crate) fn encode(&self) -> Result<String> {
+        let mut buffer = Vec::new();
+
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .context("Failed to encode metrics")?;
+
+        String::from_utf8(buffer).context("Metrics are not valid UTF-8")
+    }
+
+    pub(crate) fn observe_http_request(
+        &self,
+        destination: &str,
+        method: &str,
+        status: u16,
+        duration: Duration,
+    ) {
+        let destination = self.config.destination_label(destination);
+        let method = self.config.method_label(method);
+
+        self.http_request_duration
+            .with_label_values(&[destination, method, &status.to_string()])
+            .observe(duration.as_secs_f64());
+    }
+
+    pub(crate) fn observe_mqtt_round_trip(&self, duration: Duration) {
+        self.mqtt_round_trip_duration
+            .observe(duration.as_secs_f64());
+    }
+
+    pub(crate) fn set_adapter_in_flight(&self, value: usize) {
+        self.adapter_in_flight.set(value as i64);
+    }
+
+    pub(crate) fn observe_callback_delivery(
+        &self,
+        audience: &str,
+        success: bool,
+        duration: Duration,
+    ) {
+        let outcome = if success { "success" } else { "failure" };
+
+        self.callback_deliveries
+            .with_label_values(&[audience, outcome])
+            .inc();
+
+        self.callback_delivery_duration
+            .with_label_values(&[audience])
+            .observe(duration.as_secs_f64());
+    }
+
+    pub(crate) fn inc_outgoing_queue_depth(&self) {
+        self.outgoing_queue_depth.inc();
+    }
+
+    pub(crate) fn dec_outgoing_queue_depth(&self) {
+        self.outgoing_queue_depth.dec();
+    }
+
+    pub(crate) fn outgoing_queue_depth(&self) -> i64 {
+        self.outgoing_queue_depth.get()
+    }
+
+    pub(crate) fn set_broker_connected(&self, connected: bool) {
+        self.broker_connected.set(connected as i64);
+    }
+
+    /// Replaces per topic subscription states, stale topics are dropped.
+    pub(crate) fn set_subscriptions(&self, subscriptions: &[(String, SubscriptionStatus)]) {
+        self.subscription_confirmed.reset();
+
+        for (topic, status) in subscriptions {
+            let confirmed = *status == SubscriptionStatus::Confirmed;
+
+            self.subscription_confirmed
+                .with_label_values(&[topic])
+                .set(confirmed as i64);
+        }
+    }
+
+    pub(crate) fn inc_resubscription_attempts(&self) {
+        self.resubscription_attempts.inc();
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test {
+    use super::{Config, Metrics};
+    use serde_json::json;
+    use std::time::Duration;
+
+    #[test]
+    fn encodes_metrics() {
+        let config = serde_json::from_value::<Config>(json!({
+            "destinations": ["conference.svc.example.org"],
+            "methods": ["room.create"],
+        }))
+        .expect("Failed to parse config");
+
+        let metrics = Metrics::new(&config).expect("Failed to create metrics");
+        metrics.observe_http_request(
+            "conference.svc.example.org",
+            "room.create",
+            200,
+            Duration::from_millis(10),
+        );
+        metrics.observe_http_request(
+            "random-1.svc.example.org",
+            "random.method",
+            404,
+            Duration::from_millis(10),
+        );
+        metrics.set_broker_connected(true);
+
+        let text = metrics.encode().expect("Failed to encode metrics");
+        assert!(text.contains("http_gateway_broker_connected 1"));
+        assert!(text.contains(
+            r#"http_gateway_http_request_duration_seconds_count{destination="conference.svc.example.org",method="room.create",status="200"} 1"#
+        ));
+        assert!(text.contains(
+            r#"http_gateway_http_request_duration_seconds_count{destination="other",method="other",status="404"} 1"#
+        ));
+        assert!(!text.contains("random"));
+    }
+}
@@ -1,3 +1,15 @@
+pub(crate) mod agent;
+pub(crate) mod envelope;
 pub(crate) mod headers;
+pub(crate) mod health;
 pub(crate) mod http_stream;
+pub(crate) mod jwks;
+pub(crate) mod logging;
+pub(crate) mod metrics;
 pub(crate) mod mqtt_request;
+pub(crate) mod poll;
+pub(crate) mod sink;
+pub(crate) mod tls;
+pub(crate) mod topic;
+pub(crate) mod trace;
+pub(crate) mod unix_socket;
@@ -1,10 +1,17 @@
 use anyhow::{format_err, Result};
 use futures::sync::oneshot;
+use serde_derive::Serialize;
 use serde_json::Value as JsonValue;
 use std::collections::HashMap;
-use svc_agent::mqtt::{Agent, OutgoingMessage};
+use std::sync::Arc;
+use std::time::Instant;
 use svc_agent::AgentId;
 
+use crate::util::agent::AgentHandle;
+use crate::util::envelope::ExtendedRequest;
+use crate::util::metrics::Metrics;
+use crate::util::trace::Span;
+
 ////////////////////////////////////////////////////////////////////////////////
 
 pub(crate) type IncomingResponse = svc_agent::mqtt::IncomingResponse<JsonValue>;
@@ -12,17 +19,40 @@ pub(crate) type IncomingResponse = svc_agent::mqtt::IncomingResponse<JsonValue>;
 ////////////////////////////////////////////////////////////////////////////////
 
 pub(crate) struct Adapter {
-    tx: Agent,
-    store: HashMap<String, oneshot::Sender<IncomingResponse>>,
+    tx: AgentHandle,
+    pending: PendingRequests,
+}
+
+/// Requests waiting for responses by correlation data.
+struct PendingRequests {
+    store: HashMap<String, PendingRequest>,
+    metrics: Arc<Metrics>,
+}
+
+struct PendingRequest {
+    tx: oneshot::Sender<IncomingResponse>,
+    destination: String,
+    method: String,
+    published_at: Instant,
+    span: Span,
+}
+
+/// A request waiting for its response as shown by the admin API.
+#[derive(Debug, Serialize)]
+pub(crate) struct PendingRequestInfo {
+    correlation_id: String,
+    destination: String,
+    method: String,
+    age_ms: u64,
 }
 
 ////////////////////////////////////////////////////////////////////////////////
 
 impl Adapter {
-    pub(crate) fn new(tx: Agent) -> Self {
+    pub(crate) fn new(tx: AgentHandle, metrics: Arc<Metrics>) -> Self {
         Self {
             tx,
-            store: HashMap::new(),
+            pending: PendingRequests::new(metrics),
         }
     }
 
@@ -30,27 +60,56 @@ impl Adapter {
         self.tx.id()
     }
 
-    pub(crate) fn request<T: serde::Serialize>(
+    /// Publishes the request. The span ends when the response is committed.
+    pub(crate) fn request<T: serde::Serialize + 'static>(
         &mut self,
-        req: OutgoingMessage<T>,
+        req: ExtendedRequest<T>,
+        destination: &str,
+        method: &str,
+        span: Span,
     ) -> Result<oneshot::Receiver<IncomingResponse>> {
-        let id = match req {
-            OutgoingMessage::Request(ref req) => req.properties().correlation_data().to_owned(),
-            _ => return Err(format_err!("Wrong message type")),
-        };
-        self.tx.publish(req)?;
+        let id = req.correlation_data().to_owned();
 
-        let (tx, rx) = oneshot::channel();
-        self.store.insert(id, tx);
+        if let Err(err) = self.tx.get().publish_publishable(Box::new(req)) {
+            span.end(false);
+            return Err(err.into());
+        }
+
+        Ok(self.pending.insert(id, destination, method, span))
+    }
+
+    /// Number of requests waiting for responses. Ones abandoned by timeout are forgotten.
+    pub(crate) fn in_flight(&mut self) -> usize {
+        self.pending.in_flight()
+    }
+
+    /// Forgets the request the caller stopped waiting for, e.g. on timeout.
+    /// Its span ends as failed so that it's still exported.
+    pub(crate) fn abandon(&mut self, id: &str) {
+        self.pending.abandon(id)
+    }
+
+    /// Requests waiting for responses, the oldest first.
+    pub(crate) fn pending(&self) -> Vec<PendingRequestInfo> {
+        self.pending.list()
+    }
 
-        Ok(rx)
+    /// Fails all requests waiting for responses, e.g. when the connection to the broker is lost.
+    /// Dropping the senders cancels the receivers so the callers don't wait till the timeout.
+    pub(crate) fn fail_all(&mut self, reason: &str) -> usize {
+        self.pending.fail_all(reason)
     }
 
     pub(crate) fn commit_response(&mut self, resp: IncomingResponse) -> Result<()> {
         let id = resp.properties().correlation_data();
 
-        if let Some(tx) = self.store.remove(id) {
-            return tx.send(resp).map_err(|_| {
+        if let Some(pending) = self.pending.take(id) {
+            let status = resp.properties().status();
+            let mut span = pending.span;
+            span.set_attribute("status", status.as_str());
+            span.end(status.is_success());
+
+            return pending.tx.send(resp).map_err(|_| {
                 format_err!("error committing incoming MQTT response, a receiver may have been already destroyed by timeout")
             });
         }
@@ -59,4 +118,161 @@ impl Adapter {
     }
 }
 
+impl PendingRequests {
+    fn new(metrics: Arc<Metrics>) -> Self {
+        Self {
+            store: HashMap::new(),
+            metrics,
+        }
+    }
+
+    fn insert(
+        &mut self,
+        id: String,
+        destination: &str,
+        method: &str,
+        span: Span,
+    ) -> oneshot::Receiver<IncomingResponse> {
+        let (tx, rx) = oneshot::channel();
+
+        let pending = PendingRequest {
+            tx,
+            destination: destination.to_owned(),
+            method: method.to_owned(),
+            published_at: Instant::now(),
+            span,
+        };
+
+        self.store.insert(id, pending);
+        self.metrics.set_adapter_in_flight(self.store.len());
+        rx
+    }
+
+    fn take(&mut self, id: &str) -> Option<PendingRequest> {
+        let pending = self.store.remove(id)?;
+
+        self.metrics
+            .observe_mqtt_round_trip(pending.published_at.elapsed());
+        self.metrics.set_adapter_in_flight(self.store.len());
+        Some(pending)
+    }
+
+    fn in_flight(&mut self) -> usize {
+        let canceled = self
+            .store
+            .iter()
+            .filter(|(_, pending)| pending.tx.is_canceled())
+            .map(|(id, _)| id.to_owned())
+            .collect::<Vec<_>>();
+
+        for id in canceled {
+            self.abandon(&id);
+        }
+
+        self.store.len()
+    }
+
+    fn abandon(&mut self, id: &str) {
+        if let Some(pending) = self.store.remove(id) {
+            let mut span = pending.span;
+            span.set_attribute("error", "timeout");
+            span.end(false);
+        }
+
+        self.metrics.set_adapter_in_flight(self.store.len());
+    }
+
+    fn list(&self) -> Vec<PendingRequestInfo> {
+        let mut pending = self
+            .store
+            .iter()
+            .filter(|(_, pending)| !pending.tx.is_canceled())
+            .map(|(id, pending)| PendingRequestInfo {
+                correlation_id: id.to_owned(),
+                destination: pending.destination.to_owned(),
+                method: pending.method.to_owned(),
+                age_ms: pending.published_at.elapsed().as_millis() as u64,
+            })
+            .collect::<Vec<_>>();
+
+        pending.sort_by(|a, b| b.age_ms.cmp(&a.age_ms));
+        pending
+    }
+
+    fn fail_all(&mut self, reason: &str) -> usize {
+        let failed = self.store.len();
+
+        for (_, pending) in self.store.drain() {
+            let mut span = pending.span;
+            span.set_attribute("error", reason);
+            span.end(false);
+        }
+
+        self.metrics.set_adapter_in_flight(0);
+        failed
+    }
+}
+
 ////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use futures::sync::oneshot;
+    use futures::Future;
+
+    use super::{IncomingResponse, PendingRequests};
+    use crate::util::metrics::{Config as MetricsConfig, Metrics};
+    use crate::util::trace::{SpanKind, Tracer};
+
+    fn pending_requests() -> (PendingRequests, Arc<Metrics>) {
+        let metrics = Metrics::new(&MetricsConfig::default()).expect("Failed to create metrics");
+        let metrics = Arc::new(metrics);
+        (PendingRequests::new(metrics.clone()), metrics)
+    }
+
+    fn insert(pending: &mut PendingRequests, id: &str) -> oneshot::Receiver<IncomingResponse> {
+        let span = Tracer::default().start("request", SpanKind::Client, None);
+        pending.insert(id.to_owned(), "app.svc.example.org", "room.read", span)
+    }
+
+    #[test]
+    fn fails_all_requests_on_disconnection() {
+        let (mut pending, metrics) = pending_requests();
+        let first = insert(&mut pending, "1");
+        let second = insert(&mut pending, "2");
+
+        let text = metrics.encode().expect("Failed to encode metrics");
+        assert!(text.contains("http_gateway_adapter_in_flight 2"));
+
+        assert_eq!(pending.fail_all("broker_unavailable"), 2);
+        assert_eq!(pending.in_flight(), 0);
+        assert!(pending.list().is_empty());
+
+        // Callers are released right away instead of waiting for the timeout.
+        assert!(first.wait().is_err());
+        assert!(second.wait().is_err());
+
+        let text = metrics.encode().expect("Failed to encode metrics");
+        assert!(text.contains("http_gateway_adapter_in_flight 0"));
+        assert_eq!(pending.fail_all("broker_unavailable"), 0);
+    }
+
+    #[test]
+    fn forgets_abandoned_requests() {
+        let (mut pending, _metrics) = pending_requests();
+        let abandoned = insert(&mut pending, "1");
+        let _waiting = insert(&mut pending, "2");
+        let _timed_out = insert(&mut pending, "3");
+
+        pending.abandon("3");
+        assert_eq!(pending.list().len(), 2);
+
+        drop(abandoned);
+        assert_eq!(pending.in_flight(), 1);
+        assert_eq!(pending.list()[0].correlation_id, "2");
+        assert!(pending.take("1").is_none());
+        assert!(pending.take("2").is_some());
+    }
+}
@@ -0,0 +1,38 @@
+use std::time::{Duration, Instant};
+
+use futures::future::Shared;
+use futures::sync::oneshot;
+use futures::{Future, Stream};
+use log::error;
+use tokio::timer::Interval;
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Resolves once the gateway is stopping.
+pub(crate) type Stop = Shared<oneshot::Receiver<()>>;
+
+/// Runs the future until it completes or `stop` resolves.
+///
+/// The future is polled first so the work ready by then, e.g. queued messages, is done.
+pub(crate) fn until<F>(future: F, stop: Stop) -> impl Future<Item = (), Error = ()>
+where
+    F: Future<Item = (), Error = ()>,
+{
+    future
+        .select(stop.map(|_| ()).map_err(|_| ()))
+        .map(|_| ())
+        .map_err(|_| ())
+}
+
+/// Resolves as soon as the predicate holds, checking it every `interval`.
+pub(crate) fn wait_until<F>(interval: Duration, predicate: F) -> impl Future<Item = (), Error = ()>
+where
+    F: Fn() -> bool,
+{
+    Interval::new(Instant::now(), interval)
+        .map_err(|err| error!("Timer error: {}", err))
+        .skip_while(move |_| Ok(!predicate()))
+        .into_future()
+        .map(|_| ())
+        .map_err(|_| ())
+}
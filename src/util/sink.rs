@@ -0,0 +1,524 @@
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Mutex,
+};
+use std::time::Duration;
+
+use anyhow::{bail, format_err, Context, Error, Result};
+use futures::{future, Future, Stream};
+use serde_derive::{Deserialize, Serialize};
+use serde_json::{json, Value as JsonValue};
+use tokio::net::UnixStream;
+use tokio::prelude::FutureExt;
+
+use crate::util::http_stream::OutgoingMessage;
+use crate::util::trace::TRACEPARENT_HEADER;
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Where events of an audience are delivered to.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub(crate) enum Config {
+    /// POST to the audience's callback URL.
+    #[default]
+    Http,
+    /// Append newline-delimited JSON to a file.
+    File { path: PathBuf },
+    /// Write newline-delimited JSON to a Unix domain socket, one connection per event.
+    Unix { path: PathBuf },
+    /// Print newline-delimited JSON to stdout.
+    Stdout,
+}
+
+impl fmt::Display for Config {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Config::Http => write!(fmt, "http"),
+            Config::File { path } => write!(fmt, "file:{}", path.display()),
+            Config::Unix { path } => write!(fmt, "unix:{}", path.display()),
+            Config::Stdout => write!(fmt, "stdout"),
+        }
+    }
+}
+
+impl Config {
+    /// Path of `file` and `unix` sinks.
+    pub(crate) fn path(&self) -> Option<&Path> {
+        match self {
+            Config::File { path } | Config::Unix { path } => Some(path),
+            Config::Http | Config::Stdout => None,
+        }
+    }
+
+    /// Fails unless the sink's path is inside `base_dir`.
+    ///
+    /// Symlinks are resolved so that a link inside the directory can't point outside of it.
+    pub(crate) fn check_base_dir(&self, base_dir: &Path) -> Result<()> {
+        let path = match self.path() {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+
+        let base_dir = base_dir
+            .canonicalize()
+            .with_context(|| format!("Failed to resolve sink directory {}", base_dir.display()))?;
+
+        let resolved = if path.exists() {
+            path.canonicalize()
+        } else {
+            let file_name = match path.file_name() {
+                Some(file_name) => file_name,
+                None => bail!("sink path = '{}' has no file name", path.display()),
+            };
+
+            let parent = match path.parent() {
+                Some(parent) if !parent.as_os_str().is_empty() => parent,
+                _ => Path::new("."),
+            };
+
+            parent.canonicalize().map(|parent| parent.join(file_name))
+        }
+        .with_context(|| format!("Failed to resolve sink path {}", path.display()))?;
+
+        if !resolved.starts_with(&base_dir) {
+            bail!(
+                "sink path = '{}' is outside of the sink directory = '{}'",
+                path.display(),
+                base_dir.display()
+            );
+        }
+
+        Ok(())
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Resolves to the reply to the message if the sink supports replies and the message expects one.
+pub(crate) type SinkFuture = Box<dyn Future<Item = Option<JsonValue>, Error = Error> + Send>;
+
+pub(crate) trait Sink: Send + Sync {
+    fn send(&self, message: &OutgoingMessage) -> SinkFuture;
+}
+
+/// Builds a sink for the config. HTTP sinks share the client.
+pub(crate) fn build(config: &Config, http: &Arc<HttpSink>) -> Result<Arc<dyn Sink>> {
+    let sink: Arc<dyn Sink> = match config {
+        Config::Http => http.clone(),
+        Config::File { path } => Arc::new(FileSink::open(path)?),
+        Config::Unix { path } => Arc::new(UnixSink::new(path)),
+        Config::Stdout => Arc::new(StdoutSink),
+    };
+
+    Ok(sink)
+}
+
+/// Serializes the message as a single line of JSON.
+fn to_line(message: &OutgoingMessage) -> Result<Vec<u8>> {
+    let record = json!({
+        "audience": message.audience(),
+        "properties": message.headers().to_json(),
+        "payload": message.payload(),
+    });
+
+    let mut line = serde_json::to_vec(&record).context("Failed to serialize message")?;
+    line.push(b'\n');
+    Ok(line)
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+type HttpClient = reqwest::r#async::Client;
+
+/// Replies are published to MQTT so they're limited to a size a broker accepts.
+const MAX_REPLY_SIZE: usize = 64 * 1024;
+
+pub(crate) struct HttpSink {
+    client: HttpClient,
+    /// Milliseconds.
+    timeout: Arc<AtomicU64>,
+}
+
+impl HttpSink {
+    pub(crate) fn new(timeout: Arc<AtomicU64>) -> Self {
+        let client = HttpClient::builder()
+            .build()
+            .expect("Error creating HTTP client");
+
+        Self { client, timeout }
+    }
+}
+
+impl Sink for HttpSink {
+    fn send(&self, message: &OutgoingMessage) -> SinkFuture {
+        let uri = match message.callback() {
+            Some(uri) => uri.to_owned(),
+            None => return Box::new(future::err(format_err!("missing HTTP callback"))),
+        };
+
+        let timeout = Duration::from_millis(self.timeout.load(Ordering::Relaxed));
+        let expects_reply = message.expects_reply();
+
+        let mut request = self
+            .client
+            .post(&uri)
+            .bearer_auth(message.token().to_owned())
+            .headers(message.headers().to_header_map());
+
+        if let Some(trace) = message.trace() {
+            request = request.header(TRACEPARENT_HEADER, trace.to_traceparent());
+        }
+
+        let future = request
+            .json(message.payload())
+            .send()
+            .map_err(Error::from)
+            .and_then(move |res| {
+                let status = res.status();
+
+                let body = if expects_reply && status.is_success() {
+                    future::Either::A(read_reply(res).map(Some))
+                } else {
+                    future::Either::B(future::ok(None))
+                };
+
+                body.map(move |body| (status, body))
+            })
+            .timeout(timeout)
+            .then(move |resp| match resp {
+                Ok((status, _)) if !status.is_success() => Err(format_err!(
+                    "error with status code = '{}' on sending to the HTTP callback = '{}'",
+                    status,
+                    uri,
+                )),
+                Ok((_, Some(ref body))) if !body.is_empty() => {
+                    serde_json::from_slice(body).map(Some).map_err(|err| {
+                        format_err!("invalid reply from the HTTP callback = '{}', {}", uri, err)
+                    })
+                }
+                Ok(_) => Ok(None),
+                Err(err) => Err(format_err!(
+                    "error on sending to the HTTP callback = '{}', {}",
+                    uri,
+                    err,
+                )),
+            });
+
+        Box::new(future)
+    }
+}
+
+/// Reads the reply body failing if it's larger than `MAX_REPLY_SIZE`.
+fn read_reply(res: reqwest::r#async::Response) -> impl Future<Item = Vec<u8>, Error = Error> {
+    let content_length = res
+        .headers()
+        .get(reqwest::header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<usize>().ok());
+
+    if let Some(len) = content_length {
+        if len > MAX_REPLY_SIZE {
+            return future::Either::A(future::err(reply_too_large()));
+        }
+    }
+
+    // Content-Length may be missing or wrong so the actual size is checked too.
+    let body = res
+        .into_body()
+        .map_err(Error::from)
+        .fold(Vec::new(), |mut body, chunk| {
+            if body.len() + chunk.len() > MAX_REPLY_SIZE {
+                return Err(reply_too_large());
+            }
+
+            body.extend_from_slice(&chunk);
+            Ok(body)
+        });
+
+    future::Either::B(body)
+}
+
+fn reply_too_large() -> Error {
+    format_err!("reply is larger than {} bytes", MAX_REPLY_SIZE)
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+pub(crate) struct FileSink {
+    file: Mutex<File>,
+}
+
+impl FileSink {
+    fn open(path: &Path) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Failed to open sink file {}", path.display()))?;
+
+        Ok(Self {
+            file: Mutex::new(file),
+        })
+    }
+}
+
+impl Sink for FileSink {
+    fn send(&self, message: &OutgoingMessage) -> SinkFuture {
+        let result = to_line(message).and_then(|line| {
+            let mut file = self
+                .file
+                .lock()
+                .map_err(|_| format_err!("error acquiring a lock for sink file"))?;
+
+            file.write_all(&line)
+                .context("Failed to write to sink file")
+        });
+
+        Box::new(future::result(result.map(|()| None)))
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+pub(crate) struct UnixSink {
+    path: PathBuf,
+}
+
+impl UnixSink {
+    fn new(path: &Path) -> Self {
+        Self {
+            path: path.to_owned(),
+        }
+    }
+}
+
+impl Sink for UnixSink {
+    fn send(&self, message: &OutgoingMessage) -> SinkFuture {
+        let line = match to_line(message) {
+            Ok(line) => line,
+            Err(err) => return Box::new(future::err(err)),
+        };
+
+        let path = self.path.clone();
+
+        let future = UnixStream::connect(&self.path)
+            .and_then(move |stream| tokio::io::write_all(stream, line))
+            .map(|_| None)
+            .map_err(move |err| {
+                format_err!(
+                    "error writing to the socket = '{}', {}",
+                    path.display(),
+                    err
+                )
+            });
+
+        Box::new(future)
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+pub(crate) struct StdoutSink;
+
+impl Sink for StdoutSink {
+    fn send(&self, message: &OutgoingMessage) -> SinkFuture {
+        let result = to_line(message).and_then(|line| {
+            let stdout = std::io::stdout();
+            let mut stdout = stdout.lock();
+            stdout.write_all(&line).context("Failed to write to stdout")
+        });
+
+        Box::new(future::result(result.map(|()| None)))
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::io::Read;
+    use std::net::TcpListener;
+    use std::os::unix::net::UnixListener;
+    use std::thread;
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::util::headers::Headers;
+
+    fn tmp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("http-gateway-sink-{}-{}", name, std::process::id()));
+
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).expect("Failed to create temporary directory");
+        dir
+    }
+
+    fn message(sink: &Config) -> OutgoingMessage {
+        let headers = serde_json::from_value::<Headers>(json!([["type", "event"]]))
+            .expect("Failed to parse headers");
+
+        OutgoingMessage::new(json!({"x": 1}), headers, "example.org", sink, None, "token")
+    }
+
+    /// Serves a single HTTP response on a loopback port returning its URL.
+    fn serve_once(body: Vec<u8>, content_length: bool) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("Failed to bind listener");
+        let addr = listener.local_addr().expect("Failed to get local address");
+
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().expect("Failed to accept");
+            let mut request = [0; 1024];
+            let _ = stream.read(&mut request);
+
+            let mut head = String::from("HTTP/1.1 200 OK\r\nConnection: close\r\n");
+
+            if content_length {
+                head.push_str(&format!("Content-Length: {}\r\n", body.len()));
+            }
+
+            head.push_str("\r\n");
+            let _ = stream.write_all(head.as_bytes());
+            let _ = stream.write_all(&body);
+        });
+
+        format!("http://{}/callback", addr)
+    }
+
+    fn fetch_reply(url: &str) -> Result<Vec<u8>> {
+        let client = HttpClient::new();
+        let mut runtime = tokio::runtime::current_thread::Runtime::new().unwrap();
+        runtime.block_on(
+            client
+                .get(url)
+                .send()
+                .map_err(Error::from)
+                .and_then(read_reply),
+        )
+    }
+
+    #[test]
+    fn deserializes_config() {
+        let config = serde_json::from_value::<Config>(json!({"type": "http"})).unwrap();
+        assert_eq!(config, Config::Http);
+
+        let config = serde_json::from_value::<Config>(json!({
+            "type": "file",
+            "path": "/var/log/events.ndjson",
+        }))
+        .unwrap();
+
+        assert_eq!(config.to_string(), "file:/var/log/events.ndjson");
+        assert!(serde_json::from_value::<Config>(json!({"type": "unix"})).is_err());
+    }
+
+    #[test]
+    fn limits_reply_size() {
+        let reply = br#"{"label": "room.notify"}"#.to_vec();
+        let url = serve_once(reply.clone(), true);
+        assert_eq!(fetch_reply(&url).expect("Failed to read reply"), reply);
+
+        for &content_length in &[true, false] {
+            let url = serve_once(vec![b' '; MAX_REPLY_SIZE + 1], content_length);
+            assert!(
+                fetch_reply(&url).is_err(),
+                "content_length = {}",
+                content_length
+            );
+        }
+    }
+
+    #[test]
+    fn restricts_paths_to_base_dir() {
+        let base_dir = tmp_dir("base");
+        let outside = tmp_dir("outside");
+
+        let inside = Config::File {
+            path: base_dir.join("events.ndjson"),
+        };
+
+        assert!(inside.check_base_dir(&base_dir).is_ok());
+        assert!(Config::Stdout.check_base_dir(&base_dir).is_ok());
+
+        let escaping = Config::File {
+            path: base_dir.join("../events.ndjson"),
+        };
+
+        assert!(escaping.check_base_dir(&base_dir).is_err());
+
+        let absolute = Config::Unix {
+            path: outside.join("events.sock"),
+        };
+
+        assert!(absolute.check_base_dir(&base_dir).is_err());
+
+        let link = base_dir.join("link");
+        std::os::unix::fs::symlink(&outside, &link).expect("Failed to create symlink");
+
+        let linked = Config::File {
+            path: link.join("events.ndjson"),
+        };
+
+        assert!(linked.check_base_dir(&base_dir).is_err());
+
+        let _ = fs::remove_dir_all(&base_dir);
+        let _ = fs::remove_dir_all(&outside);
+    }
+
+    #[test]
+    fn writes_lines_to_file_and_socket() {
+        let dir = tmp_dir("write");
+
+        let path = dir.join("events.ndjson");
+        let config = Config::File { path: path.clone() };
+        let sink = FileSink::open(&path).expect("Failed to open file sink");
+        sink.send(&message(&config))
+            .wait()
+            .expect("Failed to write");
+        sink.send(&message(&config))
+            .wait()
+            .expect("Failed to write");
+
+        let data = fs::read_to_string(&path).expect("Failed to read sink file");
+        let lines = data.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 2);
+
+        let record = serde_json::from_str::<JsonValue>(lines[0]).expect("Invalid line");
+        assert_eq!(record["audience"], "example.org");
+        assert_eq!(record["properties"]["type"], "event");
+        assert_eq!(record["payload"]["x"], 1);
+
+        let path = dir.join("events.sock");
+        let listener = UnixListener::bind(&path).expect("Failed to bind socket");
+
+        let reader = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().expect("Failed to accept");
+            let mut data = String::new();
+            stream.read_to_string(&mut data).expect("Failed to read");
+            data
+        });
+
+        let config = Config::Unix { path: path.clone() };
+        let mut runtime = tokio::runtime::current_thread::Runtime::new().unwrap();
+
+        runtime
+            .block_on(UnixSink::new(&path).send(&message(&config)))
+            .expect("Failed to write to socket");
+
+        let data = reader.join().expect("Reader panicked");
+        assert!(data.ends_with('\n'));
+        assert_eq!(
+            serde_json::from_str::<JsonValue>(&data).unwrap()["audience"],
+            "example.org"
+        );
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,511 @@
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::{format_err, Context, Result};
+use futures::{Async, Poll};
+use log::{error, info};
+use rustls::internal::pemfile;
+use rustls::{
+    AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient, Certificate, NoClientAuth,
+    PrivateKey, RootCertStore, ServerConfig, ServerSession, Session,
+};
+use serde_derive::Deserialize;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+
+////////////////////////////////////////////////////////////////////////////////
+
+const ALPN_HTTP2: &[u8] = b"h2";
+const ALPN_HTTP1: &[u8] = b"http/1.1";
+
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct Config {
+    /// PEM encoded certificate chain.
+    cert_path: PathBuf,
+    /// PEM encoded PKCS #8 or RSA private key.
+    key_path: PathBuf,
+    /// PEM encoded CA certificates to verify client certificates with.
+    client_ca_path: Option<PathBuf>,
+    #[serde(default)]
+    client_auth: ClientAuth,
+    /// Offer HTTP/2 through ALPN.
+    #[serde(default = "Config::default_http2")]
+    http2: bool,
+    /// How often the files are checked for changes.
+    #[serde(deserialize_with = "crate::serde::duration")]
+    #[serde(default = "Config::default_reload_interval")]
+    reload_interval: Duration,
+}
+
+impl Config {
+    fn default_http2() -> bool {
+        true
+    }
+
+    fn default_reload_interval() -> Duration {
+        Duration::from_secs(10)
+    }
+
+    fn paths(&self) -> Vec<&Path> {
+        let mut paths = vec![self.cert_path.as_path(), self.key_path.as_path()];
+
+        if let Some(ref client_ca_path) = self.client_ca_path {
+            paths.push(client_ca_path);
+        }
+
+        paths
+    }
+
+    // Modification times of the files, `None` for ones which can't be read.
+    fn modified(&self) -> Vec<Option<SystemTime>> {
+        self.paths()
+            .into_iter()
+            .map(|path| fs::metadata(path).and_then(|meta| meta.modified()).ok())
+            .collect()
+    }
+}
+
+/// Whether clients must present a certificate when `client_ca_path` is set.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ClientAuth {
+    #[default]
+    Required,
+    Optional,
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Wraps accepted connections into TLS sessions.
+///
+/// The certificate is reloaded on accepting a connection if its files have changed.
+pub(crate) struct TlsAcceptor {
+    config: Config,
+    state: Mutex<AcceptorState>,
+}
+
+struct AcceptorState {
+    server_config: Arc<ServerConfig>,
+    modified: Vec<Option<SystemTime>>,
+    checked_at: Instant,
+}
+
+impl TlsAcceptor {
+    pub(crate) fn new(config: &Config) -> Result<Self> {
+        let modified = config.modified();
+        let server_config = build_server_config(config)?;
+
+        Ok(Self {
+            config: config.to_owned(),
+            state: Mutex::new(AcceptorState {
+                server_config: Arc::new(server_config),
+                modified,
+                checked_at: Instant::now(),
+            }),
+        })
+    }
+
+    /// The handshake is made lazily on the first read or write so it doesn't block accepting.
+    pub(crate) fn accept(&self, io: TcpStream) -> TlsStream {
+        TlsStream {
+            session: ServerSession::new(&self.server_config()),
+            io,
+            closing: false,
+        }
+    }
+
+    fn server_config(&self) -> Arc<ServerConfig> {
+        let mut state = match self.state.lock() {
+            Ok(state) => state,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+
+        if state.checked_at.elapsed() >= self.config.reload_interval {
+            state.checked_at = Instant::now();
+            let modified = self.config.modified();
+
+            if modified != state.modified {
+                // A failed reload is retried on the next check, e.g. if only the certificate
+                // has been written by the moment and the key is still old.
+                match build_server_config(&self.config) {
+                    Ok(server_config) => {
+                        info!(
+                            "TLS certificate reloaded from '{}'",
+                            self.config.cert_path.display()
+                        );
+
+                        state.server_config = Arc::new(server_config);
+                        state.modified = modified;
+                    }
+                    Err(err) => error!("Failed to reload TLS certificate, {:#}", err),
+                }
+            }
+        }
+
+        state.server_config.clone()
+    }
+}
+
+fn build_server_config(config: &Config) -> Result<ServerConfig> {
+    let verifier = match config.client_ca_path {
+        Some(ref path) => {
+            let mut roots = RootCertStore::empty();
+
+            for cert in load_certs(path)? {
+                roots.add(&cert).map_err(|err| {
+                    format_err!("Invalid CA certificate in {}: {:?}", path.display(), err)
+                })?;
+            }
+
+            match config.client_auth {
+                ClientAuth::Required => AllowAnyAuthenticatedClient::new(roots),
+                ClientAuth::Optional => AllowAnyAnonymousOrAuthenticatedClient::new(roots),
+            }
+        }
+        None => NoClientAuth::new(),
+    };
+
+    let mut server_config = ServerConfig::new(verifier);
+
+    server_config
+        .set_single_cert(load_certs(&config.cert_path)?, load_key(&config.key_path)?)
+        .with_context(|| {
+            format!(
+                "Invalid TLS certificate or key in {} and {}",
+                config.cert_path.display(),
+                config.key_path.display()
+            )
+        })?;
+
+    if config.http2 {
+        server_config.set_protocols(&[ALPN_HTTP2.to_vec(), ALPN_HTTP1.to_vec()]);
+    } else {
+        server_config.set_protocols(&[ALPN_HTTP1.to_vec()]);
+    }
+
+    Ok(server_config)
+}
+
+fn open(path: &Path) -> Result<BufReader<File>> {
+    File::open(path)
+        .map(BufReader::new)
+        .with_context(|| format!("Failed to open {}", path.display()))
+}
+
+fn load_certs(path: &Path) -> Result<Vec<Certificate>> {
+    let certs = pemfile::certs(&mut open(path)?)
+        .map_err(|()| format_err!("Failed to parse certificates from {}", path.display()))?;
+
+    if certs.is_empty() {
+        return Err(format_err!("No certificates found in {}", path.display()));
+    }
+
+    Ok(certs)
+}
+
+fn load_key(path: &Path) -> Result<PrivateKey> {
+    let parse_error = || format_err!("Failed to parse private key from {}", path.display());
+
+    let mut keys = pemfile::pkcs8_private_keys(&mut open(path)?).map_err(|()| parse_error())?;
+
+    if keys.is_empty() {
+        keys = pemfile::rsa_private_keys(&mut open(path)?).map_err(|()| parse_error())?;
+    }
+
+    keys.into_iter()
+        .next()
+        .ok_or_else(|| format_err!("No private key found in {}", path.display()))
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Server side of a TLS connection.
+pub(crate) struct TlsStream {
+    session: ServerSession,
+    io: TcpStream,
+    closing: bool,
+}
+
+impl TlsStream {
+    pub(crate) fn peer_addr(&self) -> Option<SocketAddr> {
+        self.io.peer_addr().ok()
+    }
+}
+
+// `rustls::Stream` drives the handshake and passes `WouldBlock` of the socket through
+// which makes the task wait for the socket readiness.
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        rustls::Stream::new(&mut self.session, &mut self.io).read(buf)
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        rustls::Stream::new(&mut self.session, &mut self.io).write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        rustls::Stream::new(&mut self.session, &mut self.io).flush()
+    }
+}
+
+impl AsyncRead for TlsStream {}
+
+impl AsyncWrite for TlsStream {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        if !self.closing {
+            self.session.send_close_notify();
+            self.closing = true;
+        }
+
+        match self.flush() {
+            Ok(()) => (),
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(Async::NotReady),
+            Err(err) => return Err(err),
+        }
+
+        AsyncWrite::shutdown(&mut self.io)
+    }
+}
+
+impl tower_web::net::Connection for TlsStream {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        TlsStream::peer_addr(self)
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test {
+    use std::net::TcpListener;
+    use std::thread;
+
+    use futures::Future;
+    use rcgen::{BasicConstraints, Certificate as RcgenCertificate, CertificateParams, IsCa};
+    use rustls::{ClientConfig, ClientSession};
+    use serde_json::json;
+    use tokio::runtime::Runtime;
+    use webpki::DNSNameRef;
+
+    use super::*;
+
+    fn tmp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("http-gateway-tls-{}-{}", name, std::process::id()));
+
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).expect("Failed to create temporary directory");
+        dir
+    }
+
+    fn pem(tag: &str, der: &[u8]) -> String {
+        let encoded = base64::encode(der);
+        let mut pem = format!("-----BEGIN {}-----\n", tag);
+
+        for line in encoded.as_bytes().chunks(64) {
+            pem.push_str(std::str::from_utf8(line).unwrap());
+            pem.push('\n');
+        }
+
+        pem.push_str(&format!("-----END {}-----\n", tag));
+        pem
+    }
+
+    fn generate_ca() -> RcgenCertificate {
+        let mut params = CertificateParams::new(vec![]);
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        RcgenCertificate::from_params(params).expect("Failed to generate CA")
+    }
+
+    /// Certificate for `localhost` issued by the CA as (certificate DER, PKCS #8 key DER).
+    fn issue(ca: &RcgenCertificate) -> (Vec<u8>, Vec<u8>) {
+        let cert =
+            RcgenCertificate::from_params(CertificateParams::new(vec!["localhost".to_owned()]))
+                .expect("Failed to generate certificate");
+
+        let der = cert
+            .serialize_der_with_signer(ca)
+            .expect("Failed to sign certificate");
+
+        (der, cert.serialize_private_key_der())
+    }
+
+    /// Writes the server certificate issued by the CA and returns the config to load it.
+    fn write_server_files(dir: &Path, ca: &RcgenCertificate, client_auth: Option<&str>) -> Config {
+        let (cert, key) = issue(ca);
+        fs::write(dir.join("cert.pem"), pem("CERTIFICATE", &cert)).unwrap();
+        fs::write(dir.join("key.pem"), pem("PRIVATE KEY", &key)).unwrap();
+
+        let mut config = json!({
+            "cert_path": dir.join("cert.pem"),
+            "key_path": dir.join("key.pem"),
+            "reload_interval": 0,
+        });
+
+        if let Some(client_auth) = client_auth {
+            config["client_ca_path"] = json!(dir.join("client-ca.pem"));
+            config["client_auth"] = json!(client_auth);
+        }
+
+        serde_json::from_value::<Config>(config).expect("Failed to parse config")
+    }
+
+    fn client_config(ca: &RcgenCertificate, alpn: &[&[u8]]) -> ClientConfig {
+        let mut config = ClientConfig::new();
+        let ca_der = ca.serialize_der().expect("Failed to serialize CA");
+
+        config
+            .root_store
+            .add(&Certificate(ca_der))
+            .expect("Failed to add CA");
+
+        config.set_protocols(&alpn.iter().map(|p| p.to_vec()).collect::<Vec<_>>());
+        config
+    }
+
+    /// Makes a TLS connection through the acceptor, the client sends "ping" and the server
+    /// replies "pong". Returns the server's result and the protocol the client has negotiated.
+    fn exchange(
+        acceptor: &TlsAcceptor,
+        client_config: ClientConfig,
+    ) -> (io::Result<()>, io::Result<Option<Vec<u8>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let client = thread::spawn(move || -> io::Result<Option<Vec<u8>>> {
+            let mut socket = std::net::TcpStream::connect(addr)?;
+            socket.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+            let name = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+            let mut session = ClientSession::new(&Arc::new(client_config), name);
+            let mut stream = rustls::Stream::new(&mut session, &mut socket);
+
+            stream.write_all(b"ping")?;
+            let mut buf = [0; 4];
+            stream.read_exact(&mut buf)?;
+            assert_eq!(&buf, b"pong");
+
+            Ok(session.get_alpn_protocol().map(|p| p.to_vec()))
+        });
+
+        let (socket, _) = listener.accept().unwrap();
+        let socket = TcpStream::from_std(socket, &tokio::reactor::Handle::default()).unwrap();
+        let stream = acceptor.accept(socket);
+
+        let server = tokio::io::read_exact(stream, [0; 4])
+            .and_then(|(stream, buf)| {
+                assert_eq!(&buf, b"ping");
+                tokio::io::write_all(stream, b"pong")
+            })
+            .and_then(|(stream, _)| tokio::io::flush(stream))
+            .map(|_| ());
+
+        let server_result = Runtime::new().unwrap().block_on(server);
+        (server_result, client.join().unwrap())
+    }
+
+    #[test]
+    fn names_missing_files() {
+        let config = serde_json::from_value::<Config>(serde_json::json!({
+            "cert_path": "/nonexistent/cert.pem",
+            "key_path": "/nonexistent/key.pem",
+        }))
+        .expect("Failed to parse config");
+
+        assert!(config.http2);
+        assert_eq!(config.client_auth, ClientAuth::Required);
+
+        let err = build_server_config(&config)
+            .err()
+            .expect("Config built without files");
+        assert!(format!("{:#}", err).contains("/nonexistent/cert.pem"));
+    }
+
+    #[test]
+    fn negotiates_http2() {
+        let dir = tmp_dir("alpn");
+        let ca = generate_ca();
+        let acceptor = TlsAcceptor::new(&write_server_files(&dir, &ca, None)).unwrap();
+
+        let (server, client) = exchange(&acceptor, client_config(&ca, &[ALPN_HTTP2, ALPN_HTTP1]));
+        server.expect("Server failed");
+        assert_eq!(client.expect("Client failed"), Some(ALPN_HTTP2.to_vec()));
+
+        let (server, client) = exchange(&acceptor, client_config(&ca, &[ALPN_HTTP1]));
+        server.expect("Server failed");
+        assert_eq!(client.expect("Client failed"), Some(ALPN_HTTP1.to_vec()));
+
+        let mut config = write_server_files(&dir, &ca, None);
+        config.http2 = false;
+        let acceptor = TlsAcceptor::new(&config).unwrap();
+
+        let (server, client) = exchange(&acceptor, client_config(&ca, &[ALPN_HTTP2, ALPN_HTTP1]));
+        server.expect("Server failed");
+        assert_eq!(client.expect("Client failed"), Some(ALPN_HTTP1.to_vec()));
+    }
+
+    #[test]
+    fn verifies_client_certificates() {
+        let dir = tmp_dir("client-auth");
+        let ca = generate_ca();
+        let client_ca = generate_ca();
+        fs::write(
+            dir.join("client-ca.pem"),
+            pem("CERTIFICATE", &client_ca.serialize_der().unwrap()),
+        )
+        .unwrap();
+
+        let (cert, key) = issue(&client_ca);
+        let mut with_cert = client_config(&ca, &[]);
+        with_cert
+            .set_single_client_cert(vec![Certificate(cert)], PrivateKey(key))
+            .expect("Failed to set client certificate");
+
+        let required = TlsAcceptor::new(&write_server_files(&dir, &ca, Some("required"))).unwrap();
+        let (server, client) = exchange(&required, with_cert.clone());
+        server.expect("Server failed");
+        client.expect("Client failed");
+
+        let (server, _) = exchange(&required, client_config(&ca, &[]));
+        assert!(server.is_err());
+
+        let optional = TlsAcceptor::new(&write_server_files(&dir, &ca, Some("optional"))).unwrap();
+        let (server, client) = exchange(&optional, client_config(&ca, &[]));
+        server.expect("Server failed");
+        client.expect("Client failed");
+
+        let (server, client) = exchange(&optional, with_cert);
+        server.expect("Server failed");
+        client.expect("Client failed");
+    }
+
+    #[test]
+    fn reloads_changed_certificate() {
+        let dir = tmp_dir("reload");
+        let old_ca = generate_ca();
+        let new_ca = generate_ca();
+        let acceptor = TlsAcceptor::new(&write_server_files(&dir, &old_ca, None)).unwrap();
+
+        let (server, _) = exchange(&acceptor, client_config(&new_ca, &[]));
+        assert!(server.is_err());
+
+        write_server_files(&dir, &new_ca, None);
+
+        let (server, client) = exchange(&acceptor, client_config(&new_ca, &[]));
+        server.expect("Server failed");
+        client.expect("Client failed");
+
+        // A broken file keeps the last loaded certificate.
+        fs::write(dir.join("key.pem"), "").unwrap();
+
+        let (server, client) = exchange(&acceptor, client_config(&new_ca, &[]));
+        server.expect("Server failed");
+        client.expect("Client failed");
+    }
+}
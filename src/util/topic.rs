@@ -0,0 +1,317 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::{bail, format_err, Error, Result};
+
+////////////////////////////////////////////////////////////////////////////////
+
+pub(crate) type Variables = HashMap<String, String>;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Literal(String),
+    Variable(String),
+}
+
+/// MQTT topic pattern with named placeholders, e.g. `rooms/{room_id}/events`.
+///
+/// Each placeholder takes up a whole topic level and matches exactly one level
+/// of an incoming topic, just like the `+` wildcard of MQTT topic filters.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct TopicPattern {
+    segments: Vec<Segment>,
+}
+
+impl TopicPattern {
+    /// Returns a pattern consisting of the given literal levels followed by this pattern.
+    pub(crate) fn prefixed<S: AsRef<str>>(&self, prefix: &[S]) -> Self {
+        let mut segments = prefix
+            .iter()
+            .map(|level| Segment::Literal(level.as_ref().to_owned()))
+            .collect::<Vec<_>>();
+
+        segments.extend(self.segments.iter().cloned());
+        Self { segments }
+    }
+
+    /// Substitutes placeholders known in advance with their values.
+    pub(crate) fn bind(&self, vars: &Variables) -> Self {
+        let segments = self
+            .segments
+            .iter()
+            .map(|segment| match segment {
+                Segment::Variable(name) => match vars.get(name) {
+                    Some(value) => Segment::Literal(value.to_owned()),
+                    None => segment.clone(),
+                },
+                Segment::Literal(_) => segment.clone(),
+            })
+            .collect();
+
+        Self { segments }
+    }
+
+    /// Names of the placeholders.
+    pub(crate) fn variables(&self) -> impl Iterator<Item = &str> {
+        self.segments.iter().filter_map(|segment| match segment {
+            Segment::Variable(name) => Some(name.as_str()),
+            Segment::Literal(_) => None,
+        })
+    }
+
+    /// MQTT topic filter to subscribe to with placeholders replaced by `+` wildcards.
+    pub(crate) fn to_filter(&self) -> String {
+        self.segments
+            .iter()
+            .map(|segment| match segment {
+                Segment::Literal(value) => value.as_str(),
+                Segment::Variable(_) => "+",
+            })
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+
+    /// Topic to publish to, fails if any placeholder isn't bound.
+    pub(crate) fn to_topic(&self) -> Result<String> {
+        let levels = self
+            .segments
+            .iter()
+            .map(|segment| match segment {
+                Segment::Literal(value) => Ok(value.as_str()),
+                Segment::Variable(name) => Err(format_err!(
+                    "placeholder = '{}' of topic pattern = '{}' is not bound",
+                    name,
+                    self
+                )),
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(levels.join("/"))
+    }
+
+    /// Matches the topic against the pattern returning values of placeholders.
+    pub(crate) fn captures(&self, topic: &str) -> Option<Variables> {
+        let levels = topic.split('/').collect::<Vec<_>>();
+
+        if levels.len() != self.segments.len() {
+            return None;
+        }
+
+        let mut vars = Variables::new();
+
+        for (segment, level) in self.segments.iter().zip(levels) {
+            match segment {
+                Segment::Literal(value) if value == level => (),
+                Segment::Literal(_) => return None,
+                Segment::Variable(_) if level.is_empty() => return None,
+                Segment::Variable(name) => {
+                    vars.insert(name.to_owned(), level.to_owned());
+                }
+            }
+        }
+
+        Some(vars)
+    }
+}
+
+impl FromStr for TopicPattern {
+    type Err = Error;
+
+    fn from_str(pattern: &str) -> Result<Self> {
+        if pattern.is_empty() {
+            bail!("topic pattern is empty");
+        }
+
+        let mut segments = Vec::new();
+
+        for level in pattern.split('/') {
+            if level.starts_with('{') && level.ends_with('}') && level.len() > 2 {
+                let name = &level[1..level.len() - 1];
+
+                if !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+                    bail!(
+                        "invalid placeholder name = '{}' in topic pattern = '{}'",
+                        name,
+                        pattern
+                    );
+                }
+
+                if segments.contains(&Segment::Variable(name.to_owned())) {
+                    bail!(
+                        "duplicate placeholder = '{}' in topic pattern = '{}'",
+                        name,
+                        pattern
+                    );
+                }
+
+                segments.push(Segment::Variable(name.to_owned()));
+            } else if level.contains(&['{', '}', '+', '#'][..]) {
+                bail!(
+                    "topic level = '{}' of topic pattern = '{}' must be either a literal or a placeholder",
+                    level,
+                    pattern
+                );
+            } else {
+                segments.push(Segment::Literal(level.to_owned()));
+            }
+        }
+
+        Ok(Self { segments })
+    }
+}
+
+impl fmt::Display for TopicPattern {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let levels = self
+            .segments
+            .iter()
+            .map(|segment| match segment {
+                Segment::Literal(value) => value.to_owned(),
+                Segment::Variable(name) => format!("{{{}}}", name),
+            })
+            .collect::<Vec<_>>();
+
+        write!(fmt, "{}", levels.join("/"))
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Renders a template like `https://example.org/rooms/{room_id}` with the variables.
+///
+/// Values are percent-encoded so that they can't change the path or query of the URL.
+pub(crate) fn render(template: &str, vars: &Variables) -> Result<String> {
+    let mut result = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find('{') {
+        let end = rest[start..]
+            .find('}')
+            .map(|end| start + end)
+            .ok_or_else(|| format_err!("unclosed placeholder in template = '{}'", template))?;
+
+        let name = &rest[start + 1..end];
+
+        let value = vars.get(name).ok_or_else(|| {
+            format_err!(
+                "unknown placeholder = '{}' in template = '{}'",
+                name,
+                template
+            )
+        })?;
+
+        result.push_str(&rest[..start]);
+        result.push_str(&percent_encode(value));
+        rest = &rest[end + 1..];
+    }
+
+    result.push_str(rest);
+    Ok(result)
+}
+
+// Everything but unreserved characters of RFC 3986.
+fn percent_encode(value: &str) -> String {
+    let mut result = String::with_capacity(value.len());
+
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                result.push(byte as char)
+            }
+            _ => result.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+
+    result
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn captures_variables() {
+        let pattern = "rooms/{room_id}/events".parse::<TopicPattern>().unwrap();
+        let pattern = pattern.prefixed(&["apps", "conference.example.org", "api", "v1"]);
+
+        let vars = pattern
+            .captures("apps/conference.example.org/api/v1/rooms/123/events")
+            .expect("Topic doesn't match");
+
+        assert_eq!(vars.get("room_id").map(|v| v.as_str()), Some("123"));
+        assert!(pattern
+            .captures("apps/conference.example.org/api/v1/rooms/events")
+            .is_none());
+        assert!(pattern
+            .captures("apps/conference.example.org/api/v2/rooms/123/events")
+            .is_none());
+    }
+
+    #[test]
+    fn binds_and_builds_filter() {
+        let pattern = "audiences/{audience}/rooms/{room_id}/events"
+            .parse::<TopicPattern>()
+            .unwrap();
+
+        let mut vars = Variables::new();
+        vars.insert("audience".to_owned(), "example.org".to_owned());
+
+        assert_eq!(
+            pattern.bind(&vars).to_filter(),
+            "audiences/example.org/rooms/+/events"
+        );
+
+        assert_eq!(
+            pattern.variables().collect::<Vec<_>>(),
+            vec!["audience", "room_id"]
+        );
+        assert_eq!(
+            pattern.bind(&vars).variables().collect::<Vec<_>>(),
+            vec!["room_id"]
+        );
+
+        assert!(pattern.bind(&vars).to_topic().is_err());
+        vars.insert("room_id".to_owned(), "123".to_owned());
+
+        assert_eq!(
+            pattern.bind(&vars).to_topic().unwrap(),
+            "audiences/example.org/rooms/123/events"
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_patterns() {
+        assert!("".parse::<TopicPattern>().is_err());
+        assert!("rooms/+/events".parse::<TopicPattern>().is_err());
+        assert!("rooms/#".parse::<TopicPattern>().is_err());
+        assert!("rooms/id-{id}/events".parse::<TopicPattern>().is_err());
+        assert!("rooms/{id}/{id}".parse::<TopicPattern>().is_err());
+    }
+
+    #[test]
+    fn renders_template() {
+        let mut vars = Variables::new();
+        vars.insert("room_id".to_owned(), "123".to_owned());
+
+        assert_eq!(
+            render("https://example.org/rooms/{room_id}/callback", &vars).unwrap(),
+            "https://example.org/rooms/123/callback"
+        );
+
+        assert!(render("https://example.org/{unknown}", &vars).is_err());
+    }
+
+    #[test]
+    fn encodes_rendered_values() {
+        let mut vars = Variables::new();
+        vars.insert("room_id".to_owned(), "1/../admin?x=1#y ü".to_owned());
+
+        assert_eq!(
+            render("https://example.org/rooms/{room_id}/callback", &vars).unwrap(),
+            "https://example.org/rooms/1%2F..%2Fadmin%3Fx%3D1%23y%20%C3%BC/callback"
+        );
+    }
+}
@@ -0,0 +1,539 @@
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Context, Result};
+use futures::{future, sync::mpsc, Future, Stream};
+use log::{error, warn};
+use serde_derive::Deserialize;
+use serde_json::{json, Value as JsonValue};
+use tokio::prelude::FutureExt;
+use uuid::Uuid;
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// MQTT property carrying the trace context of requests and events.
+pub(crate) const TRACEPARENT_PROPERTY: &str = "traceparent";
+/// HTTP header carrying the trace context of incoming requests and callbacks.
+pub(crate) const TRACEPARENT_HEADER: &str = "traceparent";
+const TRACEPARENT_VERSION: &str = "00";
+const FLAG_SAMPLED: u8 = 0x01;
+
+/// W3C trace context of a span.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct TraceContext {
+    trace_id: String,
+    span_id: String,
+    sampled: bool,
+}
+
+impl TraceContext {
+    fn new_root() -> Self {
+        Self {
+            trace_id: to_hex(Uuid::new_v4().as_bytes()),
+            span_id: new_span_id(),
+            sampled: true,
+        }
+    }
+
+    fn child(&self) -> Self {
+        Self {
+            trace_id: self.trace_id.clone(),
+            span_id: new_span_id(),
+            sampled: self.sampled,
+        }
+    }
+
+    /// Parses `traceparent` header value, returns `None` if it's malformed.
+    pub(crate) fn parse(traceparent: &str) -> Option<Self> {
+        let parts = traceparent.trim().split('-').collect::<Vec<_>>();
+
+        match parts.as_slice() {
+            [version, trace_id, span_id, flags]
+                if is_hex(version, 2)
+                    && *version != "ff"
+                    && is_hex(trace_id, 32)
+                    && is_hex(span_id, 16)
+                    && is_hex(flags, 2) =>
+            {
+                if is_zero(trace_id) || is_zero(span_id) {
+                    return None;
+                }
+
+                let flags = u8::from_str_radix(flags, 16).ok()?;
+
+                Some(Self {
+                    trace_id: trace_id.to_lowercase(),
+                    span_id: span_id.to_lowercase(),
+                    sampled: flags & FLAG_SAMPLED != 0,
+                })
+            }
+            _ => None,
+        }
+    }
+
+    pub(crate) fn to_traceparent(&self) -> String {
+        let flags = if self.sampled { FLAG_SAMPLED } else { 0 };
+
+        format!(
+            "{}-{}-{}-{:02x}",
+            TRACEPARENT_VERSION, self.trace_id, self.span_id, flags
+        )
+    }
+}
+
+fn new_span_id() -> String {
+    to_hex(&Uuid::new_v4().as_bytes()[..8])
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn is_hex(value: &str, len: usize) -> bool {
+    value.len() == len && value.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+fn is_zero(value: &str) -> bool {
+    value.chars().all(|c| c == '0')
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum SpanKind {
+    Server,
+    Client,
+}
+
+impl SpanKind {
+    // Values of `SpanKind` enum from the OTLP protocol.
+    fn code(self) -> u8 {
+        match self {
+            SpanKind::Server => 2,
+            SpanKind::Client => 3,
+        }
+    }
+}
+
+/// An operation being traced. The span is exported when ended, dropping it discards the span.
+#[derive(Debug)]
+pub(crate) struct Span {
+    tx: Option<SpanSender>,
+    data: SpanData,
+}
+
+#[derive(Debug)]
+struct SpanData {
+    name: String,
+    kind: SpanKind,
+    context: TraceContext,
+    parent_span_id: Option<String>,
+    start_time: SystemTime,
+    end_time: SystemTime,
+    attributes: Vec<(String, String)>,
+    ok: bool,
+}
+
+impl Span {
+    pub(crate) fn context(&self) -> &TraceContext {
+        &self.data.context
+    }
+
+    pub(crate) fn set_attribute(&mut self, key: &str, value: &str) {
+        self.data
+            .attributes
+            .push((key.to_owned(), value.to_owned()));
+    }
+
+    pub(crate) fn end(mut self, ok: bool) {
+        self.data.end_time = SystemTime::now();
+        self.data.ok = ok;
+
+        if let Some(tx) = self.tx.take() {
+            if self.data.context.sampled {
+                tx.send(self.data);
+            }
+        }
+    }
+}
+
+/// Sends ended spans to the exporter dropping them while `queue_size` spans wait for export.
+#[derive(Debug, Clone)]
+struct SpanSender {
+    tx: mpsc::UnboundedSender<SpanData>,
+    queue: Arc<SpanQueue>,
+}
+
+#[derive(Debug)]
+struct SpanQueue {
+    len: AtomicUsize,
+    capacity: usize,
+    // Whether spans have been dropped since the last export, to warn once per overflow.
+    overflowed: AtomicBool,
+}
+
+impl SpanSender {
+    fn send(&self, span: SpanData) {
+        if self.queue.len.fetch_add(1, Ordering::SeqCst) >= self.queue.capacity {
+            self.queue.len.fetch_sub(1, Ordering::SeqCst);
+
+            if !self.queue.overflowed.swap(true, Ordering::SeqCst) {
+                warn!("Spans queue is full, dropping spans until the exporter catches up");
+            }
+
+            return;
+        }
+
+        if self.tx.unbounded_send(span).is_err() {
+            self.queue.len.fetch_sub(1, Ordering::SeqCst);
+            error!("Error sending span to the exporter");
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct Config {
+    #[serde(default = "Config::default_service_name")]
+    service_name: String,
+    exporter: Exporter,
+    /// Ended spans waiting for export, more are dropped.
+    #[serde(default = "Config::default_queue_size")]
+    queue_size: usize,
+    /// Spans sent in one export request at most.
+    #[serde(default = "Config::default_batch_size")]
+    batch_size: usize,
+    #[serde(deserialize_with = "crate::serde::duration")]
+    #[serde(default = "Config::default_export_timeout")]
+    export_timeout: Duration,
+}
+
+impl Config {
+    fn default_service_name() -> String {
+        String::from("http-gateway")
+    }
+
+    fn default_queue_size() -> usize {
+        2048
+    }
+
+    fn default_batch_size() -> usize {
+        512
+    }
+
+    fn default_export_timeout() -> Duration {
+        Duration::from_secs(10)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub(crate) enum Exporter {
+    /// POST spans in OTLP/HTTP JSON encoding to the collector's endpoint,
+    /// e.g. `http://localhost:4318/v1/traces`.
+    Otlp { endpoint: String },
+    /// Append OTLP JSON export requests to a file, one per line.
+    File { path: PathBuf },
+}
+
+/// Creates spans and sends ended ones to the exporter. Spans are discarded when tracing is disabled.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Tracer {
+    tx: Option<SpanSender>,
+}
+
+impl Tracer {
+    pub(crate) fn new(
+        config: Option<&Config>,
+    ) -> Result<(Self, impl Future<Item = (), Error = ()>)> {
+        let config = match config {
+            Some(config) => config.to_owned(),
+            None => return Ok((Self::default(), future::Either::A(future::ok(())))),
+        };
+
+        if config.queue_size == 0 || config.batch_size == 0 {
+            bail!("'tracing.queue_size' and 'tracing.batch_size' must be positive");
+        }
+
+        let mut exporter = SpanExporter::new(&config.exporter, config.export_timeout)?;
+        let (tx, rx) = mpsc::unbounded::<SpanData>();
+
+        let queue = Arc::new(SpanQueue {
+            len: AtomicUsize::new(0),
+            capacity: config.queue_size,
+            overflowed: AtomicBool::new(false),
+        });
+
+        let tx = SpanSender {
+            tx,
+            queue: queue.clone(),
+        };
+
+        // Spans queued while the previous batch is exported are sent together, one batch at a time.
+        let export = rx.chunks(config.batch_size).for_each(move |spans| {
+            queue.len.fetch_sub(spans.len(), Ordering::SeqCst);
+            queue.overflowed.store(false, Ordering::SeqCst);
+
+            let request = export_request(&config.service_name, &spans);
+            exporter.export(&request)
+        });
+
+        Ok((Self { tx: Some(tx) }, future::Either::B(export)))
+    }
+
+    /// Whether spans are exported, trace context is propagated only if so.
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.tx.is_some()
+    }
+
+    /// Starts a span continuing the parent's trace or a new trace if there's no parent.
+    pub(crate) fn start(&self, name: &str, kind: SpanKind, parent: Option<&TraceContext>) -> Span {
+        let (context, parent_span_id) = match parent {
+            Some(parent) => (parent.child(), Some(parent.span_id.clone())),
+            None => (TraceContext::new_root(), None),
+        };
+
+        let now = SystemTime::now();
+
+        Span {
+            tx: self.tx.clone(),
+            data: SpanData {
+                name: name.to_owned(),
+                kind,
+                context,
+                parent_span_id,
+                start_time: now,
+                end_time: now,
+                attributes: Vec::new(),
+                ok: true,
+            },
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+enum SpanExporter {
+    Otlp {
+        client: reqwest::r#async::Client,
+        endpoint: String,
+        timeout: Duration,
+    },
+    File(File),
+}
+
+impl SpanExporter {
+    fn new(config: &Exporter, timeout: Duration) -> Result<Self> {
+        let exporter = match config {
+            Exporter::Otlp { endpoint } => SpanExporter::Otlp {
+                client: reqwest::r#async::Client::new(),
+                endpoint: endpoint.to_owned(),
+                timeout,
+            },
+            Exporter::File { path } => {
+                let file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .with_context(|| format!("Failed to open spans file {}", path.display()))?;
+
+                SpanExporter::File(file)
+            }
+        };
+
+        Ok(exporter)
+    }
+
+    /// Resolves once the spans are exported or failed to, errors are logged.
+    fn export(&mut self, request: &JsonValue) -> impl Future<Item = (), Error = ()> {
+        match self {
+            SpanExporter::Otlp {
+                client,
+                endpoint,
+                timeout,
+            } => {
+                let endpoint = endpoint.to_owned();
+
+                let future = client
+                    .post(&endpoint)
+                    .json(request)
+                    .send()
+                    .timeout(*timeout)
+                    .then(move |resp| {
+                        match resp {
+                            Ok(ref resp) if resp.status().is_success() => (),
+                            Ok(resp) => error!(
+                                "error with status code = '{}' on exporting spans to '{}'",
+                                resp.status(),
+                                endpoint
+                            ),
+                            Err(ref err) if err.is_elapsed() => {
+                                error!("timed out exporting spans to '{}'", endpoint)
+                            }
+                            Err(err) => error!(
+                                "network error on exporting spans to '{}', {}",
+                                endpoint, err
+                            ),
+                        }
+
+                        Ok(())
+                    });
+
+                future::Either::A(future)
+            }
+            SpanExporter::File(file) => {
+                let result = serde_json::to_vec(request)
+                    .context("Failed to serialize spans")
+                    .and_then(|mut line| {
+                        line.push(b'\n');
+                        file.write_all(&line)
+                            .context("Failed to write to spans file")
+                    });
+
+                if let Err(err) = result {
+                    error!("Error exporting spans, {:#}", err);
+                }
+
+                future::Either::B(future::ok(()))
+            }
+        }
+    }
+}
+
+/// Builds OTLP `ExportTraceServiceRequest` in JSON encoding.
+fn export_request(service_name: &str, spans: &[SpanData]) -> JsonValue {
+    let spans = spans.iter().map(otlp_span).collect::<Vec<_>>();
+
+    json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": [{"key": "service.name", "value": {"stringValue": service_name}}],
+            },
+            "scopeSpans": [{
+                "scope": {"name": service_name},
+                "spans": spans,
+            }],
+        }],
+    })
+}
+
+fn otlp_span(span: &SpanData) -> JsonValue {
+    let attributes = span
+        .attributes
+        .iter()
+        .map(|(key, value)| json!({"key": key, "value": {"stringValue": value}}))
+        .collect::<Vec<_>>();
+
+    let mut otlp_span = json!({
+        "traceId": span.context.trace_id,
+        "spanId": span.context.span_id,
+        "name": span.name,
+        "kind": span.kind.code(),
+        "startTimeUnixNano": unix_nanos(span.start_time),
+        "endTimeUnixNano": unix_nanos(span.end_time),
+        "attributes": attributes,
+        // STATUS_CODE_OK = 1, STATUS_CODE_ERROR = 2
+        "status": {"code": if span.ok { 1 } else { 2 }},
+    });
+
+    if let Some(ref parent_span_id) = span.parent_span_id {
+        otlp_span["parentSpanId"] = json!(parent_span_id);
+    }
+
+    otlp_span
+}
+
+// 64-bit integers are encoded as strings in OTLP JSON.
+fn unix_nanos(time: SystemTime) -> String {
+    time.duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_nanos())
+        .unwrap_or(0)
+        .to_string()
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_traceparent() {
+        let traceparent = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+        let context = TraceContext::parse(traceparent).expect("Failed to parse traceparent");
+        assert!(context.sampled);
+        assert_eq!(context.to_traceparent(), traceparent);
+
+        let child = context.child();
+        assert_eq!(child.trace_id, context.trace_id);
+        assert_ne!(child.span_id, context.span_id);
+
+        assert!(
+            TraceContext::parse("00-00000000000000000000000000000000-00f067aa0ba902b7-01")
+                .is_none()
+        );
+        assert!(
+            TraceContext::parse("ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01")
+                .is_none()
+        );
+        assert!(
+            TraceContext::parse("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7").is_none()
+        );
+        assert!(TraceContext::parse("label").is_none());
+    }
+
+    #[test]
+    fn builds_export_request() {
+        let tracer = Tracer::default();
+        let parent = TraceContext::new_root();
+        let mut span = tracer.start("room.create", SpanKind::Server, Some(&parent));
+        span.set_attribute("destination", "conference.svc.example.org");
+
+        let request = export_request("http-gateway", &[span.data]);
+        let otlp_span = &request["resourceSpans"][0]["scopeSpans"][0]["spans"][0];
+        assert_eq!(otlp_span["traceId"], json!(parent.trace_id));
+        assert_eq!(otlp_span["parentSpanId"], json!(parent.span_id));
+        assert_eq!(otlp_span["kind"], json!(2));
+        assert_eq!(otlp_span["attributes"][0]["key"], json!("destination"));
+    }
+
+    #[test]
+    fn drops_spans_over_queue_size() {
+        let path = std::env::temp_dir().join(format!("spans-{}.ndjson", Uuid::new_v4()));
+
+        let config = serde_json::from_value::<Config>(json!({
+            "exporter": {"type": "file", "path": path},
+            "queue_size": 2,
+            "batch_size": 10,
+        }))
+        .expect("Failed to parse config");
+
+        let (tracer, export) = Tracer::new(Some(&config)).expect("Failed to create tracer");
+
+        for name in &["room.create", "room.read", "room.update"] {
+            tracer.start(name, SpanKind::Server, None).end(true);
+        }
+
+        // The export ends once all senders are dropped.
+        drop(tracer);
+        export.wait().expect("Failed to export spans");
+
+        let exported = std::fs::read_to_string(&path).expect("Failed to read spans file");
+        std::fs::remove_file(&path).ok();
+
+        // The queued spans are exported in a single batch, the one over the queue size is dropped.
+        let lines = exported.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 1);
+
+        let request = serde_json::from_str::<JsonValue>(lines[0]).unwrap();
+        let spans = &request["resourceSpans"][0]["scopeSpans"][0]["spans"];
+        assert_eq!(spans[0]["name"], json!("room.create"));
+        assert_eq!(spans[1]["name"], json!("room.read"));
+        assert_eq!(spans.as_array().map(|spans| spans.len()), Some(2));
+    }
+}
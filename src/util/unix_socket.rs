@@ -0,0 +1,297 @@
+use std::ffi::{CString, OsString};
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+
+use anyhow::{format_err, Context, Result};
+use log::{info, warn};
+use serde::de::{self, Deserializer};
+use serde_derive::Deserialize;
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Permissions and ownership of a Unix socket file.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+pub(crate) struct Config {
+    /// Octal file mode, e.g. `"0660"`.
+    #[serde(default, deserialize_with = "deserialize_mode")]
+    mode: Option<u32>,
+    /// User name or numeric id.
+    owner: Option<String>,
+    /// Group name or numeric id.
+    group: Option<String>,
+}
+
+fn deserialize_mode<'de, D>(deserializer: D) -> std::result::Result<Option<u32>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let mode: Option<String> = serde::Deserialize::deserialize(deserializer)?;
+
+    mode.map(|mode| parse_mode(&mode).map_err(de::Error::custom))
+        .transpose()
+}
+
+fn parse_mode(mode: &str) -> Result<u32> {
+    u32::from_str_radix(mode.trim_start_matches("0o"), 8)
+        .ok()
+        .filter(|mode| *mode <= 0o777)
+        .ok_or_else(|| format_err!("invalid octal file mode = '{}'", mode))
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Binds the socket removing a stale socket file left by a previous run.
+///
+/// The socket is bound in a directory only the gateway can access and moved to the path
+/// once it has the configured mode and owner so that nobody can connect to it before.
+pub(crate) fn bind(path: &Path, config: &Config) -> Result<UnixListener> {
+    remove_stale(path)?;
+
+    let dir = private_dir(path)?;
+    let result = bind_private(path, &dir, config);
+
+    if let Err(err) = fs::remove_dir_all(&dir) {
+        warn!("Failed to remove directory {}: {}", dir.display(), err);
+    }
+
+    result
+}
+
+fn bind_private(path: &Path, dir: &Path, config: &Config) -> Result<UnixListener> {
+    let private_path = dir.join("s");
+
+    let listener = UnixListener::bind(&private_path)
+        .with_context(|| format!("Failed to bind Unix socket {}", path.display()))?;
+
+    if let Some(mode) = config.mode {
+        fs::set_permissions(&private_path, fs::Permissions::from_mode(mode))
+            .with_context(|| format!("Failed to set mode of {}", path.display()))?;
+    }
+
+    if config.owner.is_some() || config.group.is_some() {
+        let uid = config.owner.as_deref().map(resolve_user).transpose()?;
+        let gid = config.group.as_deref().map(resolve_group).transpose()?;
+        chown(&private_path, uid, gid)?;
+    }
+
+    fs::rename(&private_path, path)
+        .with_context(|| format!("Failed to move Unix socket to {}", path.display()))?;
+
+    Ok(listener)
+}
+
+// Created next to the socket path since a socket can't be moved across file systems.
+fn private_dir(path: &Path) -> Result<PathBuf> {
+    let name = path
+        .file_name()
+        .ok_or_else(|| format_err!("Invalid Unix socket path = '{}'", path.display()))?;
+
+    let mut dir_name = OsString::from(".");
+    dir_name.push(name);
+    dir_name.push(format!(".{}", std::process::id()));
+
+    let dir = match path.parent() {
+        Some(parent) => parent.join(dir_name),
+        None => PathBuf::from(dir_name),
+    };
+
+    // Left by a previous run which has had the same pid.
+    let _ = fs::remove_dir_all(&dir);
+
+    fs::DirBuilder::new()
+        .mode(0o700)
+        .create(&dir)
+        .with_context(|| format!("Failed to create directory {}", dir.display()))?;
+
+    Ok(dir)
+}
+
+/// Removes the socket file. Errors are only logged since it's called on shutdown.
+pub(crate) fn remove(path: &Path) {
+    match fs::remove_file(path) {
+        Ok(()) => info!("Unix socket {} removed", path.display()),
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => (),
+        Err(err) => warn!("Failed to remove Unix socket {}: {}", path.display(), err),
+    }
+}
+
+// A socket file nobody listens on is left by a process which hasn't exited cleanly.
+// Anything else is kept so that neither a running instance nor a regular file is affected.
+fn remove_stale(path: &Path) -> Result<()> {
+    let meta = match fs::symlink_metadata(path) {
+        Ok(meta) => meta,
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => {
+            return Err(err).with_context(|| format!("Failed to inspect {}", path.display()))
+        }
+    };
+
+    if !meta.file_type().is_socket() {
+        return Err(format_err!(
+            "{} exists and is not a Unix socket",
+            path.display()
+        ));
+    }
+
+    match UnixStream::connect(path) {
+        Ok(_) => Err(format_err!(
+            "Unix socket {} is in use by another process",
+            path.display()
+        )),
+        Err(ref err) if err.kind() == io::ErrorKind::ConnectionRefused => {
+            warn!("Removing stale Unix socket {}", path.display());
+
+            fs::remove_file(path)
+                .with_context(|| format!("Failed to remove stale Unix socket {}", path.display()))
+        }
+        Err(err) => Err(err).with_context(|| format!("Failed to probe {}", path.display())),
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+fn resolve_user(user: &str) -> Result<libc::uid_t> {
+    if let Ok(uid) = user.parse() {
+        return Ok(uid);
+    }
+
+    let name = CString::new(user).context("Invalid user name")?;
+
+    // Safety: the name is a valid C string, the result is only dereferenced when not null.
+    // The gateway doesn't resolve users concurrently so the static buffer isn't shared.
+    let passwd = unsafe { libc::getpwnam(name.as_ptr()) };
+
+    if passwd.is_null() {
+        return Err(format_err!("Unknown user = '{}'", user));
+    }
+
+    Ok(unsafe { (*passwd).pw_uid })
+}
+
+fn resolve_group(group: &str) -> Result<libc::gid_t> {
+    if let Ok(gid) = group.parse() {
+        return Ok(gid);
+    }
+
+    let name = CString::new(group).context("Invalid group name")?;
+
+    // Safety: same as for `getpwnam` above.
+    let entry = unsafe { libc::getgrnam(name.as_ptr()) };
+
+    if entry.is_null() {
+        return Err(format_err!("Unknown group = '{}'", group));
+    }
+
+    Ok(unsafe { (*entry).gr_gid })
+}
+
+fn chown(path: &Path, uid: Option<libc::uid_t>, gid: Option<libc::gid_t>) -> Result<()> {
+    let c_path = CString::new(path.as_os_str().as_bytes()).context("Invalid socket path")?;
+
+    // `-1` keeps the corresponding id unchanged.
+    let uid = uid.unwrap_or(libc::uid_t::MAX);
+    let gid = gid.unwrap_or(libc::gid_t::MAX);
+
+    // Safety: the path is a valid C string.
+    if unsafe { libc::chown(c_path.as_ptr(), uid, gid) } != 0 {
+        return Err(io::Error::last_os_error())
+            .with_context(|| format!("Failed to change ownership of {}", path.display()));
+    }
+
+    Ok(())
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test {
+    use std::os::unix::fs::MetadataExt;
+
+    use super::*;
+
+    #[test]
+    fn parses_mode() {
+        assert_eq!(parse_mode("0660").ok(), Some(0o660));
+        assert_eq!(parse_mode("0o600").ok(), Some(0o600));
+        assert!(parse_mode("0999").is_err());
+        assert!(parse_mode("10000").is_err());
+    }
+
+    #[test]
+    fn replaces_stale_socket() {
+        let dir = std::env::temp_dir().join(format!("http-gateway-{}", uuid::Uuid::new_v4()));
+        fs::create_dir(&dir).expect("Failed to create temp dir");
+        let path = dir.join("gateway.sock");
+
+        let config = Config {
+            mode: Some(0o600),
+            ..Default::default()
+        };
+
+        let listener = bind(&path, &config).expect("Failed to bind");
+        let mode = fs::metadata(&path)
+            .expect("No socket file")
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        // Bound by a running process.
+        assert!(bind(&path, &config).is_err());
+
+        // Left by a process which has exited.
+        drop(listener);
+        let _listener = bind(&path, &config).expect("Failed to replace stale socket");
+
+        remove(&path);
+        assert!(!path.exists());
+
+        fs::write(&path, b"").expect("Failed to write file");
+        assert!(bind(&path, &config).is_err());
+
+        fs::remove_dir_all(&dir).expect("Failed to remove temp dir");
+    }
+
+    #[test]
+    fn applies_mode_and_owner_before_moving_socket() {
+        let dir = std::env::temp_dir().join(format!("http-gateway-{}", uuid::Uuid::new_v4()));
+        fs::create_dir(&dir).expect("Failed to create temp dir");
+        let path = dir.join("gateway.sock");
+
+        // Safety: these calls have no preconditions.
+        let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+
+        let config = Config {
+            mode: Some(0o660),
+            owner: Some(uid.to_string()),
+            group: Some(gid.to_string()),
+        };
+
+        let _listener = bind(&path, &config).expect("Failed to bind");
+        let meta = fs::metadata(&path).expect("No socket file");
+        assert!(meta.file_type().is_socket());
+        assert_eq!(meta.permissions().mode() & 0o777, 0o660);
+        assert_eq!((meta.uid(), meta.gid()), (uid, gid));
+        UnixStream::connect(&path).expect("Failed to connect");
+
+        // The private directory is removed.
+        let entries = fs::read_dir(&dir)
+            .expect("Failed to read temp dir")
+            .map(|entry| entry.unwrap().file_name())
+            .collect::<Vec<_>>();
+        assert_eq!(entries, vec![OsString::from("gateway.sock")]);
+
+        let config = Config {
+            owner: Some("nonexistent-http-gateway-user".to_owned()),
+            ..Default::default()
+        };
+        remove(&path);
+        assert!(bind(&path, &config).is_err());
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+
+        fs::remove_dir_all(&dir).expect("Failed to remove temp dir");
+    }
+}